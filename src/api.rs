@@ -1,13 +1,53 @@
+use crate::diag::diag;
+use crate::theme::theme;
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{io::Write, sync::LazyLock, time::Duration};
+use std::{
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-/// Suppresses ANSI color when NO_COLOR env var is set (https://no-color.org/).
-static USE_COLOR: LazyLock<bool> = LazyLock::new(|| std::env::var_os("NO_COLOR").is_none());
+/// Remove ANSI escape sequences (CSI `ESC [ ... letter` and bare `ESC x`).
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\x1b' {
+            out.push(ch);
+        } else if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Plain-text copy of a session (assistant prose and tool activity) for humans
+/// skimming what happened; written as it streams, never colored.
+#[derive(Clone)]
+pub struct TextTee(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl TextTee {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::from_writer(std::fs::File::create(path)?))
+    }
 
-pub fn color(code: &str) -> &str {
-    if *USE_COLOR { code } else { "" }
+    pub fn from_writer(w: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(w))))
+    }
+
+    pub fn write(&self, text: &str) {
+        if let Ok(mut w) = self.0.lock() {
+            w.write_all(strip_ansi(text).as_bytes()).ok();
+            w.flush().ok();
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -18,6 +58,73 @@ pub enum AgentError {
     Json(#[from] serde_json::Error),
     #[error("stream: {0}")]
     StreamParse(String),
+    #[error("context window exceeded: {0}")]
+    ContextOverflow(String),
+    /// The stream failed after text reached the terminal; re-sending would print it twice
+    #[error("stream failed after partial output (not retried): {0}")]
+    PartialOutput(String),
+    #[error("ANTHROPIC_API_KEY is set but empty — unset it (to use the proxy) or set a key")]
+    MissingApiKey,
+    #[error("API returned {status}{retry_after}: {body}")]
+    Status {
+        status: reqwest::StatusCode,
+        /// " (retry after Ns)" when the response carried a retry-after header
+        retry_after: String,
+        body: String,
+    },
+}
+
+impl AgentError {
+    /// HTTP status for errors that came from a non-success response.
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            Self::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Response body or stream message carried by the error, if any.
+    pub fn body(&self) -> &str {
+        match self {
+            Self::Status { body, .. } | Self::ContextOverflow(body) => body,
+            Self::StreamParse(msg) => msg,
+            _ => "",
+        }
+    }
+}
+
+/// Single source of truth for whether a failed request is worth re-sending unchanged.
+/// Context overflow is not: it needs compaction first, which the caller handles.
+pub fn is_retryable(status: Option<reqwest::StatusCode>, body: &str, kind: &AgentError) -> bool {
+    if let Some(status) = status {
+        match status.as_u16() {
+            408 | 409 | 429 | 500 | 502 | 503 | 504 | 529 => return true,
+            400 | 401 | 403 | 404 | 413 | 422 => return false,
+            _ => {}
+        }
+    }
+    if body.contains("overloaded_error") || body.contains("api_error") {
+        return true;
+    }
+    match kind {
+        AgentError::Api(e) => e.is_timeout() || e.is_connect() || e.is_body(),
+        AgentError::StreamParse(msg) => msg.contains("stream ended without stop_reason"),
+        _ => false,
+    }
+}
+
+/// True when an API error body says the request exceeded the model's input limit.
+pub fn is_context_overflow_error(body: &str) -> bool {
+    let body = body.to_lowercase();
+    [
+        "prompt is too long",
+        "input is too long",
+        "context length",
+        "context window",
+        "exceed context limit",
+    ]
+    .iter()
+    .any(|needle| body.contains(needle))
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -40,7 +147,35 @@ pub struct Usage {
     pub cache_read_input_tokens: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Usage {
+    /// Accumulate another response's counts (e.g. across the requests of one turn).
+    pub fn add(&mut self, other: &Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
+
+    /// Take the counts present in a stream event's `usage` object. They are running
+    /// totals for the response, so a later event's value replaces the earlier one.
+    fn update_from(&mut self, u: &Value) {
+        for (field, n) in [
+            (&mut self.input_tokens, "input_tokens"),
+            (&mut self.output_tokens, "output_tokens"),
+            (
+                &mut self.cache_creation_input_tokens,
+                "cache_creation_input_tokens",
+            ),
+            (&mut self.cache_read_input_tokens, "cache_read_input_tokens"),
+        ] {
+            if let Some(v) = u[n].as_u64() {
+                *field = v;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
     User,
@@ -65,6 +200,12 @@ pub enum ContentBlock {
         #[serde(skip_serializing_if = "Option::is_none")]
         is_error: Option<bool>,
     },
+    /// Kept only when thinking was requested: the API needs the signed block back
+    /// ahead of the tool_use it led to.
+    #[serde(rename = "thinking")]
+    Thinking { thinking: String, signature: String },
+    #[serde(rename = "redacted_thinking")]
+    RedactedThinking { data: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,6 +214,256 @@ pub struct Message {
     pub content: Vec<ContentBlock>,
 }
 
+/// How streamed thinking blocks are shown. Display only: whether they are kept in the
+/// conversation depends on whether thinking was requested (`--effort`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReasoningMode {
+    /// Hide thinking entirely
+    #[default]
+    Off,
+    /// One condensed line per thinking block
+    Summary,
+    /// Stream the full thinking text, dimmed
+    Full,
+}
+
+/// `--effort` presets over the extended-thinking budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Effort {
+    /// No extended thinking
+    Low,
+    /// A moderate thinking budget
+    Medium,
+    /// A large thinking budget
+    High,
+}
+
+/// `--tool-choice`: whether the model may, must, or must not call tools.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// The model decides (the API default; the field is omitted)
+    #[default]
+    Auto,
+    /// No tool calls
+    None,
+    /// Some tool call, any tool
+    Any,
+    /// A call to this tool
+    Tool(String),
+}
+
+impl ToolChoice {
+    /// `auto`, `none`, `any`, or a tool name; never fails, names are checked against
+    /// the registered tools later.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "none" => Self::None,
+            "any" => Self::Any,
+            name => Self::Tool(name.to_string()),
+        })
+    }
+
+    /// Whether a tool call is required.
+    pub fn forces_tool(&self) -> bool {
+        matches!(self, Self::Any | Self::Tool(_))
+    }
+
+    /// The request's `tool_choice` field. Forcing applies only to a turn's `first`
+    /// request; repeating it after the tool results would never let the model answer.
+    pub fn to_request(&self, first: bool) -> Option<Value> {
+        match self {
+            Self::Auto => None,
+            Self::None => Some(serde_json::json!({"type": "none"})),
+            Self::Any => first.then(|| serde_json::json!({"type": "any"})),
+            Self::Tool(name) => first.then(|| serde_json::json!({"type": "tool", "name": name})),
+        }
+    }
+}
+
+/// Tokens always left for the answer on top of the thinking budget.
+pub const MIN_ANSWER_TOKENS: u32 = 4096;
+
+/// Thinking budget for `effort` on `model` (None = thinking off), and the max_tokens to
+/// request: raised when needed so it exceeds the budget, as the API requires.
+pub fn effort_settings(effort: Effort, model: &str, max_tokens: u32) -> (Option<u32>, u32) {
+    // Claude 3 models before 3.7 have no extended thinking
+    let supported = !model.contains("claude-3-") || model.contains("claude-3-7");
+    let (medium, high) = if model.contains("haiku") {
+        (4_000, 16_000)
+    } else if model.contains("sonnet") {
+        (8_000, 24_000)
+    } else {
+        (10_000, 32_000)
+    };
+    let budget = match effort {
+        Effort::Low => None,
+        Effort::Medium => Some(medium),
+        Effort::High => Some(high),
+    }
+    .filter(|_| supported);
+    let max_tokens = budget.map_or(max_tokens, |b| max_tokens.max(b + MIN_ANSWER_TOKENS));
+    (budget, max_tokens)
+}
+
+/// Documented output cap (largest max_tokens) for `model`; None for unrecognized models,
+/// which are sent whatever was asked for.
+pub fn max_output_tokens(model: &str) -> Option<u32> {
+    let cap = if model.contains("claude-3-7") {
+        64_000
+    } else if model.contains("claude-3-5") {
+        8_192
+    } else if model.contains("claude-3-") {
+        4_096
+    } else if model.contains("opus-4-6") {
+        128_000
+    } else if model.contains("opus-4-5") {
+        64_000
+    } else if model.contains("opus-4") {
+        32_000
+    } else if model.contains("sonnet-4") || model.contains("haiku-4") {
+        64_000
+    } else {
+        return None;
+    };
+    Some(cap)
+}
+
+/// Condense a thinking block to its first sentence on one line (max ~120 chars).
+pub fn summarize_thinking(text: &str) -> String {
+    const MAX: usize = 120;
+    let first_para = text
+        .split("\n\n")
+        .map(str::trim)
+        .find(|p| !p.is_empty())
+        .unwrap_or_default();
+    let flat = first_para.split_whitespace().collect::<Vec<_>>().join(" ");
+    let sentence = flat
+        .match_indices(['.', '?', '!'])
+        .map(|(i, _)| i + 1)
+        .find(|&end| end == flat.len() || flat[end..].starts_with(' '))
+        .map_or(flat.as_str(), |end| &flat[..end]);
+    if sentence.chars().count() <= MAX {
+        return sentence.to_string();
+    }
+    let cut: String = sentence.chars().take(MAX - 1).collect();
+    format!("{}…", cut.trim_end())
+}
+
+/// Longest SSE line buffered while waiting for a newline; beyond this the stream is
+/// treated as hostile or broken rather than growing memory without bound.
+const MAX_SSE_LINE: usize = 16 * 1024 * 1024;
+
+/// Default coalescing window for streamed text (~one frame at 60Hz).
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Coalesces streamed text deltas so stdout is flushed on newline or once per
+/// interval instead of on every delta. A zero interval flushes every delta.
+struct FlushBuffer {
+    interval: Duration,
+    pending: String,
+    last_flush: Instant,
+}
+
+impl Default for FlushBuffer {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+impl FlushBuffer {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            pending: String::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, text: &str, out: &mut dyn Write) {
+        self.pending.push_str(text);
+        if text.contains('\n') || self.last_flush.elapsed() >= self.interval {
+            self.flush(out);
+        }
+    }
+
+    fn flush(&mut self, out: &mut dyn Write) {
+        if !self.pending.is_empty() {
+            let (c, r) = (&theme().assistant, &theme().reset);
+            write!(out, "{c}{}{r}", self.pending).ok();
+            self.pending.clear();
+        }
+        out.flush().ok();
+        self.last_flush = Instant::now();
+    }
+}
+
+/// Minimum time between redraws of the tool-argument progress line.
+const ARG_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// Arguments smaller than this stream too quickly to be worth a progress line.
+const ARG_PROGRESS_MIN_BYTES: usize = 2048;
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// A spinner and byte count on one stderr line while a tool_use block's input streams
+/// in, so a large `new_str` doesn't look like a stall. Cleared when the block ends.
+struct ArgProgress {
+    interval: Duration,
+    name: String,
+    bytes: usize,
+    frame: usize,
+    /// Set once the line has been drawn
+    last_draw: Option<Instant>,
+}
+
+impl ArgProgress {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            name: String::new(),
+            bytes: 0,
+            frame: 0,
+            last_draw: None,
+        }
+    }
+
+    fn start(&mut self, name: &str) {
+        self.name = name.into();
+        self.bytes = 0;
+    }
+
+    fn advance(&mut self, bytes: usize, out: &mut dyn Write) {
+        self.bytes += bytes;
+        if self.bytes < ARG_PROGRESS_MIN_BYTES
+            || self.last_draw.is_some_and(|t| t.elapsed() < self.interval)
+        {
+            return;
+        }
+        let (c, r) = (&theme().context, &theme().reset);
+        let spin = SPINNER[self.frame % SPINNER.len()];
+        let size = if self.bytes < 1024 {
+            format!("{} B", self.bytes)
+        } else {
+            format!("{:.1} KB", self.bytes as f64 / 1024.0)
+        };
+        write!(out, "\r\x1b[2K{c}{spin} {}: {size} of input{r}", self.name).ok();
+        out.flush().ok();
+        self.frame += 1;
+        self.last_draw = Some(Instant::now());
+    }
+
+    /// Erase the line if it was drawn.
+    fn finish(&mut self, out: &mut dyn Write) {
+        if self.last_draw.take().is_some() {
+            write!(out, "\r\x1b[2K").ok();
+            out.flush().ok();
+        }
+    }
+}
+
+/// How far past the known blocks an early input_json_delta may reach and still be kept,
+/// and a content_block_start may open a slot.
+const MAX_EARLY_GAP: usize = 8;
+
 #[derive(Default)]
 struct SseParser {
     event: String,
@@ -81,9 +472,44 @@ struct SseParser {
     stop_reason: Option<StopReason>,
     message_complete: bool,
     usage: Usage,
+    out: FlushBuffer,
+    tee: Option<TextTee>,
+    reasoning: ReasoningMode,
+    /// Keep signed thinking blocks in the response (thinking was requested)
+    keep_thinking: bool,
+    /// Tool input progress on stderr (interactive terminals only)
+    progress: Option<ArgProgress>,
+    /// Some text or thinking has been shown to the user
+    printed: bool,
 }
 
 impl SseParser {
+    fn with_flush_interval(interval: Duration) -> Self {
+        Self {
+            out: FlushBuffer::new(interval),
+            ..Default::default()
+        }
+    }
+
+    /// Once output has been shown, a failure becomes `PartialOutput`, which is not retried.
+    fn cut_off(&self, e: AgentError) -> AgentError {
+        if self.printed {
+            AgentError::PartialOutput(e.to_string())
+        } else {
+            e
+        }
+    }
+
+    /// Pad `blocks`/`fragments` with placeholders so index `len - 1` exists.
+    fn grow_to(&mut self, len: usize) {
+        while self.blocks.len() < len {
+            self.blocks.push(ContentBlock::Text {
+                text: String::new(),
+            });
+            self.fragments.push(String::new());
+        }
+    }
+
     fn process_line(&mut self, line: &str) -> Result<(), AgentError> {
         if line.is_empty() {
             return Ok(());
@@ -98,23 +524,47 @@ impl SseParser {
         let p: Value = serde_json::from_str(data)?;
         match self.event.as_str() {
             "content_block_start" => {
+                let idx = p["index"].as_u64().map_or(self.blocks.len(), |i| {
+                    usize::try_from(i).unwrap_or(usize::MAX)
+                });
+                // The index is the server's word: bound it before allocating slots for it
+                if idx >= self.blocks.len() + MAX_EARLY_GAP {
+                    return Err(AgentError::StreamParse(format!(
+                        "content_block_start index {idx} is out of range ({} blocks so far)",
+                        self.blocks.len()
+                    )));
+                }
+                // Text/unknown types stay as the placeholder — keeps indices aligned.
+                // A slot already grown by an early delta keeps whatever it collected.
+                self.grow_to(idx + 1);
                 let b = &p["content_block"];
-                if b["type"].as_str() == Some("tool_use")
-                    && let Some(id) = b["id"].as_str().filter(|s| !s.is_empty())
-                    && let Some(name) = b["name"].as_str().filter(|s| !s.is_empty())
-                {
-                    self.blocks.push(ContentBlock::ToolUse {
-                        id: id.into(),
-                        name: name.into(),
-                        input: Value::Null,
-                    });
-                } else {
-                    // Placeholder for text/unknown types — keeps indices aligned
-                    self.blocks.push(ContentBlock::Text {
-                        text: String::new(),
-                    });
+                match b["type"].as_str() {
+                    Some("tool_use") => {
+                        if let Some(id) = b["id"].as_str().filter(|s| !s.is_empty())
+                            && let Some(name) = b["name"].as_str().filter(|s| !s.is_empty())
+                        {
+                            self.blocks[idx] = ContentBlock::ToolUse {
+                                id: id.into(),
+                                name: name.into(),
+                                input: Value::Null,
+                            };
+                            if let Some(progress) = &mut self.progress {
+                                progress.start(name);
+                            }
+                        }
+                    }
+                    Some("thinking") if self.keep_thinking => {
+                        self.blocks[idx] = ContentBlock::Thinking {
+                            thinking: String::new(),
+                            signature: String::new(),
+                        };
+                    }
+                    Some("redacted_thinking") if self.keep_thinking => {
+                        let data = b["data"].as_str().unwrap_or_default();
+                        self.blocks[idx] = ContentBlock::RedactedThinking { data: data.into() };
+                    }
+                    _ => {}
                 }
-                self.fragments.push(String::new());
             }
             "content_block_delta" => {
                 let Some(idx) = p["index"].as_u64().map(|i| i as usize) else {
@@ -124,16 +574,49 @@ impl SseParser {
                 match delta["type"].as_str() {
                     Some("text_delta") => {
                         let t = delta["text"].as_str().unwrap_or_default();
-                        let (c, r) = (color("\x1b[93m"), color("\x1b[0m"));
-                        print!("{c}{t}{r}");
-                        std::io::stdout().flush().ok();
+                        self.printed |= !t.is_empty();
+                        self.out.push(t, &mut std::io::stdout());
+                        if let Some(tee) = &self.tee {
+                            tee.write(t);
+                        }
                         if let Some(ContentBlock::Text { text }) = self.blocks.get_mut(idx) {
                             text.push_str(t);
                         }
                     }
+                    Some("thinking_delta") => {
+                        let t = delta["thinking"].as_str().unwrap_or_default();
+                        if self.reasoning == ReasoningMode::Full {
+                            self.printed |= !t.is_empty();
+                            let (c, r) = (&theme().context, &theme().reset);
+                            eprint!("{c}{t}{r}");
+                        }
+                        if let Some(f) = self.fragments.get_mut(idx) {
+                            f.push_str(t);
+                        }
+                    }
+                    Some("signature_delta") => {
+                        if let Some(ContentBlock::Thinking { signature, .. }) =
+                            self.blocks.get_mut(idx)
+                        {
+                            signature.push_str(delta["signature"].as_str().unwrap_or_default());
+                        }
+                    }
                     Some("input_json_delta") => {
+                        // Tool input is too costly to lose to a reordered stream: hold it in
+                        // a placeholder until the late content_block_start fills the slot
+                        if idx >= self.blocks.len() && idx < self.blocks.len() + MAX_EARLY_GAP {
+                            let (c, r) = (&theme().warning, &theme().reset);
+                            diag!(
+                                "{c}[warning]{r} input_json_delta for index {idx} arrived before its content_block_start"
+                            );
+                            self.grow_to(idx + 1);
+                        }
+                        let partial = delta["partial_json"].as_str().unwrap_or_default();
+                        if let Some(progress) = &mut self.progress {
+                            progress.advance(partial.len(), &mut std::io::stderr());
+                        }
                         if let Some(f) = self.fragments.get_mut(idx) {
-                            f.push_str(delta["partial_json"].as_str().unwrap_or_default());
+                            f.push_str(partial);
                         }
                     }
                     _ => {}
@@ -144,19 +627,22 @@ impl SseParser {
                     return Ok(());
                 };
                 if idx >= self.blocks.len() {
-                    let (c, r) = (color("\x1b[91m"), color("\x1b[0m"));
-                    eprintln!(
+                    let (c, r) = (&theme().error, &theme().reset);
+                    diag!(
                         "{c}[warning]{r} content_block_stop index {idx} out of bounds (have {} blocks)",
                         self.blocks.len()
                     );
                     return Ok(());
                 }
+                if let Some(progress) = &mut self.progress {
+                    progress.finish(&mut std::io::stderr());
+                }
                 if let Some(ContentBlock::ToolUse { input, .. }) = self.blocks.get_mut(idx) {
                     match self.fragments.get(idx).filter(|f| !f.is_empty()) {
                         Some(f) => {
                             *input = serde_json::from_str(f).unwrap_or_else(|e| {
-                                let (c, r) = (color("\x1b[91m"), color("\x1b[0m"));
-                                eprintln!(
+                                let (c, r) = (&theme().error, &theme().reset);
+                                diag!(
                                     "{c}[warning]{r} Corrupt tool input (JSON parse failed: {e})"
                                 );
                                 Value::Null
@@ -165,24 +651,38 @@ impl SseParser {
                         None => *input = serde_json::json!({}),
                     }
                 }
+                if let Some(ContentBlock::Thinking { thinking, .. }) = self.blocks.get_mut(idx) {
+                    thinking.clone_from(&self.fragments[idx]);
+                }
+                // Text blocks never collect fragments, so a filled one here was thinking
+                let was_thinking = match self.blocks.get(idx) {
+                    Some(ContentBlock::Text { text }) => text.is_empty(),
+                    Some(ContentBlock::Thinking { .. }) => true,
+                    _ => false,
+                };
+                if was_thinking
+                    && let Some(thinking) = self.fragments.get(idx).filter(|f| !f.is_empty())
+                {
+                    let (c, r) = (&theme().context, &theme().reset);
+                    match self.reasoning {
+                        ReasoningMode::Off => {}
+                        ReasoningMode::Summary => {
+                            eprintln!("{c}[thinking] {}{r}", summarize_thinking(thinking));
+                        }
+                        ReasoningMode::Full => eprintln!(),
+                    }
+                }
                 if let Some(ContentBlock::Text { text }) = self.blocks.get(idx)
                     && !text.is_empty()
                 {
+                    self.out.flush(&mut std::io::stdout());
                     println!();
+                    if let Some(tee) = &self.tee {
+                        tee.write("\n");
+                    }
                 }
             }
-            "message_start" => {
-                let u = &p["message"]["usage"];
-                if let Some(n) = u["input_tokens"].as_u64() {
-                    self.usage.input_tokens = n;
-                }
-                if let Some(n) = u["cache_creation_input_tokens"].as_u64() {
-                    self.usage.cache_creation_input_tokens = n;
-                }
-                if let Some(n) = u["cache_read_input_tokens"].as_u64() {
-                    self.usage.cache_read_input_tokens = n;
-                }
-            }
+            "message_start" => self.usage.update_from(&p["message"]["usage"]),
             "message_delta" => {
                 match p["delta"]["stop_reason"].as_str() {
                     Some("end_turn") => self.stop_reason = Some(StopReason::EndTurn),
@@ -190,42 +690,172 @@ impl SseParser {
                     Some("max_tokens") => self.stop_reason = Some(StopReason::MaxTokens),
                     _ => {}
                 }
-                if let Some(n) = p["usage"]["output_tokens"].as_u64() {
-                    self.usage.output_tokens = n;
-                }
+                // Usage may arrive on deltas with or without a stop reason
+                self.usage.update_from(&p["usage"]);
             }
             "message_stop" => self.message_complete = true,
             "error" => {
+                self.out.flush(&mut std::io::stdout());
+                if let Some(progress) = &mut self.progress {
+                    progress.finish(&mut std::io::stderr());
+                }
                 let msg = p["error"]["message"]
                     .as_str()
                     .unwrap_or("unknown stream error");
-                return Err(AgentError::StreamParse(format!("stream error: {msg}")));
+                let kind = p["error"]["type"].as_str().unwrap_or("error");
+                return Err(AgentError::StreamParse(format!(
+                    "stream error ({kind}): {msg}"
+                )));
             }
             _ => {}
         }
         Ok(())
     }
 
+    /// Completeness rules when the stream ends:
+    /// - `stop_reason` seen (any kind), with or without `message_stop`: complete — the API
+    ///   only sends `message_delta` after every content block has closed.
+    /// - `message_stop` without `stop_reason`: complete, treated as EndTurn.
+    /// - neither: incomplete, an error.
+    ///
+    /// A tool_use block still open at the end (no `content_block_stop`) is finalized from
+    /// its fragments if they form valid JSON; otherwise its input stays null (corrupt).
     fn finish(mut self) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
-        self.blocks
-            .retain(|b| !matches!(b, ContentBlock::Text { text } if text.is_empty()));
-        let stop = self
+        self.out.flush(&mut std::io::stdout());
+        if let Some(progress) = &mut self.progress {
+            progress.finish(&mut std::io::stderr());
+        }
+        for (block, fragment) in self.blocks.iter_mut().zip(&self.fragments) {
+            if let ContentBlock::ToolUse { input, .. } = block
+                && input.is_null()
+                && let Ok(v) = serde_json::from_str::<Value>(fragment)
+            {
+                *input = v;
+            }
+        }
+        // Unsigned thinking (cut off mid-stream) would be rejected if sent back
+        self.blocks.retain(|b| match b {
+            ContentBlock::Text { text } => !text.is_empty(),
+            ContentBlock::Thinking { signature, .. } => !signature.is_empty(),
+            _ => true,
+        });
+        let Some(stop) = self
             .stop_reason
+            .clone()
             .or(self.message_complete.then_some(StopReason::EndTurn))
-            .ok_or_else(|| AgentError::StreamParse("stream ended without stop_reason".into()))?;
+        else {
+            let e = AgentError::StreamParse("stream ended without stop_reason".into());
+            return Err(self.cut_off(e));
+        };
         Ok((self.blocks, stop, self.usage))
     }
 }
 
+/// Feed every complete line in `buf` to the parser, keeping the partial tail.
+/// Errors if that tail has grown past `max_line` without a newline.
+fn drain_lines(
+    buf: &mut String,
+    parser: &mut SseParser,
+    max_line: usize,
+) -> Result<(), AgentError> {
+    while let Some(nl) = buf.find('\n') {
+        let line = buf[..nl].trim_end().to_string();
+        buf.drain(..nl + 1);
+        parser.process_line(&line)?;
+    }
+    if buf.len() > max_line {
+        return Err(AgentError::StreamParse(format!(
+            "oversized SSE line: over {max_line} bytes without a newline"
+        )));
+    }
+    Ok(())
+}
+
+/// Unset means no key (the proxy authenticates); set-but-blank is a mistake worth
+/// failing on before a confusing 401. Whitespace from copy-paste is trimmed.
+fn api_key_from(value: Option<String>) -> Result<Option<String>, AgentError> {
+    match value.as_deref().map(str::trim) {
+        None => Ok(None),
+        Some("") => Err(AgentError::MissingApiKey),
+        Some(key) => Ok(Some(key.to_string())),
+    }
+}
+
 pub struct AnthropicClient {
     client: reqwest::Client,
     api_url: String,
     api_key: Option<String>,
+    flush_interval: Duration,
+    tee: Option<TextTee>,
+    reasoning: ReasoningMode,
+    stream: bool,
+    /// Extended-thinking budget sent with each request (None = thinking off)
+    thinking_budget: Option<u32>,
+    /// Show a spinner and byte count while tool input streams
+    arg_progress: bool,
+}
+
+/// Decode a non-streamed (`"stream": false`) Messages response into the same shape
+/// `SseParser::finish` produces. Unknown block types are dropped, and thinking too
+/// unless `keep_thinking`.
+fn parse_message_body(
+    body: &Value,
+    keep_thinking: bool,
+) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
+    let content = body["content"]
+        .as_array()
+        .ok_or_else(|| AgentError::StreamParse("response has no content array".into()))?;
+    let mut blocks = Vec::new();
+    for b in content {
+        match b["type"].as_str() {
+            Some("text") => {
+                let text = b["text"].as_str().unwrap_or_default();
+                if !text.is_empty() {
+                    blocks.push(ContentBlock::Text { text: text.into() });
+                }
+            }
+            Some("tool_use") => blocks.push(ContentBlock::ToolUse {
+                id: b["id"].as_str().unwrap_or_default().into(),
+                name: b["name"].as_str().unwrap_or_default().into(),
+                input: match &b["input"] {
+                    Value::Null => serde_json::json!({}),
+                    v => v.clone(),
+                },
+            }),
+            Some("thinking") if keep_thinking => {
+                let signature = b["signature"].as_str().unwrap_or_default();
+                if !signature.is_empty() {
+                    blocks.push(ContentBlock::Thinking {
+                        thinking: b["thinking"].as_str().unwrap_or_default().into(),
+                        signature: signature.into(),
+                    });
+                }
+            }
+            Some("redacted_thinking") if keep_thinking => {
+                blocks.push(ContentBlock::RedactedThinking {
+                    data: b["data"].as_str().unwrap_or_default().into(),
+                });
+            }
+            _ => {}
+        }
+    }
+    let stop = match body["stop_reason"].as_str() {
+        Some("tool_use") => StopReason::ToolUse,
+        Some("max_tokens") => StopReason::MaxTokens,
+        Some(_) => StopReason::EndTurn,
+        None => {
+            return Err(AgentError::StreamParse(
+                "response ended without stop_reason".into(),
+            ));
+        }
+    };
+    let usage = serde_json::from_value(body["usage"].clone()).unwrap_or_default();
+    Ok((blocks, stop, usage))
 }
 
 impl AnthropicClient {
     pub fn new(api_url: &str) -> Result<Self, AgentError> {
-        let api_key = std::env::var("ANTHROPIC_API_KEY").ok();
+        let api_key = api_key_from(std::env::var("ANTHROPIC_API_KEY").ok())?;
         let client = reqwest::ClientBuilder::new()
             .connect_timeout(Duration::from_secs(30))
             .timeout(Duration::from_secs(300))
@@ -234,22 +864,115 @@ impl AnthropicClient {
             client,
             api_url: api_url.into(),
             api_key,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            tee: None,
+            reasoning: ReasoningMode::default(),
+            stream: true,
+            thinking_budget: None,
+            arg_progress: false,
         })
     }
 
-    pub async fn send_message(
+    /// Request SSE streaming (default) or a single JSON body (`--no-stream`).
+    pub fn with_streaming(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Set how long streamed text may be buffered before flushing (zero = every delta).
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Also copy streamed assistant text to `tee`.
+    pub fn with_text_tee(mut self, tee: TextTee) -> Self {
+        self.tee = Some(tee);
+        self
+    }
+
+    /// Request extended thinking with this token budget (None = off).
+    pub fn with_thinking(mut self, budget: Option<u32>) -> Self {
+        self.thinking_budget = budget;
+        self
+    }
+
+    /// Choose how thinking blocks in the stream are displayed.
+    pub fn with_reasoning(mut self, mode: ReasoningMode) -> Self {
+        self.reasoning = mode;
+        self
+    }
+
+    /// Show streaming progress of tool_use input on stderr (off by default).
+    pub fn with_arg_progress(mut self, on: bool) -> Self {
+        self.arg_progress = on;
+        self
+    }
+
+    /// Print a non-streamed response the way the stream would have: text to stdout
+    /// (and the tee), thinking per the reasoning mode.
+    fn show_message_body(&self, body: &Value) {
+        let (c, r) = (&theme().context, &theme().reset);
+        for b in body["content"].as_array().into_iter().flatten() {
+            match b["type"].as_str() {
+                Some("text") => {
+                    let text = b["text"].as_str().unwrap_or_default();
+                    if !text.is_empty() {
+                        println!("{text}");
+                        if let Some(tee) = &self.tee {
+                            tee.write(&format!("{text}\n"));
+                        }
+                    }
+                }
+                Some("thinking") => {
+                    let thinking = b["thinking"].as_str().unwrap_or_default();
+                    match self.reasoning {
+                        ReasoningMode::Off => {}
+                        ReasoningMode::Summary => {
+                            eprintln!("{c}[thinking] {}{r}", summarize_thinking(thinking));
+                        }
+                        ReasoningMode::Full => eprintln!("{c}{thinking}{r}"),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The JSON body `send_message` posts. The API key travels in a header, never here.
+    pub fn request_body(
         &self,
         messages: &[Message],
         tools: &[Value],
         model: &str,
-        system_prompt: &str,
+        system: &Value,
         max_tokens: u32,
-    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
-        let body = serde_json::json!({
-            "model": model, "max_tokens": max_tokens, "stream": true,
-            "system": system_prompt,
+        tool_choice: Option<&Value>,
+    ) -> Value {
+        let mut body = serde_json::json!({
+            "model": model, "max_tokens": max_tokens, "stream": self.stream,
+            "system": system,
             "messages": messages, "tools": tools
         });
+        if let Some(choice) = tool_choice {
+            body["tool_choice"] = choice.clone();
+        }
+        if let Some(budget) = self.thinking_budget {
+            body["thinking"] = serde_json::json!({"type": "enabled", "budget_tokens": budget});
+        }
+        body
+    }
+
+    pub async fn send_message(
+        &self,
+        messages: &[Message],
+        tools: &[Value],
+        model: &str,
+        system: &Value,
+        max_tokens: u32,
+        tool_choice: Option<&Value>,
+    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
+        let body = self.request_body(messages, tools, model, system, max_tokens, tool_choice);
         let url = format!("{}/v1/messages", self.api_url);
         let mut req = self
             .client
@@ -268,30 +991,128 @@ impl AnthropicClient {
                 .map(|v| format!(" (retry after {v}s)"))
                 .unwrap_or_default();
             let body = response.text().await.unwrap_or_default();
-            return Err(AgentError::StreamParse(format!(
-                "API returned {status}{retry}: {body}"
-            )));
+            if status == reqwest::StatusCode::BAD_REQUEST && is_context_overflow_error(&body) {
+                return Err(AgentError::ContextOverflow(body));
+            }
+            return Err(AgentError::Status {
+                status,
+                retry_after: retry,
+                body,
+            });
+        }
+
+        if !self.stream {
+            let body: Value = response.json().await?;
+            self.show_message_body(&body);
+            return parse_message_body(&body, self.thinking_budget.is_some());
         }
 
         let mut stream = response.bytes_stream();
         let mut buf = String::new();
-        let mut parser = SseParser::default();
+        let mut parser = SseParser::with_flush_interval(self.flush_interval);
+        parser.tee = self.tee.clone();
+        parser.reasoning = self.reasoning;
+        parser.keep_thinking = self.thinking_budget.is_some();
+        parser.progress = self
+            .arg_progress
+            .then(|| ArgProgress::new(ARG_PROGRESS_INTERVAL));
 
         while let Some(chunk) = stream.next().await {
-            buf.push_str(&String::from_utf8_lossy(&chunk?));
-            while let Some(nl) = buf.find('\n') {
-                let line = buf[..nl].trim_end().to_string();
-                buf.drain(..nl + 1);
-                parser.process_line(&line)?;
-            }
+            let chunk = chunk.map_err(|e| parser.cut_off(e.into()))?;
+            buf.push_str(&String::from_utf8_lossy(&chunk));
+            drain_lines(&mut buf, &mut parser, MAX_SSE_LINE).map_err(|e| parser.cut_off(e))?;
         }
         if !buf.trim().is_empty() {
-            parser.process_line(buf.trim())?; // trailing data without final newline
+            // trailing data without final newline
+            parser
+                .process_line(buf.trim())
+                .map_err(|e| parser.cut_off(e))?;
         }
         parser.finish()
     }
 }
 
+/// Where `run_turn` sends a conversation: the Anthropic API in production, a scripted
+/// `MockBackend` in tests.
+pub trait Backend {
+    async fn send_message(
+        &self,
+        messages: &[Message],
+        tools: &[Value],
+        model: &str,
+        system: &Value,
+        max_tokens: u32,
+        tool_choice: Option<&Value>,
+    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError>;
+}
+
+impl Backend for AnthropicClient {
+    async fn send_message(
+        &self,
+        messages: &[Message],
+        tools: &[Value],
+        model: &str,
+        system: &Value,
+        max_tokens: u32,
+        tool_choice: Option<&Value>,
+    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
+        AnthropicClient::send_message(
+            self,
+            messages,
+            tools,
+            model,
+            system,
+            max_tokens,
+            tool_choice,
+        )
+        .await
+    }
+}
+
+/// Replays a scripted sequence of responses, one per request, and records the
+/// conversation each request carried. Runs out with a `StreamParse` error.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockBackend {
+    responses: Mutex<std::collections::VecDeque<MockResponse>>,
+    requests: Mutex<Vec<Vec<Message>>>,
+}
+
+#[cfg(test)]
+pub type MockResponse = Result<(Vec<ContentBlock>, StopReason, Usage), AgentError>;
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn new(responses: impl IntoIterator<Item = MockResponse>) -> Self {
+        Self {
+            responses: Mutex::new(responses.into_iter().collect()),
+            requests: Mutex::default(),
+        }
+    }
+
+    /// The messages sent with each request so far, oldest first.
+    pub fn requests(&self) -> Vec<Vec<Message>> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Backend for MockBackend {
+    async fn send_message(
+        &self,
+        messages: &[Message],
+        _tools: &[Value],
+        _model: &str,
+        _system: &Value,
+        _max_tokens: u32,
+        _tool_choice: Option<&Value>,
+    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
+        self.requests.lock().unwrap().push(messages.to_vec());
+        let next = self.responses.lock().unwrap().pop_front();
+        next.unwrap_or_else(|| Err(AgentError::StreamParse("mock backend exhausted".into())))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -465,6 +1286,40 @@ mod tests {
         parser.finish()
     }
 
+    #[test]
+    fn a_stream_dropped_after_output_is_not_retried() {
+        let text_delta = r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#;
+        let start = r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#;
+        // Nothing shown yet: the drop is retryable as before
+        let err = parse_sse(&["event: content_block_start", start]).unwrap_err();
+        assert!(is_retryable(None, err.body(), &err), "{err}");
+        // Text already printed: re-sending would print it again
+        let err = parse_sse(&[
+            "event: content_block_start",
+            start,
+            "event: content_block_delta",
+            text_delta,
+        ])
+        .unwrap_err();
+        assert!(matches!(err, AgentError::PartialOutput(_)), "{err:?}");
+        assert!(!is_retryable(None, err.body(), &err));
+        assert!(err.to_string().contains("stream ended without stop_reason"));
+    }
+
+    #[test]
+    fn sse_rejects_an_out_of_range_block_index() {
+        for index in ["100000000000", "18446744073709551615"] {
+            let start = format!(
+                r#"data: {{"type":"content_block_start","index":{index},"content_block":{{"type":"text","text":""}}}}"#
+            );
+            let err = parse_sse(&["event: content_block_start", &start]).unwrap_err();
+            assert!(
+                matches!(&err, AgentError::StreamParse(m) if m.contains("out of range")),
+                "{err:?}"
+            );
+        }
+    }
+
     #[test]
     fn sse_text_response() {
         let (blocks, stop, _usage) = parse_sse(&[
@@ -515,6 +1370,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn input_json_deltas_advance_tool_progress() {
+        let mut parser = SseParser {
+            progress: Some(ArgProgress::new(Duration::ZERO)),
+            ..Default::default()
+        };
+        parser.process_line("event: content_block_start").unwrap();
+        parser
+            .process_line(r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"t1","name":"Edit"}}"#)
+            .unwrap();
+        let mut seen = Vec::new();
+        for chunk in ["{\\\"path\\\":", "\\\"a.rs\\\"", "}"] {
+            parser.process_line("event: content_block_delta").unwrap();
+            parser
+                .process_line(&format!(
+                    r#"data: {{"type":"content_block_delta","index":0,"delta":{{"type":"input_json_delta","partial_json":"{chunk}"}}}}"#
+                ))
+                .unwrap();
+            seen.push(parser.progress.as_ref().unwrap().bytes);
+        }
+        assert_eq!(seen, [8, 14, 15]);
+
+        // Small inputs draw nothing; past the threshold each delta redraws the count
+        let mut progress = ArgProgress::new(Duration::ZERO);
+        progress.start("Edit");
+        let mut out = Vec::new();
+        progress.advance(1500, &mut out);
+        assert!(out.is_empty());
+        progress.advance(1000, &mut out);
+        progress.advance(1000, &mut out);
+        let drawn = strip_ansi(&String::from_utf8(out).unwrap());
+        assert_eq!(drawn, "\r| Edit: 2.4 KB of input\r/ Edit: 3.4 KB of input");
+        let mut out = Vec::new();
+        progress.finish(&mut out);
+        assert_eq!(out, b"\r\x1b[2K");
+    }
+
     #[test]
     fn sse_mixed_text_and_tool() {
         let (blocks, stop, _usage) = parse_sse(&[
@@ -564,6 +1456,127 @@ mod tests {
         assert!(matches!(&blocks[0], ContentBlock::Text { text } if text == "visible"));
     }
 
+    #[test]
+    fn sse_thinking_deltas_stay_out_of_blocks() {
+        let (blocks, _, _) = parse_sse(&[
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Consider the file."}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"text","text":""}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"answer"}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":1}"#,
+            r#"event: message_delta"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"end_turn"}}"#,
+        ])
+        .unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert!(matches!(&blocks[0], ContentBlock::Text { text } if text == "answer"));
+    }
+
+    #[test]
+    fn sse_signed_thinking_is_kept_when_requested() {
+        let lines = [
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Read it first."}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"sig=="}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"t1","name":"Read"}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"path\":\"a.rs\"}"}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":1}"#,
+            r#"event: message_delta"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"tool_use"}}"#,
+        ];
+        let mut parser = SseParser {
+            keep_thinking: true,
+            ..Default::default()
+        };
+        for line in lines {
+            parser.process_line(line).unwrap();
+        }
+        let (blocks, _, _) = parser.finish().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert!(
+            matches!(&blocks[0], ContentBlock::Thinking { thinking, signature } if thinking == "Read it first." && signature == "sig==")
+        );
+        assert_eq!(
+            serde_json::to_value(&blocks[0]).unwrap(),
+            serde_json::json!({"type": "thinking", "thinking": "Read it first.", "signature": "sig=="})
+        );
+        // Not requested: dropped as before
+        let (blocks, _, _) = parse_sse(&lines).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let body = serde_json::json!({
+            "content": [
+                {"type": "thinking", "thinking": "Hm.", "signature": "s"},
+                {"type": "redacted_thinking", "data": "opaque"},
+                {"type": "text", "text": "Done."}
+            ],
+            "stop_reason": "end_turn"
+        });
+        assert_eq!(parse_message_body(&body, true).unwrap().0.len(), 3);
+        assert_eq!(parse_message_body(&body, false).unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn effort_presets_map_to_thinking_budgets() {
+        let opus = "claude-opus-4-6";
+        assert_eq!(effort_settings(Effort::Low, opus, 16384), (None, 16384));
+        assert_eq!(
+            effort_settings(Effort::Medium, opus, 16384),
+            (Some(10_000), 16384)
+        );
+        // max_tokens must exceed the budget, so it is raised
+        assert_eq!(
+            effort_settings(Effort::High, opus, 16384),
+            (Some(32_000), 36_096)
+        );
+        assert_eq!(
+            effort_settings(Effort::High, "claude-sonnet-4-5", 64_000),
+            (Some(24_000), 64_000)
+        );
+        assert_eq!(
+            effort_settings(Effort::Medium, "claude-haiku-4-5", 8192),
+            (Some(4_000), 8192)
+        );
+        // Older models have no extended thinking
+        assert_eq!(
+            effort_settings(Effort::High, "claude-3-5-sonnet-latest", 8192),
+            (None, 8192)
+        );
+    }
+
+    #[test]
+    fn summarize_thinking_keeps_first_sentence() {
+        let text = "\n\nThe user wants a refactor of\n  main.rs. I should read it first.\n\nThen I'll plan the edits.";
+        assert_eq!(
+            summarize_thinking(text),
+            "The user wants a refactor of main.rs."
+        );
+        // Decimal points and paths don't end a sentence
+        assert_eq!(
+            summarize_thinking("Version 1.5 of src/api.rs looks fine"),
+            "Version 1.5 of src/api.rs looks fine"
+        );
+        let long = "word ".repeat(60);
+        let summary = summarize_thinking(&long);
+        assert_eq!(summary.chars().count(), 120);
+        assert!(summary.ends_with('…'));
+        assert_eq!(summarize_thinking("  \n\n "), "");
+    }
+
     #[test]
     fn sse_missing_index_skipped() {
         // Delta events without an index field should be silently skipped
@@ -647,6 +1660,143 @@ mod tests {
         assert_eq!(stop, StopReason::EndTurn);
     }
 
+    #[test]
+    fn sse_stop_reason_without_message_stop_is_complete_for_every_kind() {
+        for (kind, expected) in [
+            ("end_turn", StopReason::EndTurn),
+            ("tool_use", StopReason::ToolUse),
+            ("max_tokens", StopReason::MaxTokens),
+        ] {
+            let delta =
+                format!(r#"data: {{"type":"message_delta","delta":{{"stop_reason":"{kind}"}}}}"#);
+            let head = [
+                r#"event: content_block_start"#,
+                r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"t1","name":"Read"}}"#,
+                r#"event: content_block_delta"#,
+                r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"input_json_delta","partial_json":"{\"path\":\"a\"}"}}"#,
+                r#"event: content_block_stop"#,
+                r#"data: {"type":"content_block_stop","index":0}"#,
+                r#"event: message_delta"#,
+                &delta,
+            ];
+            let stop_lines = [r#"event: message_stop"#, r#"data: {"type":"message_stop"}"#];
+            let with_stop: Vec<&str> = head.iter().chain(&stop_lines).copied().collect();
+            for lines in [&head[..], &with_stop[..]] {
+                let (blocks, stop, _) = parse_sse(lines).unwrap();
+                assert_eq!(stop, expected, "{kind}, {} lines", lines.len());
+                assert!(
+                    matches!(&blocks[0], ContentBlock::ToolUse { input, .. } if input["path"] == "a")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sse_tool_use_cut_before_block_stop() {
+        // stop_reason arrived but the tool_use block never got content_block_stop
+        let cut = |partial: &str| {
+            let delta = format!(
+                r#"data: {{"type":"content_block_delta","index":0,"delta":{{"type":"input_json_delta","partial_json":{}}}}}"#,
+                serde_json::to_string(partial).unwrap()
+            );
+            let (blocks, stop, _) = parse_sse(&[
+                r#"event: content_block_start"#,
+                r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"t1","name":"Bash"}}"#,
+                r#"event: content_block_delta"#,
+                &delta,
+                r#"event: message_delta"#,
+                r#"data: {"type":"message_delta","delta":{"stop_reason":"tool_use"}}"#,
+            ])
+            .unwrap();
+            assert_eq!(stop, StopReason::ToolUse);
+            let ContentBlock::ToolUse { input, .. } = &blocks[0] else {
+                panic!("expected ToolUse");
+            };
+            input.clone()
+        };
+        assert_eq!(
+            cut(r#"{"command":"ls"}"#),
+            serde_json::json!({"command": "ls"})
+        );
+        assert!(cut(r#"{"comm"#).is_null(), "partial JSON stays corrupt");
+    }
+
+    #[test]
+    fn sse_input_delta_before_block_start_still_assembles() {
+        let (blocks, stop, _) = parse_sse(&[
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Reading."}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"path\":"}}"#,
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"t1","name":"Read"}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"a.rs\"}"}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":1}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            r#"event: message_delta"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"tool_use"}}"#,
+        ])
+        .unwrap();
+        assert_eq!(stop, StopReason::ToolUse);
+        assert_eq!(blocks.len(), 2);
+        assert!(matches!(&blocks[0], ContentBlock::Text { text } if text == "Reading."));
+        let ContentBlock::ToolUse { id, input, .. } = &blocks[1] else {
+            panic!("expected ToolUse, got {:?}", blocks[1]);
+        };
+        assert_eq!(id, "t1");
+        assert_eq!(input, &serde_json::json!({"path": "a.rs"}));
+    }
+
+    #[test]
+    fn non_streamed_body_decodes_like_the_stream() {
+        let body = serde_json::json!({
+            "id": "msg_1", "type": "message", "role": "assistant",
+            "content": [
+                {"type": "thinking", "thinking": "Let me look.", "signature": "x"},
+                {"type": "text", "text": "Reading the file."},
+                {"type": "tool_use", "id": "t1", "name": "Read", "input": {"path": "a.rs"}},
+                {"type": "tool_use", "id": "t2", "name": "Glob", "input": {}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 120, "output_tokens": 45, "cache_read_input_tokens": 100}
+        });
+        let (blocks, stop, usage) = parse_message_body(&body, false).unwrap();
+        assert_eq!(stop, StopReason::ToolUse);
+        assert_eq!(blocks.len(), 3);
+        assert!(matches!(&blocks[0], ContentBlock::Text { text } if text == "Reading the file."));
+        assert!(
+            matches!(&blocks[1], ContentBlock::ToolUse { id, name, input } if id == "t1" && name == "Read" && input["path"] == "a.rs")
+        );
+        assert!(
+            matches!(&blocks[2], ContentBlock::ToolUse { input, .. } if input == &serde_json::json!({}))
+        );
+        assert_eq!(
+            (
+                usage.input_tokens,
+                usage.output_tokens,
+                usage.cache_read_input_tokens
+            ),
+            (120, 45, 100)
+        );
+        let end = serde_json::json!({"content": [{"type": "text", "text": "Done."}], "stop_reason": "end_turn"});
+        assert_eq!(
+            parse_message_body(&end, false).unwrap().1,
+            StopReason::EndTurn
+        );
+        let cut = serde_json::json!({"content": []});
+        assert!(
+            parse_message_body(&cut, false)
+                .unwrap_err()
+                .to_string()
+                .contains("stop_reason")
+        );
+    }
+
     #[test]
     fn sse_max_tokens_stop() {
         let (_, stop, _usage) = parse_sse(&[
@@ -886,6 +2036,31 @@ mod tests {
         assert_eq!(usage.cache_read_input_tokens, 800);
     }
 
+    #[test]
+    fn sse_usage_takes_the_latest_message_delta() {
+        let (_, stop, usage) = parse_sse(&[
+            r#"event: message_start"#,
+            r#"data: {"type":"message_start","message":{"usage":{"input_tokens":900,"output_tokens":1}}}"#,
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"hi"}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            r#"event: message_delta"#,
+            r#"data: {"type":"message_delta","delta":{},"usage":{"output_tokens":120}}"#,
+            r#"event: message_delta"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":345}}"#,
+            r#"event: message_stop"#,
+            r#"data: {"type":"message_stop"}"#,
+        ])
+        .unwrap();
+        assert_eq!(stop, StopReason::EndTurn);
+        assert_eq!(usage.output_tokens, 345);
+        // Fields a delta leaves out keep their message_start values
+        assert_eq!(usage.input_tokens, 900);
+    }
+
     #[test]
     fn sse_usage_defaults_when_absent() {
         let (_, _, usage) = parse_sse(&[
@@ -905,6 +2080,130 @@ mod tests {
         assert_eq!(usage.cache_read_input_tokens, 0);
     }
 
+    /// Writer that counts flush calls and captures what was written.
+    #[derive(Default)]
+    struct CountingWriter {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn detects_context_overflow_error_body() {
+        let body = r#"{"type":"error","error":{"type":"invalid_request_error","message":"prompt is too long: 215463 tokens > 200000 maximum"}}"#;
+        assert!(is_context_overflow_error(body));
+        assert!(is_context_overflow_error(
+            "input length and `max_tokens` exceed context limit: 198000 + 16384 > 200000"
+        ));
+        let other = r#"{"type":"error","error":{"type":"invalid_request_error","message":"messages.1.content: field required"}}"#;
+        assert!(!is_context_overflow_error(other));
+    }
+
+    #[test]
+    fn drain_lines_rejects_oversized_line() {
+        let mut parser = SseParser::default();
+        let mut buf = String::new();
+        // Complete lines are consumed even when they are individually long
+        buf.push_str(&format!("event: {}\n", "x".repeat(100)));
+        drain_lines(&mut buf, &mut parser, 64).unwrap();
+        assert!(buf.is_empty());
+        // A newline-free data line growing chunk by chunk trips the guard
+        let mut result = Ok(());
+        for _ in 0..10 {
+            buf.push_str("data: aaaaaaaaaa");
+            result = drain_lines(&mut buf, &mut parser, 64);
+            if result.is_err() {
+                break;
+            }
+        }
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("oversized SSE line"), "{err}");
+        assert!(
+            buf.len() <= 64 + 16,
+            "guard should fire as soon as the cap is passed"
+        );
+    }
+
+    /// Writer whose bytes stay readable after being moved into a TextTee.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn text_tee_captures_streamed_prose_without_color() {
+        let buf = SharedBuf::default();
+        let mut parser = SseParser {
+            tee: Some(TextTee::from_writer(buf.clone())),
+            ..Default::default()
+        };
+        for line in [
+            r#"event: content_block_start"#,
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello "}}"#,
+            r#"event: content_block_delta"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"\u001b[1mworld\u001b[0m"}}"#,
+            r#"event: content_block_stop"#,
+            r#"data: {"type":"content_block_stop","index":0}"#,
+            r#"event: message_delta"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"end_turn"}}"#,
+        ] {
+            parser.process_line(line).unwrap();
+        }
+        parser.finish().unwrap();
+        let text = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, "Hello world\n");
+    }
+
+    #[test]
+    fn strip_ansi_removes_escape_sequences() {
+        assert_eq!(strip_ansi("\x1b[96mtool\x1b[0m: Bash"), "tool: Bash");
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+
+    #[test]
+    fn flush_buffer_batches_deltas() {
+        let mut out = CountingWriter::default();
+        let mut buf = FlushBuffer::new(Duration::from_secs(60));
+        for t in ["Hel", "lo", " wor", "ld"] {
+            buf.push(t, &mut out);
+        }
+        assert_eq!(out.flushes, 0, "deltas within the interval stay buffered");
+        buf.push("!\n", &mut out);
+        assert_eq!(out.flushes, 1, "newline forces a single flush");
+        let written = String::from_utf8(out.data).unwrap();
+        assert!(written.contains("Hello world!\n"), "{written:?}");
+    }
+
+    #[test]
+    fn flush_buffer_zero_interval_flushes_every_delta() {
+        let mut out = CountingWriter::default();
+        let mut buf = FlushBuffer::default();
+        for t in ["a", "b", "c"] {
+            buf.push(t, &mut out);
+        }
+        assert_eq!(out.flushes, 3);
+    }
+
     #[test]
     fn stop_reason_serialization() {
         assert_eq!(
@@ -927,6 +2226,97 @@ mod tests {
         assert_eq!(client.api_url, "https://example.com");
     }
 
+    #[test]
+    fn retryable_statuses() {
+        let err = |code: u16| AgentError::Status {
+            status: reqwest::StatusCode::from_u16(code).unwrap(),
+            retry_after: String::new(),
+            body: String::new(),
+        };
+        for code in [408, 409, 429, 500, 502, 503, 504, 529] {
+            let e = err(code);
+            assert!(
+                is_retryable(e.status(), e.body(), &e),
+                "{code} should retry"
+            );
+        }
+        for code in [400, 401, 403, 404, 413, 422] {
+            let e = err(code);
+            assert!(
+                !is_retryable(e.status(), e.body(), &e),
+                "{code} should not retry"
+            );
+        }
+        // A status verdict wins over the body
+        let e = AgentError::Status {
+            status: reqwest::StatusCode::UNAUTHORIZED,
+            retry_after: String::new(),
+            body: r#"{"type":"error","error":{"type":"api_error"}}"#.into(),
+        };
+        assert!(!is_retryable(e.status(), e.body(), &e));
+        assert_eq!(
+            e.to_string(),
+            format!("API returned 401 Unauthorized: {}", e.body())
+        );
+    }
+
+    #[test]
+    fn retryable_bodies_and_kinds() {
+        let overloaded =
+            AgentError::StreamParse("stream error (overloaded_error): Overloaded".into());
+        assert!(is_retryable(None, overloaded.body(), &overloaded));
+        let api_err = AgentError::StreamParse("stream error (api_error): Internal".into());
+        assert!(is_retryable(None, api_err.body(), &api_err));
+        let dropped = AgentError::StreamParse("stream ended without stop_reason".into());
+        assert!(is_retryable(None, dropped.body(), &dropped));
+        let invalid = AgentError::StreamParse("stream error (invalid_request_error): bad".into());
+        assert!(!is_retryable(None, invalid.body(), &invalid));
+        let overflow = AgentError::ContextOverflow("prompt is too long".into());
+        assert!(!is_retryable(None, overflow.body(), &overflow));
+        let json = AgentError::Json(serde_json::from_str::<Value>("{").unwrap_err());
+        assert!(!is_retryable(None, json.body(), &json));
+        assert!(!is_retryable(None, "", &AgentError::MissingApiKey));
+    }
+
+    #[tokio::test]
+    async fn connection_errors_are_retryable() {
+        // Nothing listens on port 1, so the connect fails
+        let e: AgentError = reqwest::Client::new()
+            .get("http://127.0.0.1:1")
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert!(is_retryable(None, e.body(), &e), "{e}");
+    }
+
+    #[test]
+    fn api_key_empty_or_blank_is_rejected() {
+        assert!(matches!(
+            api_key_from(Some(String::new())),
+            Err(AgentError::MissingApiKey)
+        ));
+        assert!(matches!(
+            api_key_from(Some(" \n\t".into())),
+            Err(AgentError::MissingApiKey)
+        ));
+        assert!(
+            AgentError::MissingApiKey
+                .to_string()
+                .contains("set but empty"),
+            "message should say the key is empty, not missing"
+        );
+    }
+
+    #[test]
+    fn api_key_is_trimmed_and_unset_is_allowed() {
+        assert_eq!(
+            api_key_from(Some("  sk-test\n".into())).unwrap().as_deref(),
+            Some("sk-test")
+        );
+        assert_eq!(api_key_from(None).unwrap(), None);
+    }
+
     #[test]
     fn client_api_key_from_env() {
         // When ANTHROPIC_API_KEY is not set, api_key should be None
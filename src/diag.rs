@@ -0,0 +1,115 @@
+//! Diagnostics facade: `diag!` prints to stderr like `eprintln!` and keeps the last
+//! `MAX_DIAGNOSTICS` lines (color stripped) for `diagnostics.log` in the session dir.
+
+use crate::api::strip_ansi;
+use std::{
+    collections::VecDeque,
+    fs,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+/// Lines kept; older ones are dropped first.
+pub const MAX_DIAGNOSTICS: usize = 500;
+
+static DIAGNOSTICS: Mutex<Diagnostics> = Mutex::new(Diagnostics::new(MAX_DIAGNOSTICS));
+
+/// `eprintln!` that also records the line for the session's diagnostics.log.
+macro_rules! diag {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        eprintln!("{line}");
+        $crate::diag::record(&line);
+    }};
+}
+pub(crate) use diag;
+
+/// Ring buffer of recent diagnostic lines.
+pub struct Diagnostics {
+    lines: VecDeque<String>,
+    cap: usize,
+}
+
+impl Diagnostics {
+    pub const fn new(cap: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            cap,
+        }
+    }
+
+    /// Add one message; multi-line messages take one slot per line.
+    pub fn push(&mut self, text: &str) {
+        for line in strip_ansi(text).lines() {
+            if self.lines.len() == self.cap {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(line.to_string());
+        }
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Write `diagnostics.log` under `dir`, oldest line first; nothing when empty.
+    pub fn write_to(&self, dir: &Path) -> std::io::Result<()> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)?;
+        let mut text = self.lines().collect::<Vec<_>>().join("\n");
+        text.push('\n');
+        fs::write(dir.join("diagnostics.log"), text)
+    }
+}
+
+pub fn record(line: &str) {
+    DIAGNOSTICS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(line);
+}
+
+/// Write the process-wide buffer to `dir/diagnostics.log`.
+pub fn flush(dir: &Path) {
+    let result = DIAGNOSTICS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .write_to(dir);
+    if let Err(e) = result {
+        eprintln!("[session] diagnostics.log error: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_last_lines_in_order_and_flushes_them() {
+        let mut diags = Diagnostics::new(3);
+        diags.push("\x1b[93m[warning]\x1b[0m first");
+        diags.push("tool: Read(a.rs)\n  second line");
+        diags.push("[context] Trimmed 4 messages");
+        diags.push("[retry] overloaded; retrying in 2s");
+        assert_eq!(
+            diags.lines().collect::<Vec<_>>(),
+            [
+                "  second line",
+                "[context] Trimmed 4 messages",
+                "[retry] overloaded; retrying in 2s"
+            ]
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("session");
+        diags.write_to(&out).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join("diagnostics.log")).unwrap(),
+            "  second line\n[context] Trimmed 4 messages\n[retry] overloaded; retrying in 2s\n"
+        );
+        let empty = dir.path().join("empty");
+        Diagnostics::new(3).write_to(&empty).unwrap();
+        assert!(!empty.exists());
+    }
+}
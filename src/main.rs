@@ -1,47 +1,214 @@
 mod api;
+mod diag;
 mod session;
+mod stats;
+mod status;
+mod theme;
 mod tools;
 
-use api::{AnthropicClient, ContentBlock, Message, Role, StopReason, color};
+use api::{
+    AgentError, AnthropicClient, Backend, ContentBlock, Effort, MIN_ANSWER_TOKENS, Message,
+    ReasoningMode, Role, StopReason, TextTee, ToolChoice, Usage, effort_settings, is_retryable,
+    max_output_tokens,
+};
 use clap::Parser;
-use std::io::{IsTerminal, Write};
-use tools::{all_tool_schemas, dispatch_tool};
+use diag::diag;
+use session::{COMPACT_RESULT_BYTES, Session, SessionFiles};
+use stats::ToolStats;
+use status::StatusLine;
+use std::{
+    collections::HashSet,
+    io::{IsTerminal, Write},
+    rc::Rc,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::Duration,
+};
+use theme::{Theme, ThemeName, color_enabled, disable_color, set_theme, theme};
+use tools::{
+    CommandRisk, DEFAULT_FORMATTERS, SearchEngine, ToolContext, ToolFixtures, all_tool_schemas,
+    apply_tool_overrides, check_tool_input, dispatch_tool, register_tools,
+};
 
-fn build_system_prompt() -> String {
-    let cwd = std::env::current_dir()
-        .map(|p| p.display().to_string())
-        .unwrap_or_else(|_| ".".into());
-    format!(
-        "You are a coding agent. Environment: {cwd} on {os}/{arch}\n\
+/// Inputs that change the system prompt beyond the detected environment.
+#[derive(Default)]
+struct PromptConfig {
+    /// Extra instructions appended after the built-in rules
+    append: Option<String>,
+    /// Bash working directory carries over between calls
+    persist_cwd: bool,
+    /// Directory named in the environment section (default: the process cwd)
+    cwd: Option<String>,
+    /// (path, contents) of each --pin-file, already bounded by `load_pinned_files`
+    pinned: Vec<(String, String)>,
+    /// Working-tree summary from `working_tree_summary` (--git-status)
+    git_status: Option<String>,
+}
+
+/// Per-file cap on --pin-file contents; the rest is cut with a note.
+const MAX_PINNED_BYTES: usize = 32 * 1024;
+
+/// Read each --pin-file, cutting oversized ones at `MAX_PINNED_BYTES`. Missing or binary
+/// files are left out, with a warning apiece.
+fn load_pinned_files(paths: &[std::path::PathBuf]) -> (Vec<(String, String)>, Vec<String>) {
+    let (mut pinned, mut warnings) = (Vec::new(), Vec::new());
+    for path in paths {
+        let shown = path.display().to_string();
+        let bytes = match std::fs::read(path) {
+            Ok(b) => b,
+            Err(e) => {
+                warnings.push(format!("--pin-file {shown}: {e}; not pinned"));
+                continue;
+            }
+        };
+        let Ok(mut text) = String::from_utf8(bytes) else {
+            warnings.push(format!("--pin-file {shown}: not UTF-8 text; not pinned"));
+            continue;
+        };
+        if text.len() > MAX_PINNED_BYTES {
+            let total = text.len();
+            text.truncate(text.floor_char_boundary(MAX_PINNED_BYTES));
+            text.push_str(&format!(
+                "\n[truncated: first {MAX_PINNED_BYTES} of {total} bytes pinned]"
+            ));
+            warnings.push(format!(
+                "--pin-file {shown}: {total} bytes, only the first {MAX_PINNED_BYTES} pinned"
+            ));
+        }
+        pinned.push((shown, text));
+    }
+    (pinned, warnings)
+}
+
+fn parse_formatter(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((ext, cmd)) if !ext.is_empty() && !cmd.trim().is_empty() => Ok((
+            ext.trim_start_matches('.').to_string(),
+            cmd.trim().to_string(),
+        )),
+        _ => Err(format!("expected EXT=COMMAND, got '{s}'")),
+    }
+}
+
+/// Default formatters with any `--formatter` overrides applied.
+fn formatter_map(overrides: &[(String, String)]) -> Vec<(String, String)> {
+    let mut map: Vec<(String, String)> = DEFAULT_FORMATTERS
+        .iter()
+        .filter(|(ext, _)| !overrides.iter().any(|(o, _)| o == ext))
+        .map(|(ext, cmd)| (ext.to_string(), cmd.to_string()))
+        .collect();
+    map.extend(overrides.iter().cloned());
+    map
+}
+
+/// The full system prompt as shown by `--print-system-prompt` and `/system`.
+fn build_system_prompt(config: &PromptConfig) -> String {
+    let (docs, env) = system_prompt_parts(config);
+    format!("{docs}\n\n{env}")
+}
+
+/// System prompt as API blocks: the static docs carry a cache breakpoint so they are
+/// billed once per session; the environment section after it may vary freely.
+fn system_prompt_blocks(config: &PromptConfig) -> serde_json::Value {
+    let (docs, env) = system_prompt_parts(config);
+    serde_json::json!([
+        {"type": "text", "text": docs, "cache_control": {"type": "ephemeral"}},
+        {"type": "text", "text": env}
+    ])
+}
+
+/// Split the prompt into static tool/workflow docs (identical across machines and
+/// directories) and the environment-specific section, which goes last. Pinned files
+/// join the docs, so they sit before the cache breakpoint.
+fn system_prompt_parts(config: &PromptConfig) -> (String, String) {
+    let cwd = config.cwd.clone().unwrap_or_else(|| {
+        std::env::current_dir()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|_| ".".into())
+    });
+    let docs = format!(
+        "You are a coding agent.\n\
          \n\
          # Tools\n\
          \n\
-         Read(path): Returns file contents with line numbers. 1MB limit. Detects binary files.\n\
+         Read(path, force_text?, start_byte?, end_byte?): Returns file contents with line numbers. 1MB limit. Detects binary files.\n\
+         - force_text=true reads a file flagged binary (e.g. UTF-16, embedded NULs) lossily.\n\
+         - start_byte/end_byte return that raw window (no line numbers), widened to UTF-8 boundaries — for minified files.\n\
          - Use BEFORE editing any file. Never edit blind.\n\
          - Prefer over Bash cat/head — gives line numbers for precise edits.\n\
+         - A repeat Read of an unchanged file returns \"(unchanged since last read)\" — you already have it.\n\
          \n\
          Glob(path?, recursive?): Lists files/dirs. Default: non-recursive. 1000 entry cap.\n\
          - Skips: .git, node_modules, target, .venv, vendor, .devenv\n\
          - Use to orient in unfamiliar directories before diving into files.\n\
          \n\
-         Bash(command, cwd?): Executes shell command. 120s timeout, 100KB output cap.\n\
+         Bash(command, cwd?, timeout_secs?, background?): Executes shell command. 120s default timeout (max 600), 100KB output cap.\n\
          - Non-zero exit = is_error. Use for builds, tests, git, installs.\n\
-         - Working directory resets each call — use cwd param or absolute paths.\n\
+         - background=true: for servers/watchers; returns a job id at once. Poll with JobOutput(id), stop with JobKill(id).\n\
+         - {cwd_rule}\n\
          - Never run destructive ops (rm -rf, force push, reset --hard) without user approval.\n\
          \n\
-         Edit(path, old_str, new_str, replace_all?): Surgical text replacement.\n\
+         Edit(path, old_str, new_str, replace_all?, reject_whitespace_only?): Surgical text replacement.\n\
          - old_str must match EXACTLY once unless replace_all=true.\n\
          - replace_all=true: replaces every occurrence (for renames, bulk changes).\n\
-         - old_str != new_str (no-op rejected).\n\
+         - old_str != new_str (no-op rejected). Whitespace-only changes are flagged (or refused with reject_whitespace_only).\n\
          - Empty old_str + existing file = append. Empty old_str + missing file = create (with mkdir).\n\
+         - Replays are safe: an append whose text already ends the file is skipped, and a replace whose old_str is gone but new_str is present says it may already be applied.\n\
          - On 'not found': re-read the file — likely whitespace/indentation mismatch.\n\
          - On 'found N times': include more context to make unique, or use replace_all.\n\
-         - Always verify: Read after editing to confirm the change.\n\
+         - The result shows the changed lines with context — check it instead of re-reading.\n\
+         - Never hardcode credentials; content that looks like a secret is flagged or refused.\n\
+         \n\
+         MultiEdit(path, edits[{{old_str, new_str, replace_all?}}]): Several edits to one file, atomically.\n\
+         - Edits apply in order; each sees the result of the previous one. File is written once.\n\
+         - If any edit fails (not found / not unique), nothing is written — fix that edit and retry.\n\
+         - Prefer over repeated Edit calls when changing several places in the same file.\n\
          \n\
          Grep(pattern, path?, file_type?, case_sensitive?): Wraps ripgrep.\n\
          - Regex patterns, case-insensitive by default. file_type: \"rust\", \"js\", \"py\", etc.\n\
-         - 50 match limit. Prefer over Bash grep/find for code search.\n\
-         - Use to find definitions, call sites, patterns before making changes.\n\
+         - 50 match limit, 30s timeout. Prefer over Bash grep/find for code search.\n\
+         - Use to find call sites and patterns before making changes.\n\
+         \n\
+         ReplaceInFiles(pattern, replacement, path?, file_type?, regex?, dry_run?): Rename across files.\n\
+         - Literal and case-sensitive unless regex=true ($1 captures). Run with dry_run=true first.\n\
+         - Prefer over many Edit calls for a symbol rename; check the per-file counts it reports.\n\
+         \n\
+         FindDefinition(symbol, lang?, path?): Jump to where a symbol is defined.\n\
+         - Returns the full fn/struct/class/def body with doc comments and line numbers.\n\
+         - Prefer over Grep + Read when you just need a definition.\n\
+         \n\
+         GitBlame(path, start?, end?): Last commit, date, author and summary per line range.\n\
+         - Use to learn why code is the way it is before changing it. 200 line cap.\n\
+         \n\
+         GitShow(path, rev?): A file as of a git revision (default HEAD), numbered like Read.\n\
+         - Compare with the working copy to see what changed, e.g. rev=\"HEAD~1\" or a branch.\n\
+         \n\
+         GitBranch(action, name?): create (and switch to), checkout, current or list branches.\n\
+         - Prefer over Bash git checkout; heed its warning when uncommitted changes carry over.\n\
+         \n\
+         EnvInfo(): OS/arch, cwd, git branch and installed tool versions in one call.\n\
+         - Use at the start of a task instead of several Bash version checks.\n\
+         \n\
+         ProjectStats(path?): File counts/sizes per extension, source line totals, largest files.\n\
+         - Use to gauge an unfamiliar project's scale before a recursive Glob.\n\
+         \n\
+         RunAndDiff(command, cwd?, timeout_secs?): Runs a command, returns the git diff of what it changed.\n\
+         - Use after codegen/formatters/migrations to see their effect in one call. Needs a git repo.\n\
+         \n\
+         Locate(query, path?): Ranked files (name and content hits) with a snippet each.\n\
+         - Start here for vague \"where is X\" questions; then Outline or Read the top hits.\n\
+         \n\
+         Outline(path): A file's functions/types/classes/impls and methods with line numbers.\n\
+         - Use on a large file before Read to find the lines you need.\n\
+         \n\
+         TailFile(path, lines?): The last lines of a file (default 50), numbered (from the end past 16MB); no size limit.\n\
+         - Use for long logs where only the end matters instead of Read or Bash tail.\n\
+         \n\
+         JobOutput(id) / JobKill(id): New output and state of a background Bash job / stop it.\n\
+         - Kill jobs you no longer need; any still running are killed when the session ends.\n\
          \n\
          # Workflow\n\
          \n\
@@ -54,32 +221,394 @@ fn build_system_prompt() -> String {
          # Rules\n\
          \n\
          - Minimal, focused changes. No unrelated refactoring or cleanups.\n\
+         - Paths excluded by .agentignore are off-limits; don't work around the refusal.\n\
+         - A result starting with [truncated: ...] is partial — narrow the command or pattern.\n\
          - On failure, analyze the error. Retrying the same action without changes is wasteful.\n\
          - Be concise in explanations. Show, don't tell.",
+        cwd_rule = if config.persist_cwd {
+            "Working directory persists across calls: a cd carries over (cwd param overrides)."
+        } else {
+            "Working directory resets each call — use cwd param or absolute paths."
+        },
+    );
+    let mut docs = docs;
+    if !config.pinned.is_empty() {
+        docs.push_str("\n\n# Pinned Context\n");
+        for (path, text) in &config.pinned {
+            docs.push_str(&format!("\n## {path}\n\n{}\n", text.trim_end()));
+        }
+        docs.truncate(docs.trim_end().len());
+    }
+    let mut env = format!(
+        "# Environment\n\n{cwd} on {os}/{arch}",
         os = std::env::consts::OS,
         arch = std::env::consts::ARCH,
-    )
+    );
+    if let Some(status) = &config.git_status {
+        env.push_str("\n\n");
+        env.push_str(status);
+    }
+    if let Some(extra) = config
+        .append
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        env.push_str("\n\n# Additional Instructions\n\n");
+        env.push_str(extra);
+    }
+    (docs, env)
 }
 
 const MAX_CONVERSATION_BYTES: usize = 720_000; // ~180K tokens at ~4 chars/token
+/// Context window the trimming budget must fit in, together with max_tokens.
+const CONTEXT_WINDOW_TOKENS: usize = 200_000;
 const MAX_TOOL_ITERATIONS: usize = 50; // Safety limit for tool dispatch loop
+/// Iteration counts at which the model is told how much tool budget is left.
+const BUDGET_WARN_AT: &[usize] = &[40, 45, 49];
+
+/// Append a remaining-budget note to the last tool_result once `iterations` hits a threshold.
+fn append_budget_note(tool_results: &mut [ContentBlock], iterations: usize) {
+    if !BUDGET_WARN_AT.contains(&iterations) {
+        return;
+    }
+    let left = MAX_TOOL_ITERATIONS - iterations;
+    if let Some(ContentBlock::ToolResult { content, .. }) = tool_results.last_mut() {
+        content.push_str(&format!(
+            "\n\n[note: {left} tool calls remaining before cutoff; wrap up soon]"
+        ));
+    }
+}
+
+/// Hard ceiling on one tool_result sent to the API, above every tool's own output cap.
+const MAX_TOOL_RESULT_BYTES: usize = 300 * 1024;
+/// Leading bytes of a spilled result kept inline.
+const SPILLED_PREVIEW_BYTES: usize = 32 * 1024;
+
+/// Replace any tool_result over MAX_TOOL_RESULT_BYTES with its head and a pointer to the
+/// full text, saved as `tool-<id>.txt` under the session directory `dir`.
+fn spill_oversized_results(results: &mut [ContentBlock], dir: &std::path::Path) {
+    for block in results {
+        let ContentBlock::ToolResult {
+            tool_use_id,
+            content,
+            ..
+        } = block
+        else {
+            continue;
+        };
+        if content.len() <= MAX_TOOL_RESULT_BYTES {
+            continue;
+        }
+        let total = content.len();
+        let path = dir.join(format!("tool-{tool_use_id}.txt"));
+        let saved = std::fs::create_dir_all(dir).and_then(|()| std::fs::write(&path, &*content));
+        content.truncate(content.floor_char_boundary(SPILLED_PREVIEW_BYTES));
+        match saved {
+            Ok(()) => content.push_str(&format!(
+                "\n... [{total} bytes; full output saved to {}; read it with Read (start_byte/end_byte) if needed]",
+                path.display()
+            )),
+            Err(e) => {
+                content.push_str(&format!("\n... [{total} bytes; truncated, saving failed: {e}]"))
+            }
+        }
+    }
+}
 
-/// Pop trailing User message on API error; if it was tool_results, also pop orphaned tool_use.
+/// Pop trailing User message on API error or interrupt; if it was tool_results, also pop
+/// the orphaned tool_use. A trailing tool_use whose results never arrived is popped too.
 fn recover_conversation(conversation: &mut Vec<Message>) {
-    let was_tool_results = conversation
-        .pop_if(|m| matches!(m.role, Role::User))
-        .is_some_and(|m| matches!(m.content.first(), Some(ContentBlock::ToolResult { .. })));
-    if was_tool_results {
-        conversation.pop_if(|m| matches!(m.role, Role::Assistant));
+    match conversation.pop_if(|m| matches!(m.role, Role::User)) {
+        Some(m) if matches!(m.content.first(), Some(ContentBlock::ToolResult { .. })) => {
+            conversation.pop_if(|m| matches!(m.role, Role::Assistant));
+        }
+        Some(_) => {}
+        None => {
+            conversation.pop_if(|m| {
+                m.role == Role::Assistant
+                    && m.content
+                        .iter()
+                        .any(|b| matches!(b, ContentBlock::ToolUse { .. }))
+            });
+        }
+    }
+}
+
+/// Unwind an interrupted turn to its last complete exchange, in the transcript as well as
+/// in memory. Completed tool results left last get a closing assistant note, so the next
+/// prompt does not land right after another user message.
+fn unwind_interrupted(
+    conversation: &mut Vec<Message>,
+    session: &mut Session,
+    sizes: &mut MessageSizes,
+) {
+    let before = conversation.len();
+    recover_conversation(conversation);
+    sizes.invalidate(conversation.len());
+    session.rewind(before - conversation.len());
+    if conversation.last().is_some_and(|m| m.role == Role::User) {
+        conversation.push(assistant_message("(interrupted by the user)"));
+        session.append_assistant_turn(conversation.last().unwrap(), &Usage::default());
+    }
+}
+
+/// Ctrl-C state shared between an interactive session's signal handler and `run_turn`.
+#[derive(Default)]
+struct Interrupt {
+    /// A turn is in progress; otherwise Ctrl-C quits as usual
+    turn_running: AtomicBool,
+    /// Ctrl-C was pressed during the current turn
+    requested: AtomicBool,
+    /// The session should end once the current step unwinds, through the usual cleanup
+    quit: AtomicBool,
+}
+
+impl Interrupt {
+    fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    fn begin_turn(&self) {
+        self.requested.store(false, Ordering::SeqCst);
+        self.turn_running.store(true, Ordering::SeqCst);
+    }
+
+    fn end_turn(&self) {
+        self.turn_running.store(false, Ordering::SeqCst);
+    }
+
+    /// Resolves once Ctrl-C is pressed; polled, so it can race a request in `select!`.
+    async fn wait(&self) {
+        while !self.requested() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+/// In an interactive session, Ctrl-C during a turn stops it at the next step (the
+/// foreground command gets the signal too). A second Ctrl-C, one at the prompt, or any
+/// in a scripted run quits: the turn unwinds and the main loop exits through `shutdown`,
+/// so background jobs are killed and session files written. Only a Ctrl-C while that
+/// is still pending exits at once.
+fn install_interrupt_handler(interrupt: Arc<Interrupt>, interactive: bool, status_line: bool) {
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            let (c, r) = (&theme().warning, &theme().reset);
+            if interrupt.quit.load(Ordering::SeqCst) {
+                if status_line {
+                    status::reset_scroll_region();
+                }
+                eprintln!();
+                std::process::exit(130);
+            }
+            let in_turn = interrupt.turn_running.load(Ordering::SeqCst);
+            if interactive && in_turn && !interrupt.requested.swap(true, Ordering::SeqCst) {
+                diag!("\n{c}[interrupt]{r} Stopping this turn; Ctrl-C again to quit");
+                continue;
+            }
+            interrupt.requested.store(true, Ordering::SeqCst);
+            interrupt.quit.store(true, Ordering::SeqCst);
+            if in_turn {
+                diag!("\n{c}[interrupt]{r} Quitting once the turn stops; Ctrl-C again to force");
+            }
+        }
+    });
+}
+
+/// Merge consecutive same-role messages (the API rejects them with a 400), keeping
+/// tool_result blocks at the front of a merged user message as the API requires.
+/// Returns the index of the first message merged into, if any.
+fn coalesce_roles(conversation: &mut Vec<Message>) -> Option<usize> {
+    let mut merged: Vec<Message> = Vec::with_capacity(conversation.len());
+    let mut first = None;
+    for msg in conversation.drain(..) {
+        let last = merged.len().checked_sub(1);
+        match merged.last_mut() {
+            Some(prev) if prev.role == msg.role => {
+                first = first.or(last);
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!("{c}[warning]{r} Merged consecutive {:?} messages", msg.role);
+                prev.content.extend(msg.content);
+                prev.content
+                    .sort_by_key(|b| !matches!(b, ContentBlock::ToolResult { .. }));
+            }
+            _ => merged.push(msg),
+        }
+    }
+    *conversation = merged;
+    first
+}
+
+/// Check the structural rules the API enforces, so a malformed history fails locally
+/// with a precise message instead of an opaque 400. Message numbers are 0-based.
+fn validate_conversation(conversation: &[Message]) -> Result<(), String> {
+    if conversation.first().is_some_and(|m| m.role != Role::User) {
+        return Err("message 0: conversation must start with a user message".into());
+    }
+    for (i, msg) in conversation.iter().enumerate() {
+        let role = format!("{:?}", msg.role).to_lowercase();
+        if msg.content.is_empty() {
+            return Err(format!("message {i} ({role}): empty content"));
+        }
+        if i > 0 && conversation[i - 1].role == msg.role {
+            return Err(format!(
+                "message {i} ({role}): follows another {role} message"
+            ));
+        }
+        let uses: Vec<&str> = msg
+            .content
+            .iter()
+            .filter_map(|b| match b {
+                ContentBlock::ToolUse { id, .. } => Some(id.as_str()),
+                _ => None,
+            })
+            .collect();
+        let next_results: Vec<&str> = conversation
+            .get(i + 1)
+            .into_iter()
+            .flat_map(|m| &m.content)
+            .filter_map(|b| match b {
+                ContentBlock::ToolResult { tool_use_id, .. } => Some(tool_use_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        if let Some(orphan) = uses.iter().find(|id| !next_results.contains(id)) {
+            return Err(format!(
+                "message {i} ({role}): tool_use {orphan} has no matching tool_result in message {}",
+                i + 1
+            ));
+        }
+        if msg.role == Role::User {
+            let prev_uses: Vec<&str> = i
+                .checked_sub(1)
+                .map(|p| &conversation[p].content)
+                .into_iter()
+                .flatten()
+                .filter_map(|b| match b {
+                    ContentBlock::ToolUse { id, .. } => Some(id.as_str()),
+                    _ => None,
+                })
+                .collect();
+            for b in &msg.content {
+                if let ContentBlock::ToolResult { tool_use_id, .. } = b
+                    && !prev_uses.contains(&tool_use_id.as_str())
+                {
+                    return Err(format!(
+                        "message {i} ({role}): tool_result {tool_use_id} has no tool_use in the previous message"
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serialized size of each message, kept across turns so trimming doesn't re-serialize
+/// the whole conversation every request. Entries line up with the conversation by
+/// position and are trusted as they are: whatever rewrites, removes or merges a message
+/// already measured must call `invalidate` (messages appended since the last `measure`
+/// are not cached yet and may change freely).
+#[derive(Default)]
+struct MessageSizes {
+    entries: Vec<usize>,
+}
+
+impl MessageSizes {
+    /// Current size of every message, measuring only those not yet cached.
+    fn measure(&mut self, conversation: &[Message]) -> Vec<usize> {
+        self.entries.truncate(conversation.len());
+        let cached = self.entries.len();
+        self.entries
+            .extend(conversation[cached..].iter().map(message_bytes));
+        self.entries.clone()
+    }
+
+    /// Forget the sizes of message `from` onward, after they were changed or removed.
+    fn invalidate(&mut self, from: usize) {
+        self.entries.truncate(from);
+    }
+
+    /// Keep entries aligned after the first `n` messages are drained.
+    fn drop_front(&mut self, n: usize) {
+        self.entries.drain(..n.min(self.entries.len()));
+    }
+}
+
+fn message_bytes(msg: &Message) -> usize {
+    serde_json::to_string(msg).map_or(0, |s| s.len())
+}
+
+/// Tool results smaller than this are not worth eliding.
+const MIN_COMPACTED_RESULT_BYTES: usize = 1024;
+
+/// Which tool results (message index, block index) to elide, oldest first, to bring a
+/// conversation of message `sizes` back under `threshold` bytes. Only exchanges before
+/// the latest user prompt are touched: those tool calls are resolved and the assistant
+/// text that followed them, their conclusion, stays. Empty at or under the threshold.
+fn plan_compaction(
+    conversation: &[Message],
+    sizes: &[usize],
+    threshold: usize,
+) -> Vec<(usize, usize)> {
+    let total: usize = sizes.iter().sum();
+    if total <= threshold {
+        return Vec::new();
+    }
+    let latest_prompt = conversation
+        .iter()
+        .rposition(|m| {
+            matches!(m.role, Role::User)
+                && m.content
+                    .first()
+                    .is_some_and(|b| matches!(b, ContentBlock::Text { .. }))
+        })
+        .unwrap_or(0);
+    let mut excess = total - threshold;
+    let mut plan = Vec::new();
+    for (i, msg) in conversation[..latest_prompt].iter().enumerate() {
+        for (j, block) in msg.content.iter().enumerate() {
+            if excess == 0 {
+                return plan;
+            }
+            if let ContentBlock::ToolResult { content, .. } = block
+                && content.len() >= MIN_COMPACTED_RESULT_BYTES
+            {
+                plan.push((i, j));
+                excess = excess.saturating_sub(content.len());
+            }
+        }
     }
+    plan
+}
+
+/// Replace the planned tool results with a short stub; returns the bytes elided.
+fn apply_compaction(conversation: &mut [Message], plan: &[(usize, usize)]) -> usize {
+    let mut elided = 0;
+    for &(i, j) in plan {
+        if let Some(ContentBlock::ToolResult { content, .. }) = conversation[i].content.get_mut(j) {
+            elided += content.len();
+            *content = format!(
+                "[earlier tool output elided to save context: {} bytes; run the tool again if it is still needed]",
+                content.len()
+            );
+        }
+    }
+    elided
 }
 
 /// Trim conversation at exchange boundaries, preserving tool_use/tool_result pairs.
 fn trim_conversation(conversation: &mut Vec<Message>, max_bytes: usize) {
-    let sizes: Vec<usize> = conversation
-        .iter()
-        .map(|m| serde_json::to_string(m).map_or(0, |s| s.len()))
-        .collect();
+    trim_conversation_cached(conversation, max_bytes, &mut MessageSizes::default());
+}
+
+/// `trim_conversation` reusing the sizes measured on earlier calls.
+fn trim_conversation_cached(
+    conversation: &mut Vec<Message>,
+    max_bytes: usize,
+    cache: &mut MessageSizes,
+) {
+    let sizes = cache.measure(conversation);
     let total: usize = sizes.iter().sum();
     if total <= max_bytes {
         return;
@@ -98,21 +627,25 @@ fn trim_conversation(conversation: &mut Vec<Message>, max_bytes: usize) {
     let keep_last = boundaries.len().saturating_sub(1);
     if keep_last == 0 {
         truncate_oversized_blocks(conversation, max_bytes);
+        cache.invalidate(0);
         return;
     }
-    let (c, r) = (color("\x1b[93m"), color("\x1b[0m"));
+    let (c, r) = (&theme().warning, &theme().reset);
     for &cut in &boundaries[1..=keep_last] {
         let prefix: usize = sizes[..cut].iter().sum();
         if total - prefix <= max_bytes {
-            eprintln!("{c}[context]{r} Trimmed {cut} messages ({prefix} bytes) to fit context");
+            diag!("{c}[context]{r} Trimmed {cut} messages ({prefix} bytes) to fit context");
             conversation.drain(..cut);
+            cache.drop_front(cut);
             return;
         }
     }
     let dropped = boundaries[keep_last];
-    eprintln!("{c}[context]{r} Trimmed to last exchange ({dropped} messages dropped)");
+    diag!("{c}[context]{r} Trimmed to last exchange ({dropped} messages dropped)");
     conversation.drain(..dropped);
+    cache.drop_front(dropped);
     truncate_oversized_blocks(conversation, max_bytes);
+    cache.invalidate(0);
 }
 
 fn truncate_oversized_blocks(conversation: &mut [Message], max_bytes: usize) {
@@ -143,221 +676,1727 @@ fn truncate_oversized_blocks(conversation: &mut [Message], max_bytes: usize) {
     }
 }
 
-#[derive(Parser)]
-#[command(name = "forgeflare", about = "Rust coding agent")]
-struct Cli {
-    #[arg(short, long)]
-    verbose: bool,
-    #[arg(short, long, default_value = "claude-opus-4-6")]
-    model: String,
-    #[arg(long, default_value = "16384")]
-    max_tokens: u32,
-    #[arg(
-        long,
-        env = "ANTHROPIC_API_URL",
-        default_value = "https://anthropic-oauth-proxy.tailfb3ea.ts.net"
-    )]
-    api_url: String,
+/// Halve the conversation after a context-overflow error: drop old exchanges, then
+/// shrink oversized blocks if the latest exchange alone is still too big.
+fn compact_after_overflow(conversation: &mut Vec<Message>) {
+    let total: usize = conversation
+        .iter()
+        .map(|m| serde_json::to_string(m).map_or(0, |s| s.len()))
+        .sum();
+    trim_conversation(conversation, total / 2);
+    truncate_oversized_blocks(conversation, total / 2);
 }
 
-#[tokio::main]
-async fn main() {
-    let cli = Cli::parse();
-    let client = AnthropicClient::new(&cli.api_url).unwrap_or_else(|e| {
-        eprintln!("Error: {e}");
-        std::process::exit(1);
-    });
-    let schemas = all_tool_schemas();
-    let system_prompt = build_system_prompt();
-    let cwd = std::env::current_dir()
-        .map(|p| p.display().to_string())
-        .unwrap_or_else(|_| ".".into());
-    let mut session = session::Session::new(&cwd, &cli.model);
-    if cli.verbose {
-        eprintln!("[verbose] API URL: {}", cli.api_url);
-        eprintln!("[verbose] Initialized {} tools", schemas.len());
-    }
-    let interactive = std::io::stdin().is_terminal();
-    if interactive {
-        println!("Chat with Claude (type 'exit' or Ctrl-D to quit)");
+/// Smallest a block is cut to when shrinking a request the gateway refused as too large.
+const MIN_SHRUNK_BLOCK_BYTES: usize = 1_000;
+
+/// Halve the request body after an HTTP 413: cut the largest text and tool-result
+/// blocks (biggest first) rather than dropping history, since one huge tool result is
+/// the usual cause. Returns the bytes removed; 0 when nothing was large enough to cut.
+fn shrink_largest_blocks(conversation: &mut [Message]) -> usize {
+    let total: usize = conversation
+        .iter()
+        .map(|m| serde_json::to_string(m).map_or(0, |s| s.len()))
+        .sum();
+    let mut blocks: Vec<&mut String> = conversation
+        .iter_mut()
+        .flat_map(|m| &mut m.content)
+        .filter_map(|block| match block {
+            ContentBlock::ToolResult { content, .. } => Some(content),
+            ContentBlock::Text { text } => Some(text),
+            _ => None,
+        })
+        .filter(|text| text.len() > MIN_SHRUNK_BLOCK_BYTES)
+        .collect();
+    blocks.sort_by_key(|text| std::cmp::Reverse(text.len()));
+    let mut needed = total / 2;
+    let mut removed = 0;
+    for text in blocks {
+        if needed == 0 {
+            break;
+        }
+        let keep = text
+            .len()
+            .saturating_sub(needed)
+            .max(MIN_SHRUNK_BLOCK_BYTES);
+        let end = text.floor_char_boundary(keep);
+        let cut = text.len() - end;
+        text.truncate(end);
+        text.push_str("\n... (truncated: request exceeded the API's size limit)");
+        needed = needed.saturating_sub(cut);
+        removed += cut;
     }
-    let mut conversation: Vec<Message> = Vec::new();
-    let mut piped_input = if !interactive {
-        let mut buf = String::new();
-        std::io::Read::read_to_string(&mut std::io::stdin(), &mut buf).ok();
-        Some(buf.trim().to_string()).filter(|s| !s.is_empty())
-    } else {
-        None
-    };
+    removed
+}
+
+/// Waits before each re-send of a transiently failed request.
+const SEND_BACKOFF: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(4),
+    Duration::from_secs(15),
+];
+
+/// Send, compacting and retrying once on a context-window overflow, shrinking the
+/// largest blocks and retrying once on HTTP 413, and re-sending after each `backoff`
+/// delay while `is_retryable` says the failure is transient.
+async fn send_with_retry<R>(
+    conversation: &mut Vec<Message>,
+    sizes: &mut MessageSizes,
+    backoff: &[Duration],
+    mut send: impl AsyncFnMut(&[Message]) -> Result<R, AgentError>,
+) -> Result<R, AgentError> {
+    let (c, r) = (&theme().warning, &theme().reset);
+    let mut compacted = false;
+    let mut shrunk = false;
+    let mut delays = backoff.iter();
     loop {
-        let input = match piped_input.take() {
-            Some(p) => p,
-            None if !interactive => break,
-            None => {
-                let (c, r) = (color("\x1b[94m"), color("\x1b[0m"));
-                print!("{c}You{r}: ");
-                std::io::stdout().flush().ok();
-                let mut line = String::new();
-                if std::io::stdin()
-                    .read_line(&mut line)
-                    .map_or(true, |n| n == 0)
-                {
-                    break;
-                }
-                let t = line.trim().to_string();
-                match t.as_str() {
-                    "" => continue,
-                    "exit" => break,
-                    _ => t,
-                }
-            }
-        };
-        if cli.verbose {
-            eprintln!("[verbose] User: {input}");
-        }
-        conversation.push(Message {
-            role: Role::User,
-            content: vec![ContentBlock::Text { text: input }],
-        });
-        session.append_user_turn(conversation.last().unwrap());
-        let mut tool_iterations = 0usize;
-        loop {
-            if tool_iterations >= MAX_TOOL_ITERATIONS {
-                let (c, r) = (color("\x1b[93m"), color("\x1b[0m"));
-                eprintln!(
-                    "{c}[warning]{r} Tool loop hit {MAX_TOOL_ITERATIONS} iterations, breaking"
-                );
-                recover_conversation(&mut conversation);
-                break;
-            }
-            if cli.verbose {
-                let n = conversation.len();
-                eprintln!("[verbose] Sending message, conversation len: {n}");
-            }
-            trim_conversation(&mut conversation, MAX_CONVERSATION_BYTES);
-            let (response, stop_reason, usage) = match client
-                .send_message(
-                    &conversation,
-                    &schemas,
-                    &cli.model,
-                    &system_prompt,
-                    cli.max_tokens,
-                )
-                .await
-            {
-                Ok(r) => r,
-                Err(e) => {
-                    let (c, r) = (color("\x1b[91m"), color("\x1b[0m"));
-                    eprintln!("{c}Error{r}: {e}");
-                    recover_conversation(&mut conversation);
-                    break;
-                }
-            };
-            if cli.verbose {
-                let n = response.len();
-                eprintln!("[verbose] Received {n} blocks, stop: {stop_reason:?}");
-            }
-            let mut response = response;
-            if response.is_empty() {
-                response.push(ContentBlock::Text {
-                    text: "(empty response)".into(),
-                });
-            }
-            conversation.push(Message {
-                role: Role::Assistant,
-                content: response,
-            });
-            session.append_assistant_turn(conversation.last().unwrap(), &usage);
-            if stop_reason != StopReason::ToolUse {
-                if stop_reason == StopReason::MaxTokens {
-                    let (c, r) = (color("\x1b[93m"), color("\x1b[0m"));
-                    eprintln!("{c}[warning]{r} Response truncated (max_tokens reached)");
-                    if let Some(msg) = conversation.last_mut() {
-                        msg.content.retain(|b| {
-                            !matches!(b, ContentBlock::ToolUse { input, .. } if input.is_null())
-                        });
-                        if msg.content.is_empty() {
-                            msg.content.push(ContentBlock::Text {
-                                text: "(truncated)".into(),
-                            });
-                        }
-                    }
-                }
-                break;
+        match send(conversation).await {
+            Err(AgentError::ContextOverflow(_)) if !compacted => {
+                diag!("{c}[context]{r} Context window exceeded; compacting and retrying once");
+                compact_after_overflow(conversation);
+                sizes.invalidate(0);
+                compacted = true;
             }
-            let mut tool_results: Vec<ContentBlock> = Vec::new();
-            for block in &conversation.last().unwrap().content {
-                if let ContentBlock::ToolUse { id, name, input } = block {
-                    if input.is_null() {
-                        let (c, r) = (color("\x1b[93m"), color("\x1b[0m"));
-                        eprintln!("{c}[warning]{r} Tool {name}: corrupt input (null)");
-                        tool_results.push(ContentBlock::ToolResult {
-                            tool_use_id: id.clone(),
-                            content: "tool input was corrupt (JSON parse failed)".into(),
-                            is_error: Some(true),
-                        });
-                        continue;
-                    }
-                    let (c, r) = (color("\x1b[96m"), color("\x1b[0m"));
-                    if cli.verbose {
-                        eprintln!("{c}tool{r}: {name}({input})");
-                    } else {
-                        eprintln!("{c}tool{r}: {name}");
-                    }
-                    let result = dispatch_tool(name, input.clone(), id, &mut |chunk| {
-                        eprint!("{chunk}");
-                    });
-                    if let ContentBlock::ToolResult {
-                        ref content,
-                        ref is_error,
-                        ..
-                    } = result
-                    {
-                        let (label, clr) = if is_error == &Some(true) {
-                            ("error", color("\x1b[91m"))
-                        } else {
-                            ("result", color("\x1b[92m"))
-                        };
-                        let r = color("\x1b[0m");
-                        if is_error == &Some(true) || cli.verbose {
-                            let t: String = content.chars().take(200).collect();
-                            eprintln!("{clr}{label}{r}: {t}");
-                        } else {
-                            eprintln!("{clr}{label}{r}: {} chars", content.len());
-                        }
-                    }
-                    tool_results.push(result);
+            Err(e) if !shrunk && e.status() == Some(reqwest::StatusCode::PAYLOAD_TOO_LARGE) => {
+                let removed = shrink_largest_blocks(conversation);
+                sizes.invalidate(0);
+                if removed == 0 {
+                    return Err(e);
                 }
+                diag!(
+                    "{c}[context]{r} Request too large (413); cut {removed} bytes from the largest blocks and retrying once"
+                );
+                shrunk = true;
             }
-            if tool_results.is_empty() {
-                break;
-            }
-            tool_iterations += 1;
-            if cli.verbose {
-                let n = tool_results.len();
-                eprintln!("[verbose] Sending {n} tool results (iteration {tool_iterations})");
+            Err(e) if is_retryable(e.status(), e.body(), &e) => {
+                let Some(delay) = delays.next() else {
+                    return Err(e);
+                };
+                diag!("{c}[retry]{r} {e}; retrying in {}s", delay.as_secs());
+                tokio::time::sleep(*delay).await;
             }
-            conversation.push(Message {
-                role: Role::User,
-                content: tool_results,
-            });
-            session.append_user_turn(conversation.last().unwrap());
+            other => return other,
         }
     }
-    session.write_supporting_files(&conversation);
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::Value;
+/// Cleanup on every way out of the main loop (end of input, exit, limits, Ctrl-C):
+/// release the status row, kill background jobs, write the session's supporting files
+/// and diagnostics, and disarm the crash hook.
+fn shutdown(opts: &mut DispatchOptions, session: &Session, conversation: &[Message]) {
+    // Release the reserved row before the exit report (process::exit skips Drop)
+    opts.status = None;
+    let killed = opts.tools.jobs.kill_all();
+    if killed > 0 {
+        diag!("[jobs] killed {killed} background job(s) still running");
+    }
+    session.write_supporting_files(conversation);
+    diag::flush(session.dir());
+    CRASH_STATE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+}
 
-    fn user_text(s: &str) -> Message {
-        Message {
-            role: Role::User,
-            content: vec![ContentBlock::Text {
-                text: s.to_string(),
-            }],
+/// Text blocks of the last assistant message, joined — the answer a script wants,
+/// without streamed tool chatter. Empty if the model never replied.
+fn final_answer(conversation: &[Message]) -> String {
+    conversation
+        .iter()
+        .rev()
+        .find(|m| m.role == Role::Assistant)
+        .map(|m| {
+            m.content
+                .iter()
+                .filter_map(|b| match b {
+                    ContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default()
+}
+
+/// Line separating prompts in piped stdin under --split-prompts.
+const PROMPT_DELIMITER: &str = "---";
+
+/// Scripted user turns: each `--prompt` in order, then piped stdin as a final turn
+/// (or, with `split`, one turn per `---`-separated section).
+fn initial_turns(prompts: &[String], piped: Option<&str>, split: bool) -> Vec<String> {
+    let mut sections: Vec<String> = Vec::new();
+    if let Some(piped) = piped {
+        if split {
+            sections.push(String::new());
+            for line in piped.lines() {
+                if line.trim_end() == PROMPT_DELIMITER {
+                    sections.push(String::new());
+                } else if let Some(cur) = sections.last_mut() {
+                    cur.push_str(line);
+                    cur.push('\n');
+                }
+            }
+        } else {
+            sections.push(piped.to_string());
+        }
+    }
+    prompts
+        .iter()
+        .map(String::as_str)
+        .chain(sections.iter().map(String::as_str))
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Session files and conversation as of the last user turn, for the panic hook.
+static CRASH_STATE: Mutex<Option<(SessionFiles, Vec<Message>)>> = Mutex::new(None);
+
+fn record_crash_state(session: &Session, conversation: &[Message]) {
+    *CRASH_STATE.lock().unwrap_or_else(PoisonError::into_inner) =
+        Some((session.files(), conversation.to_vec()));
+}
+
+/// Write the recorded session's supporting files and a `crashed` marker (what the panic
+/// hook runs). Takes the state, so it is written at most once; None if nothing was recorded.
+fn flush_crash_state(reason: &str) -> Option<std::path::PathBuf> {
+    let (files, conversation) = CRASH_STATE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()?;
+    match files.write_crashed(&conversation, reason) {
+        Ok(()) => {
+            diag::flush(files.dir());
+            Some(files.dir().to_path_buf())
+        }
+        Err(e) => {
+            diag!("[session] could not save crashed session: {e}");
+            None
+        }
+    }
+}
+
+/// After the default panic message, save the session so a crash mid-task keeps
+/// context.md and a marked transcript. Panics on helper threads (e.g. output readers)
+/// don't end the session and are left alone.
+fn install_crash_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+        if std::thread::current().name() == Some("main")
+            && let Some(dir) = flush_crash_state(&info.to_string())
+        {
+            diag!("[session] crashed; session saved to {}", dir.display());
+        }
+    }));
+}
+
+/// Result of waiting for one line of interactive input.
+#[derive(Debug, PartialEq)]
+enum ReadOutcome {
+    Line(String),
+    Eof,
+    TimedOut,
+    /// The `stop` flag was raised (Ctrl-C) while waiting
+    Stopped,
+}
+
+/// Read lines on a background thread so the prompt can wait with a timeout.
+/// The channel disconnects at EOF or on a read error.
+fn spawn_line_reader(mut reader: impl std::io::BufRead + Send + 'static) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(n) if n > 0 && tx.send(line).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    rx
+}
+
+/// How often a waiting prompt checks its `stop` flag.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Wait for the next line, giving up after `timeout` (None waits forever) or once
+/// `stop` is set.
+fn read_line_timeout(
+    lines: &mpsc::Receiver<String>,
+    timeout: Option<Duration>,
+    stop: &AtomicBool,
+) -> ReadOutcome {
+    let deadline = timeout.map(|t| std::time::Instant::now() + t);
+    loop {
+        if stop.load(Ordering::SeqCst) {
+            return ReadOutcome::Stopped;
+        }
+        let slice = deadline.map_or(STOP_POLL, |d| {
+            d.saturating_duration_since(std::time::Instant::now())
+                .min(STOP_POLL)
+        });
+        match lines.recv_timeout(slice) {
+            Ok(l) => return ReadOutcome::Line(l),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return ReadOutcome::Eof,
+            Err(mpsc::RecvTimeoutError::Timeout)
+                if deadline.is_some_and(|d| std::time::Instant::now() >= d) =>
+            {
+                return ReadOutcome::TimedOut;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+    }
+}
+
+/// Warn when an explicit ripgrep path (anything beyond a bare name) doesn't exist.
+fn rg_path_warning(rg_path: &std::path::Path, engine: SearchEngine) -> Option<String> {
+    let explicit = rg_path.components().count() > 1 || rg_path.is_absolute();
+    (engine == SearchEngine::Rg && explicit && !rg_path.is_file()).then(|| {
+        format!(
+            "--rg-path {} does not exist; Grep will fail (try --engine builtin)",
+            rg_path.display()
+        )
+    })
+}
+
+/// Cap on assistant responses across the whole run (--max-turns), distinct from the
+/// per-turn tool iteration limit.
+#[derive(Default)]
+struct TurnLimit {
+    max: Option<usize>,
+    used: usize,
+}
+
+impl TurnLimit {
+    /// Count one assistant response; true once the cap has been reached.
+    fn record(&mut self) -> bool {
+        self.used += 1;
+        self.max.is_some_and(|max| self.used >= max)
+    }
+}
+
+/// Most lines of an edit's -/+ view shown on the terminal.
+const MAX_DIFF_LINES: usize = 40;
+
+/// Terminal-only -/+ view of an Edit/MultiEdit input (red removals, green additions).
+/// The model and transcript get the plain tool result, never this.
+fn render_edit_diff(input: &serde_json::Value, theme: &Theme) -> String {
+    let (red, green, reset) = (&theme.error, &theme.result, &theme.reset);
+    let edits = match input["edits"].as_array() {
+        Some(edits) => edits.iter().collect(),
+        None => vec![input],
+    };
+    let mut lines = Vec::new();
+    for edit in edits {
+        let old = edit["old_str"].as_str().unwrap_or_default();
+        let new = edit["new_str"].as_str().unwrap_or_default();
+        lines.extend(old.lines().map(|l| format!("{red}- {l}{reset}")));
+        lines.extend(new.lines().map(|l| format!("{green}+ {l}{reset}")));
+    }
+    let total = lines.len();
+    lines.truncate(MAX_DIFF_LINES);
+    if total > MAX_DIFF_LINES {
+        lines.push(format!("  ... ({} more lines)", total - MAX_DIFF_LINES));
+    }
+    lines.join("\n")
+}
+
+/// Longest string value shown inline by `summarize_tool_input`.
+const MAX_SUMMARY_VALUE: usize = 60;
+
+/// One-line, human-friendly view of a tool call for verbose output, e.g.
+/// `Edit path=src/foo.rs (old 12 lines → new 15 lines)`. Long or multi-line strings
+/// are shortened; `-vv` prints the raw JSON instead.
+fn summarize_tool_input(name: &str, input: &serde_json::Value) -> String {
+    let lines = |v: &serde_json::Value| v.as_str().map_or(0, |s| s.lines().count());
+    let path = input["path"].as_str().unwrap_or("?");
+    match name {
+        "Edit" => {
+            let all = if input["replace_all"] == true {
+                ", all"
+            } else {
+                ""
+            };
+            format!(
+                "Edit path={path} (old {} lines → new {} lines{all})",
+                lines(&input["old_str"]),
+                lines(&input["new_str"])
+            )
+        }
+        "MultiEdit" => {
+            let n = input["edits"].as_array().map_or(0, Vec::len);
+            format!("MultiEdit path={path} ({n} edits)")
+        }
+        _ => {
+            let fields = input.as_object().into_iter().flatten().map(|(k, v)| {
+                let shown = match v {
+                    serde_json::Value::String(s) if s.contains('\n') => {
+                        format!("<{} lines>", s.lines().count())
+                    }
+                    serde_json::Value::String(s) => {
+                        let mut t: String = s.chars().take(MAX_SUMMARY_VALUE).collect();
+                        if t.len() < s.len() {
+                            t.push('…');
+                        }
+                        if t.contains(' ') { format!("{t:?}") } else { t }
+                    }
+                    serde_json::Value::Array(a) => format!("<{} items>", a.len()),
+                    serde_json::Value::Object(_) => "{…}".into(),
+                    other => other.to_string(),
+                };
+                format!("{k}={shown}")
+            });
+            std::iter::once(name.to_string())
+                .chain(fields)
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+}
+
+/// `Will run: A; B; C` for a response's tool calls, in order, each summarized by
+/// `summarize_tool_input` (--announce-tools). None when the response calls no tools.
+fn tool_plan(content: &[ContentBlock]) -> Option<String> {
+    let calls: Vec<String> = content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { name, input, .. } => {
+                let name = tools::resolve_tool_name(name).unwrap_or(name);
+                Some(summarize_tool_input(name, input))
+            }
+            _ => None,
+        })
+        .collect();
+    (!calls.is_empty()).then(|| format!("Will run: {}", calls.join("; ")))
+}
+
+/// Per-run knobs for the tool dispatch loop.
+#[derive(Default)]
+struct DispatchOptions {
+    verbose: bool,
+    /// Print tool inputs as raw JSON instead of a summary (-vv)
+    raw_inputs: bool,
+    /// Skip the remaining tool_use blocks of a turn once a Bash call fails
+    fail_fast: bool,
+    tools: ToolContext,
+    /// Plain-text copy of tool activity (--transcript-text)
+    transcript: Option<TextTee>,
+    /// Most tool_use blocks run from one assistant message; None = no cap
+    max_tool_uses: Option<usize>,
+    /// Asked before each Edit/MultiEdit/ReplaceInFiles is applied (--review-edits,
+    /// interactive only)
+    review: Option<EditReviewer>,
+    /// Per-tool calls, failures, time and bytes (/stats and the exit report)
+    stats: ToolStats,
+    /// Model, tokens and elapsed time on the terminal's bottom row (--status-line)
+    status: Option<StatusLine>,
+    /// Echo successful tool result content, dimmed and bounded (--show-results, /show)
+    show_results: bool,
+    /// Print the response's tool calls as one plan line before running them (--announce-tools)
+    announce_tools: bool,
+    /// Record tool durations as zero so the stats report is reproducible (--deterministic)
+    deterministic: bool,
+    /// Asked before a Bash command matching a risky pattern runs (interactive only);
+    /// without it such commands run and their result carries a caution
+    approve_risky: Option<CommandApprover>,
+    /// Risky commands (normalized) the user chose to always allow this session; /forget clears
+    approved_commands: HashSet<String>,
+    /// Set by Ctrl-C: calls not yet started are skipped and the turn is unwound
+    interrupt: Arc<Interrupt>,
+    /// End the run at the first failed tool result (--fail-on-tool-error, scripted only)
+    fail_on_tool_error: bool,
+    /// Serialized message sizes carried between turns for trimming
+    sizes: MessageSizes,
+    /// Percent of the conversation budget past which old tool output is elided ahead
+    /// of trimming (--compact-at); None = trim only
+    compact_at: Option<u8>,
+}
+
+/// Decides whether a risky Bash command (command, matched pattern) runs.
+type CommandApprover = Box<dyn FnMut(&str, &str) -> RiskyDecision>;
+
+/// Answer to a risky-command prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RiskyDecision {
+    Run,
+    /// Run it, and skip the prompt for the same command for the rest of the session
+    Always,
+    Decline,
+}
+
+/// `y`/`yes` runs the command and `a`/`always` also remembers it; anything else declines.
+fn parse_risky_answer(answer: &str) -> RiskyDecision {
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => RiskyDecision::Run,
+        "a" | "always" => RiskyDecision::Always,
+        _ => RiskyDecision::Decline,
+    }
+}
+
+/// Key for a remembered approval: the command with runs of whitespace collapsed.
+fn normalize_command(command: &str) -> String {
+    command.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Answer to a --review-edits prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReviewDecision {
+    Apply,
+    Skip,
+    /// Reject this edit and skip the rest of the turn's tool calls
+    Quit,
+}
+
+/// Decides whether one edit (tool name, input, the diff it would make) is applied.
+type EditReviewer = Box<dyn FnMut(&str, &serde_json::Value, &str) -> ReviewDecision>;
+
+/// `y`/`yes` applies and `q`/`quit` stops the turn; anything else skips the edit.
+fn parse_review_answer(answer: &str) -> ReviewDecision {
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => ReviewDecision::Apply,
+        "q" | "quit" => ReviewDecision::Quit,
+        _ => ReviewDecision::Skip,
+    }
+}
+
+/// Dispatch every tool_use block of an assistant message, returning tool_results in order.
+fn dispatch_tool_uses(content: &[ContentBlock], opts: &mut DispatchOptions) -> Vec<ContentBlock> {
+    let mut tool_results: Vec<ContentBlock> = Vec::new();
+    let mut failed: Option<&str> = None;
+    let mut stopped = false;
+    let mut seen = 0usize;
+    for block in content {
+        if let ContentBlock::ToolUse {
+            id,
+            name: requested,
+            input,
+        } = block
+        {
+            // A near-miss name (read-file, code_search) runs the tool it was meant to
+            let corrected = tools::resolve_tool_name(requested);
+            let name = corrected.unwrap_or(requested);
+            seen += 1;
+            if let Some(max) = opts.max_tool_uses.filter(|&max| seen > max) {
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!("{c}[skipped]{r} {name}: over {max} tool calls in one turn");
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: format!(
+                        "too many tool calls in one turn (max {max}); call fewer and re-plan"
+                    ),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            if opts.tools.deadline_passed() {
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!("{c}[skipped]{r} {name}: run deadline reached");
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: "skipped: the run deadline (--deadline) was reached".into(),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            if opts.interrupt.requested() {
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: "skipped: the user interrupted this turn".into(),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            if stopped {
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: "skipped: the user stopped this turn".into(),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            if let Some(prior) = failed {
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!("{c}[skipped]{r} {name}: prior {prior} call failed");
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: format!("skipped due to prior failure ({prior} returned an error)"),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            if input.is_null() {
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!("{c}[warning]{r} Tool {name}: corrupt input (null)");
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: "tool input was corrupt (JSON parse failed)".into(),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            let (c, r) = (&theme().tool, &theme().reset);
+            if opts.raw_inputs {
+                diag!("{c}tool{r}: {name}({input})");
+            } else if opts.verbose {
+                diag!("{c}tool{r}: {}", summarize_tool_input(name, input));
+            } else {
+                diag!("{c}tool{r}: {name}");
+            }
+            if let Some(tee) = &opts.transcript {
+                tee.write(&format!("tool: {name}({input})\n"));
+            }
+            let is_edit = matches!(name, "Edit" | "MultiEdit");
+            // A ReplaceInFiles dry run writes nothing; otherwise its preview is the diff
+            let diff = match name {
+                _ if opts.review.is_none() => None,
+                "Edit" | "MultiEdit" => Some(render_edit_diff(input, theme())),
+                "ReplaceInFiles" if input["dry_run"] != true => {
+                    tools::preview_replace(input, &mut opts.tools).ok()
+                }
+                _ => None,
+            };
+            if let (Some(review), Some(diff)) = (opts.review.as_mut(), diff) {
+                let rejection = match review(name, input, &diff) {
+                    ReviewDecision::Apply => None,
+                    ReviewDecision::Skip => Some("user rejected edit; do not retry it unchanged"),
+                    ReviewDecision::Quit => {
+                        stopped = true;
+                        Some("user rejected edit and stopped this turn")
+                    }
+                };
+                if let Some(content) = rejection {
+                    let (c, r) = (&theme().warning, &theme().reset);
+                    diag!("{c}[rejected]{r} {name}");
+                    tool_results.push(ContentBlock::ToolResult {
+                        tool_use_id: id.clone(),
+                        content: content.into(),
+                        is_error: Some(true),
+                    });
+                    continue;
+                }
+            }
+            let command = input["command"]
+                .as_str()
+                .filter(|_| matches!(name, "Bash" | "RunAndDiff"));
+            let risky = match command.map(|cmd| opts.tools.commands.classify_command(cmd)) {
+                Some(CommandRisk::Risky(pat)) => Some(pat.to_string()),
+                _ => None,
+            };
+            let decision = match (&risky, command, opts.approve_risky.as_mut()) {
+                (Some(pat), Some(cmd), Some(approve))
+                    if !opts.approved_commands.contains(&normalize_command(cmd)) =>
+                {
+                    Some((approve(cmd, pat), cmd))
+                }
+                _ => None,
+            };
+            if let Some((RiskyDecision::Always, cmd)) = decision {
+                opts.approved_commands.insert(normalize_command(cmd));
+            }
+            if let (Some(pat), Some((RiskyDecision::Decline, _))) = (&risky, decision) {
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!("{c}[rejected]{r} {name}");
+                tool_results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.clone(),
+                    content: format!(
+                        "user declined risky command (matches '{pat}'); do not retry it unchanged"
+                    ),
+                    is_error: Some(true),
+                });
+                continue;
+            }
+            let started = std::time::Instant::now();
+            let mut result =
+                dispatch_tool(name, input.clone(), id, &mut opts.tools, &mut |chunk| {
+                    eprint!("{chunk}");
+                });
+            if let (Some(pat), None, ContentBlock::ToolResult { content, .. }) =
+                (&risky, &opts.approve_risky, &mut result)
+            {
+                content.push_str(&format!(
+                    "\ncaution: this command matches risky pattern '{pat}' and ran without user approval; mention it to the user"
+                ));
+            }
+            if let (Some(real), ContentBlock::ToolResult { content, .. }) = (corrected, &mut result)
+            {
+                content.push_str(&format!(
+                    "\n[note: there is no tool named '{requested}'; ran {real} instead. Use the exact name]"
+                ));
+            }
+            if let ContentBlock::ToolResult {
+                content, is_error, ..
+            } = &result
+            {
+                let failed = is_error == &Some(true);
+                let elapsed = if opts.deterministic {
+                    Duration::ZERO
+                } else {
+                    started.elapsed()
+                };
+                opts.stats.record(name, elapsed, content.len(), failed);
+            }
+            if let ContentBlock::ToolResult {
+                ref content,
+                ref is_error,
+                ..
+            } = result
+            {
+                let failed_call = is_error == &Some(true);
+                let label = if failed_call { "error" } else { "result" };
+                let t: String = content.chars().take(200).collect();
+                eprintln!(
+                    "{}",
+                    render_tool_result(content, failed_call, opts.verbose, opts.show_results)
+                );
+                // A reviewed edit already showed its diff
+                if is_error.is_none() && is_edit && opts.review.is_none() {
+                    eprintln!("{}", render_edit_diff(input, theme()));
+                }
+                if let Some(tee) = &opts.transcript {
+                    tee.write(&format!("{label}: {t}\n\n"));
+                }
+                if opts.fail_fast && name == "Bash" && is_error == &Some(true) {
+                    failed = Some(name);
+                }
+            }
+            tool_results.push(result);
+        }
+    }
+    tool_results
+}
+
+/// Most characters of a result echoed by --show-results.
+const MAX_SHOWN_RESULT: usize = 2000;
+
+/// The line(s) printed after a tool call: errors (and every result under -v) show the
+/// first 200 chars; --show-results adds the content, dimmed and bounded, under the char
+/// count; otherwise only the count is shown.
+fn render_tool_result(content: &str, is_error: bool, verbose: bool, show_results: bool) -> String {
+    let (label, clr) = if is_error {
+        ("error", &theme().error)
+    } else {
+        ("result", &theme().result)
+    };
+    let r = &theme().reset;
+    if show_results && !is_error {
+        let (dim, n) = (&theme().context, content.chars().count());
+        let mut shown: String = content.chars().take(MAX_SHOWN_RESULT).collect();
+        if n > MAX_SHOWN_RESULT {
+            shown.push_str(&format!("\n... ({} more chars)", n - MAX_SHOWN_RESULT));
+        }
+        return format!("{clr}{label}{r}: {} chars\n{dim}{shown}{r}", content.len());
+    }
+    if is_error || verbose {
+        let t: String = content.chars().take(200).collect();
+        format!("{clr}{label}{r}: {t}")
+    } else {
+        format!("{clr}{label}{r}: {} chars", content.len())
+    }
+}
+
+/// What every request of a turn sends besides the conversation.
+struct TurnRequest<'a> {
+    schemas: &'a [serde_json::Value],
+    model: &'a str,
+    system: &'a serde_json::Value,
+    max_tokens: u32,
+    /// Conversation size kept by trimming (`conversation_budget(max_tokens)`)
+    conversation_bytes: usize,
+    /// Start of each turn's first response; the model continues from it (--prefill)
+    prefill: Option<&'a str>,
+    /// Tool use allowed, required, or disabled (--tool-choice)
+    tool_choice: ToolChoice,
+}
+
+/// Remove the tool calls a max_tokens cutoff left unfinished: no input at all, or an
+/// input that fails its tool's schema. Calls to tools without a schema are kept.
+fn drop_partial_tool_uses(msg: &mut Message, schemas: &[serde_json::Value]) {
+    msg.content.retain(|b| match b {
+        ContentBlock::ToolUse { input, .. } if input.is_null() => false,
+        ContentBlock::ToolUse { name, input, .. } => schemas
+            .iter()
+            .find(|s| s["name"] == name.as_str())
+            .is_none_or(|schema| check_tool_input(schema, input).is_ok()),
+        _ => true,
+    });
+}
+
+/// The body of the first request a turn over `conversation` would send, trimmed and
+/// prefilled as `run_turn` does (--dry-run-request).
+fn first_request_body(
+    client: &AnthropicClient,
+    conversation: &[Message],
+    request: &TurnRequest<'_>,
+) -> serde_json::Value {
+    let mut messages = conversation.to_vec();
+    trim_conversation(&mut messages, request.conversation_bytes);
+    coalesce_roles(&mut messages);
+    if let Some(text) = request.prefill {
+        messages.push(assistant_message(text));
+    }
+    client.request_body(
+        &messages,
+        request.schemas,
+        request.model,
+        request.system,
+        request.max_tokens,
+        request.tool_choice.to_request(true).as_ref(),
+    )
+}
+
+/// Validate --prefill: the API rejects a final assistant message ending in whitespace,
+/// and rejects prefill outright when extended thinking is on.
+fn check_prefill(prefill: Option<&str>, thinking: bool) -> Result<Option<String>, String> {
+    let Some(text) = prefill.map(str::trim_end) else {
+        return Ok(None);
+    };
+    if text.is_empty() {
+        return Err("--prefill is empty".into());
+    }
+    if thinking {
+        return Err("--prefill cannot be combined with --effort (extended thinking)".into());
+    }
+    Ok(Some(text.to_string()))
+}
+
+/// Validate --tool-choice against the registered tools and the options the API
+/// refuses to combine with forced tool use.
+fn check_tool_choice(
+    choice: &ToolChoice,
+    schemas: &[serde_json::Value],
+    thinking: bool,
+    prefill: bool,
+) -> Result<(), String> {
+    if let ToolChoice::Tool(name) = choice
+        && !schemas.iter().any(|s| s["name"] == name.as_str())
+    {
+        let hint = tools::resolve_tool_name(name)
+            .filter(|known| schemas.iter().any(|s| s["name"] == *known))
+            .map(|known| format!("; did you mean {known}?"))
+            .unwrap_or_default();
+        return Err(format!("--tool-choice: no tool named '{name}'{hint}"));
+    }
+    if choice.forces_tool() && thinking {
+        return Err(
+            "--tool-choice any/TOOL cannot be combined with --effort (extended thinking)".into(),
+        );
+    }
+    if choice.forces_tool() && prefill {
+        return Err("--tool-choice any/TOOL cannot be combined with --prefill".into());
+    }
+    Ok(())
+}
+
+/// Check --max-tokens (after any --effort adjustment) against `model`: 0 is rejected;
+/// above the model's output cap it is clamped with a warning, shrinking a thinking budget
+/// so the answer still has room. Returns (thinking budget, max_tokens, warning).
+fn check_max_tokens(
+    model: &str,
+    max_tokens: u32,
+    effort: Option<Effort>,
+) -> Result<(Option<u32>, u32, Option<String>), String> {
+    if max_tokens == 0 {
+        return Err("--max-tokens must be at least 1".into());
+    }
+    let (budget, wanted) = effort.map_or((None, max_tokens), |e| {
+        effort_settings(e, model, max_tokens)
+    });
+    let Some(cap) = max_output_tokens(model).filter(|&cap| wanted > cap) else {
+        return Ok((budget, wanted, None));
+    };
+    let budget = budget.map(|b| b.min(cap.saturating_sub(MIN_ANSWER_TOKENS)));
+    let warning = format!("max_tokens {wanted} exceeds {model}'s limit of {cap}; using {cap}");
+    Ok((budget, cap, Some(warning)))
+}
+
+/// Bytes of conversation kept by trimming so that, at ~4 bytes per token, the request
+/// plus a `max_tokens` reply fits the context window.
+fn conversation_budget(max_tokens: u32) -> usize {
+    let room = CONTEXT_WINDOW_TOKENS.saturating_sub(max_tokens as usize) * 4;
+    MAX_CONVERSATION_BYTES.min(room)
+}
+
+/// How one user turn ended.
+#[derive(Debug, Default)]
+struct TurnOutcome {
+    /// Text blocks of the last assistant message, joined
+    text: String,
+    /// None when the turn ended without a response (send error, invalid conversation,
+    /// or the tool iteration limit)
+    stop_reason: Option<StopReason>,
+    tools_run: usize,
+    /// Summed over every request of the turn
+    usage: Usage,
+    /// --max-turns was reached; the session should end
+    turn_limit_hit: bool,
+    /// `Tool: error` of the result that ended the run under --fail-on-tool-error
+    tool_failure: Option<String>,
+    /// --deadline passed; the session should end
+    deadline_hit: bool,
+    /// Ctrl-C stopped the turn; the conversation was unwound to its last complete exchange
+    interrupted: bool,
+}
+
+/// `Tool: first error line` for the first failed result, naming the tool from its tool_use.
+fn first_tool_error(calls: &[ContentBlock], results: &[ContentBlock]) -> Option<String> {
+    results.iter().find_map(|result| {
+        let ContentBlock::ToolResult {
+            tool_use_id,
+            content,
+            is_error: Some(true),
+        } = result
+        else {
+            return None;
+        };
+        let name = calls.iter().find_map(|c| match c {
+            ContentBlock::ToolUse { id, name, .. } if id == tool_use_id => Some(name.as_str()),
+            _ => None,
+        });
+        let line = content.lines().next().unwrap_or_default();
+        Some(format!("{}: {line}", name.unwrap_or("tool")))
+    })
+}
+
+fn assistant_message(text: &str) -> Message {
+    Message {
+        role: Role::Assistant,
+        content: vec![ContentBlock::Text {
+            text: text.to_string(),
+        }],
+    }
+}
+
+/// Run one user turn (the user message is already last in `conversation`): send,
+/// dispatch any tool_use blocks, and repeat until the model stops asking for tools.
+async fn run_turn(
+    client: &impl Backend,
+    conversation: &mut Vec<Message>,
+    request: &TurnRequest<'_>,
+    opts: &mut DispatchOptions,
+    session: &mut Session,
+    turns: &mut TurnLimit,
+) -> TurnOutcome {
+    let mut outcome = TurnOutcome::default();
+    let mut tool_iterations = 0usize;
+    loop {
+        if opts.interrupt.requested() {
+            unwind_interrupted(conversation, session, &mut opts.sizes);
+            outcome.stop_reason = None;
+            outcome.interrupted = true;
+            return outcome;
+        }
+        if opts.tools.deadline_passed() {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!("{c}[deadline]{r} run deadline reached, stopping");
+            outcome.deadline_hit = true;
+            return outcome;
+        }
+        if tool_iterations >= MAX_TOOL_ITERATIONS {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!("{c}[warning]{r} Tool loop hit {MAX_TOOL_ITERATIONS} iterations, breaking");
+            recover_conversation(conversation);
+            opts.sizes.invalidate(conversation.len());
+            outcome.stop_reason = None;
+            return outcome;
+        }
+        if opts.verbose {
+            let n = conversation.len();
+            diag!("[verbose] Sending message, conversation len: {n}");
+        }
+        if let Some(percent) = opts.compact_at {
+            let threshold = request.conversation_bytes / 100 * usize::from(percent);
+            let sizes = opts.sizes.measure(conversation);
+            let plan = plan_compaction(conversation, &sizes, threshold);
+            if !plan.is_empty() {
+                let elided = apply_compaction(conversation, &plan);
+                opts.sizes
+                    .invalidate(plan.iter().map(|&(i, _)| i).min().unwrap_or(0));
+                let (c, r) = (&theme().warning, &theme().reset);
+                diag!(
+                    "{c}[context]{r} Past {percent}% of the context budget: elided {} old tool results ({elided} bytes)",
+                    plan.len()
+                );
+            }
+        }
+        trim_conversation_cached(conversation, request.conversation_bytes, &mut opts.sizes);
+        if let Some(merged) = coalesce_roles(conversation) {
+            opts.sizes.invalidate(merged);
+        }
+        if let Err(e) = validate_conversation(conversation) {
+            let (c, r) = (&theme().error, &theme().reset);
+            diag!("{c}Error{r}: invalid conversation, not sending: {e}");
+            recover_conversation(conversation);
+            opts.sizes.invalidate(conversation.len());
+            outcome.stop_reason = None;
+            return outcome;
+        }
+        // Only the turn's first response continues the prefill; later ones follow tool results
+        let prefill = request.prefill.filter(|_| tool_iterations == 0);
+        let tool_choice = request.tool_choice.to_request(tool_iterations == 0);
+        if let Some(text) = prefill {
+            let (c, r) = (&theme().assistant, &theme().reset);
+            print!("{c}{text}{r}");
+            std::io::stdout().flush().ok();
+            if let Some(tee) = &opts.transcript {
+                tee.write(text);
+            }
+        }
+        let interrupt = Arc::clone(&opts.interrupt);
+        let sent = tokio::select! {
+            biased;
+            sent = send_with_retry(conversation, &mut opts.sizes, &SEND_BACKOFF, async |msgs| {
+                let prefilled: Vec<Message>;
+                let msgs = match prefill {
+                    Some(text) => {
+                        prefilled = [msgs, &[assistant_message(text)]].concat();
+                        &prefilled
+                    }
+                    None => msgs,
+                };
+                client
+                    .send_message(
+                        msgs,
+                        request.schemas,
+                        request.model,
+                        request.system,
+                        request.max_tokens,
+                        tool_choice.as_ref(),
+                    )
+                    .await
+            }) => Some(sent),
+            () = interrupt.wait() => None,
+        };
+        let Some(sent) = sent else {
+            unwind_interrupted(conversation, session, &mut opts.sizes);
+            outcome.stop_reason = None;
+            outcome.interrupted = true;
+            return outcome;
+        };
+        let (mut response, stop_reason, usage) = match sent {
+            Ok(r) => r,
+            Err(e) => {
+                let (c, r) = (&theme().error, &theme().reset);
+                diag!("{c}Error{r}: {e}");
+                recover_conversation(conversation);
+                opts.sizes.invalidate(conversation.len());
+                outcome.stop_reason = None;
+                return outcome;
+            }
+        };
+        if opts.verbose {
+            let n = response.len();
+            diag!("[verbose] Received {n} blocks, stop: {stop_reason:?}");
+        }
+        outcome.usage.add(&usage);
+        if let Some(status) = &mut opts.status {
+            status.add(&usage);
+        }
+        // The response only holds the continuation; record the turn as the model's whole reply
+        if let Some(text) = prefill {
+            match response.first_mut() {
+                Some(ContentBlock::Text { text: first }) => first.insert_str(0, text),
+                _ => response.insert(
+                    0,
+                    ContentBlock::Text {
+                        text: text.to_string(),
+                    },
+                ),
+            }
+        }
+        if response.is_empty() {
+            response.push(ContentBlock::Text {
+                text: "(empty response)".into(),
+            });
+        }
+        outcome.stop_reason = Some(stop_reason.clone());
+        conversation.push(Message {
+            role: Role::Assistant,
+            content: response,
+        });
+        outcome.text = final_answer(conversation);
+        // Strip a cut-off tool_use before it reaches the transcript, so resume cannot revive it
+        if stop_reason == StopReason::MaxTokens {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!("{c}[warning]{r} Response truncated (max_tokens reached)");
+            if let Some(msg) = conversation.last_mut() {
+                drop_partial_tool_uses(msg, request.schemas);
+                if msg.content.is_empty() {
+                    msg.content.push(ContentBlock::Text {
+                        text: "(truncated)".into(),
+                    });
+                }
+            }
+        }
+        session.append_assistant_turn(conversation.last().unwrap(), &usage);
+        if turns.record() {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!("{c}[limit]{r} max turns reached ({})", turns.used);
+            outcome.turn_limit_hit = true;
+            return outcome;
+        }
+        if stop_reason != StopReason::ToolUse {
+            return outcome;
+        }
+        if opts.announce_tools
+            && let Some(plan) = tool_plan(&conversation.last().unwrap().content)
+        {
+            let (c, r) = (&theme().tool, &theme().reset);
+            diag!("{c}[plan]{r} {plan}");
+            if let Some(tee) = &opts.transcript {
+                tee.write(&format!("plan: {plan}\n"));
+            }
+        }
+        let mut tool_results = dispatch_tool_uses(&conversation.last().unwrap().content, opts);
+        if tool_results.is_empty() {
+            return outcome;
+        }
+        outcome.tools_run += tool_results.len();
+        if let Some(status) = &opts.status {
+            status.draw();
+        }
+        spill_oversized_results(&mut tool_results, session.dir());
+        tool_iterations += 1;
+        append_budget_note(&mut tool_results, tool_iterations);
+        if opts.verbose {
+            let n = tool_results.len();
+            diag!("[verbose] Sending {n} tool results (iteration {tool_iterations})");
+        }
+        conversation.push(Message {
+            role: Role::User,
+            content: tool_results,
+        });
+        session.append_user_turn(conversation.last().unwrap());
+        if opts.fail_on_tool_error {
+            let n = conversation.len();
+            let failure =
+                first_tool_error(&conversation[n - 2].content, &conversation[n - 1].content);
+            if let Some(failure) = failure {
+                let (c, r) = (&theme().error, &theme().reset);
+                diag!("{c}[fatal]{r} --fail-on-tool-error: {failure}");
+                outcome.tool_failure = Some(failure);
+                return outcome;
+            }
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "forgeflare", about = "Rust coding agent")]
+struct Cli {
+    /// Show tool execution details (-vv: raw tool input JSON)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[arg(short, long, default_value = "claude-opus-4-6")]
+    model: String,
+    #[arg(long, default_value = "16384")]
+    max_tokens: u32,
+    #[arg(
+        long,
+        env = "ANTHROPIC_API_URL",
+        default_value = "https://anthropic-oauth-proxy.tailfb3ea.ts.net"
+    )]
+    api_url: String,
+    /// Extra instructions appended to the built-in system prompt
+    #[arg(long)]
+    append_system_prompt: Option<String>,
+    /// Start every reply with TEXT; the model continues from it (e.g. `{` for JSON)
+    #[arg(long, value_name = "TEXT")]
+    prefill: Option<String>,
+    /// Tool use: auto (model decides), none, any (must call one), or a tool name to
+    /// force on each turn's first request
+    #[arg(long, value_name = "auto|none|any|TOOL", default_value = "auto", value_parser = ToolChoice::parse)]
+    tool_choice: ToolChoice,
+    /// Summarize git status (branch, uncommitted files) in the system prompt
+    #[arg(long)]
+    git_status: bool,
+    /// Include PATH's contents in the system prompt on every turn (repeatable)
+    #[arg(long, value_name = "PATH")]
+    pin_file: Vec<std::path::PathBuf>,
+    /// Print the effective system prompt and exit
+    #[arg(long)]
+    print_system_prompt: bool,
+    /// Print the first turn's request body as JSON and exit without calling the API
+    #[arg(long)]
+    dry_run_request: bool,
+    /// Skip remaining tool calls in a turn after a Bash command fails
+    #[arg(long)]
+    fail_fast: bool,
+    /// In a non-interactive run, stop with exit code 3 at the first tool error
+    #[arg(long)]
+    fail_on_tool_error: bool,
+    /// Treat piped stdin as several prompts separated by lines containing only `---`
+    #[arg(long)]
+    split_prompts: bool,
+    /// User turn to send before exiting (repeatable; runs before any piped stdin)
+    #[arg(long, value_name = "TEXT")]
+    prompt: Vec<String>,
+    /// Stop after this many assistant responses (exit code 2 when non-interactive)
+    #[arg(long, value_name = "N")]
+    max_turns: Option<usize>,
+    /// End the run after SECS seconds of wall-clock time (exit code 4)
+    #[arg(long, value_name = "SECS")]
+    deadline: Option<u64>,
+    /// Exit an interactive session after this many seconds without input
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+    /// How to show model thinking: off, summary (one line per block), or full (dimmed)
+    #[arg(long, value_enum, default_value_t)]
+    show_reasoning: ReasoningMode,
+    /// Extended-thinking preset: low (off), medium, or high; raises --max-tokens if needed
+    #[arg(long, value_enum)]
+    effort: Option<Effort>,
+    /// Tee streamed assistant text and tool activity, uncolored, to this file
+    #[arg(long, value_name = "PATH")]
+    transcript_text: Option<std::path::PathBuf>,
+    /// Store large tool results in files next to full.jsonl instead of inline
+    #[arg(long)]
+    transcript_compact: bool,
+    /// Archive older sessions in .entire/metadata at startup: keep the newest N, or those
+    /// from the last N days with `Nd` (e.g. 30d)
+    #[arg(long, value_name = "N|Nd", value_parser = session::Retention::parse)]
+    session_retention: Option<session::Retention>,
+    /// Continue the conversation recorded in this session directory
+    #[arg(long, value_name = "DIR")]
+    resume: Option<std::path::PathBuf>,
+    /// Run a formatter on files after Edit/MultiEdit, chosen by extension
+    #[arg(long)]
+    format_on_edit: bool,
+    /// Formatter for an extension, as EXT=COMMAND (repeatable; overrides the default)
+    #[arg(long, value_name = "EXT=COMMAND", value_parser = parse_formatter)]
+    formatter: Vec<(String, String)>,
+    /// Search backend for Grep
+    #[arg(long, value_enum, default_value_t)]
+    engine: SearchEngine,
+    /// ripgrep executable for the rg engine (name on PATH or a path)
+    #[arg(long, env = "RG_PATH", value_name = "PATH", default_value = "rg")]
+    rg_path: std::path::PathBuf,
+    /// Refuse edits whose new content looks like a secret (default: warn only)
+    #[arg(long)]
+    block_secrets: bool,
+    /// JSONL of canned tool results returned instead of running the tool (for replay)
+    #[arg(long, value_name = "PATH")]
+    tool_fixtures: Option<std::path::PathBuf>,
+    /// JSON object of tool name -> new description (or {description, input_schema})
+    #[arg(long, value_name = "PATH")]
+    tool_overrides: Option<std::path::PathBuf>,
+    /// Elide old tool output once the conversation passes PERCENT of its budget
+    #[arg(long, value_name = "PERCENT", default_value = "75", value_parser = clap::value_parser!(u8).range(1..=100))]
+    compact_at: u8,
+    /// Most tool calls run from one assistant message; extras get an error result
+    #[arg(long, value_name = "N", default_value = "16")]
+    max_tool_calls: usize,
+    /// Show each edit's diff and ask before applying it (interactive sessions only)
+    #[arg(long)]
+    review_edits: bool,
+    /// Keep the Bash working directory across calls (a `cd` carries over)
+    #[arg(long)]
+    persist_cwd: bool,
+    /// Disable the destructive-command blocklist for this run (disposable sandboxes only)
+    #[arg(long)]
+    dangerously_allow_all: bool,
+    /// Request a single JSON response instead of SSE (for proxies that break streaming)
+    #[arg(long)]
+    no_stream: bool,
+    /// Write the final assistant answer (text only, no tool calls) to this file at exit
+    #[arg(long, value_name = "PATH")]
+    final_answer: Option<std::path::PathBuf>,
+    /// Max milliseconds streamed text is buffered before flushing (0 = every delta)
+    #[arg(long, default_value = "16")]
+    flush_interval_ms: u64,
+    /// Pin model, token totals and elapsed time to the terminal's bottom row (off under NO_COLOR)
+    #[arg(long)]
+    status_line: bool,
+    /// Print tool result content (dimmed, first 2000 chars) instead of only its length
+    #[arg(long)]
+    show_results: bool,
+    /// Before running a response's tool calls, print them as one "Will run: ..." line
+    #[arg(long)]
+    announce_tools: bool,
+    /// Color palette; plain turns off all styling
+    #[arg(long, env = "FORGEFLARE_THEME", value_enum, default_value_t)]
+    theme: ThemeName,
+    /// Per-role colors as ROLE=SGR pairs, e.g. "assistant=36,warning=1;33"
+    /// (roles: assistant, user, tool, result, error, warning, context)
+    #[arg(long, env = "FORGEFLARE_COLORS", value_name = "SPEC")]
+    colors: Option<String>,
+    /// Fixed session id, timestamps and uuids, no color or status line, zero tool timings:
+    /// identical runs write byte-identical transcripts (for golden-file tests)
+    #[arg(long)]
+    deterministic: bool,
+    /// Extra Bash pattern to refuse outright (repeatable; case/whitespace-insensitive)
+    #[arg(long, value_name = "TEXT")]
+    blocked_pattern: Vec<String>,
+    /// Extra Bash pattern that needs approval, or gets a caution when unattended (repeatable)
+    #[arg(long, value_name = "TEXT")]
+    risky_pattern: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if cli.deterministic || cli.theme == ThemeName::Plain {
+        disable_color();
+    }
+    if let Some(spec) = &cli.colors {
+        match Theme::default().with_overrides(spec) {
+            Ok(theme) => set_theme(theme),
+            Err(e) => {
+                diag!("Error: --colors: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let (pinned, pin_warnings) = load_pinned_files(&cli.pin_file);
+    for warning in pin_warnings {
+        let (c, r) = (&theme().warning, &theme().reset);
+        diag!("{c}[warning]{r} {warning}");
+    }
+    let prompt_config = PromptConfig {
+        append: cli.append_system_prompt.clone(),
+        persist_cwd: cli.persist_cwd,
+        cwd: None,
+        pinned,
+        git_status: cli
+            .git_status
+            .then(|| tools::working_tree_summary(std::path::Path::new(".")))
+            .flatten(),
+    };
+    let system_prompt = build_system_prompt(&prompt_config);
+    let system_blocks = system_prompt_blocks(&prompt_config);
+    if cli.print_system_prompt {
+        println!("{system_prompt}");
+        return;
+    }
+    let (thinking_budget, max_tokens) =
+        match check_max_tokens(&cli.model, cli.max_tokens, cli.effort) {
+            Ok((budget, max_tokens, warning)) => {
+                if let Some(warning) = warning {
+                    let (c, r) = (&theme().warning, &theme().reset);
+                    diag!("{c}[warning]{r} {warning}");
+                }
+                (budget, max_tokens)
+            }
+            Err(e) => {
+                diag!("Error: {e}");
+                std::process::exit(1);
+            }
+        };
+    let prefill =
+        check_prefill(cli.prefill.as_deref(), thinking_budget.is_some()).unwrap_or_else(|e| {
+            diag!("Error: {e}");
+            std::process::exit(1);
+        });
+    let stdin_is_tty = std::io::stdin().is_terminal();
+    if cli.dry_run_request && stdin_is_tty && cli.prompt.is_empty() {
+        diag!("Error: --dry-run-request needs a prompt (--prompt or piped stdin)");
+        std::process::exit(1);
+    }
+    // --prompt makes the run scripted: its turns (plus any piped stdin) run, then exit
+    let interactive = stdin_is_tty && cli.prompt.is_empty();
+    let client = AnthropicClient::new(&cli.api_url).unwrap_or_else(|e| {
+        diag!("Error: {e}");
+        std::process::exit(1);
+    });
+    // Buffering only helps a terminal; piped output is passed through unbuffered
+    let flush_interval = if std::io::stdout().is_terminal() {
+        Duration::from_millis(cli.flush_interval_ms)
+    } else {
+        Duration::ZERO
+    };
+    let mut client = client
+        .with_flush_interval(flush_interval)
+        .with_reasoning(cli.show_reasoning)
+        .with_thinking(thinking_budget)
+        .with_streaming(!cli.no_stream)
+        .with_arg_progress(interactive && std::io::stderr().is_terminal() && color_enabled());
+    let transcript = cli.transcript_text.as_deref().map(|path| {
+        TextTee::create(path).unwrap_or_else(|e| {
+            diag!("Error: --transcript-text {}: {e}", path.display());
+            std::process::exit(1);
+        })
+    });
+    if let Some(tee) = &transcript {
+        client = client.with_text_tee(tee.clone());
+    }
+    let mut schemas = register_tools(all_tool_schemas(), Vec::new()).unwrap_or_else(|e| {
+        diag!("Error: {e}");
+        std::process::exit(1);
+    });
+    if let Some(path) = &cli.tool_overrides {
+        let applied = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            .and_then(|overrides| apply_tool_overrides(&mut schemas, &overrides));
+        if let Err(e) = applied {
+            diag!("Error: --tool-overrides {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = check_tool_choice(
+        &cli.tool_choice,
+        &schemas,
+        thinking_budget.is_some(),
+        prefill.is_some(),
+    ) {
+        diag!("Error: {e}");
+        std::process::exit(1);
+    }
+    let mut tools = ToolContext::default();
+    if cli.dangerously_allow_all {
+        let (c, r) = (&theme().error, &theme().reset);
+        diag!("{c}[DANGER]{r} --dangerously-allow-all: destructive-command blocklist is DISABLED");
+        tools.dangerously_allow_all = true;
+    }
+    tools.commands.blocked.extend(cli.blocked_pattern);
+    tools.commands.risky.extend(cli.risky_pattern);
+    tools.persist_cwd = cli.persist_cwd;
+    tools.deadline = cli
+        .deadline
+        .map(|secs| std::time::Instant::now() + Duration::from_secs(secs));
+    tools.search_engine = cli.engine;
+    if let Some(warning) = rg_path_warning(&cli.rg_path, cli.engine) {
+        let (c, r) = (&theme().warning, &theme().reset);
+        diag!("{c}[warning]{r} {warning}");
+    }
+    tools.rg_path = cli.rg_path;
+    tools.block_secrets = cli.block_secrets;
+    if let Some(path) = &cli.tool_fixtures {
+        tools.fixtures = ToolFixtures::load(path).unwrap_or_else(|e| {
+            diag!("Error: --tool-fixtures {e}");
+            std::process::exit(1);
+        });
+        if cli.verbose > 0 {
+            diag!("[verbose] Loaded {} tool fixtures", tools.fixtures.len());
+        }
+    }
+    if cli.format_on_edit {
+        tools.formatters = formatter_map(&cli.formatter);
+    }
+    let mut opts = DispatchOptions {
+        verbose: cli.verbose > 0,
+        raw_inputs: cli.verbose > 1,
+        fail_fast: cli.fail_fast,
+        tools,
+        transcript,
+        max_tool_uses: Some(cli.max_tool_calls),
+        review: None,
+        stats: ToolStats::default(),
+        status: None,
+        show_results: cli.show_results,
+        announce_tools: cli.announce_tools,
+        approve_risky: None,
+        approved_commands: HashSet::new(),
+        interrupt: Arc::default(),
+        deterministic: cli.deterministic,
+        fail_on_tool_error: false,
+        sizes: MessageSizes::default(),
+        compact_at: Some(cli.compact_at).filter(|&p| p < 100),
+    };
+    let cwd = std::env::current_dir()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| ".".into());
+    let mut session = if cli.deterministic {
+        let session = Session::deterministic(&cwd, &cli.model);
+        // Same id every run: start over rather than append to the last run's transcript
+        let _ = std::fs::remove_file(session.dir().join("full.jsonl"));
+        session
+    } else {
+        Session::new(&cwd, &cli.model)
+    };
+    if cli.transcript_compact {
+        session = session.with_compact_results(COMPACT_RESULT_BYTES);
+    }
+    // Sessions started in this process, oldest first; the last one is active
+    let mut branches = vec![session.id().to_string()];
+    install_crash_hook();
+    if cli.verbose > 0 {
+        diag!("[verbose] API URL: {}", cli.api_url);
+        diag!("[verbose] Initialized {} tools", schemas.len());
+        if let Some(budget) = thinking_budget {
+            diag!("[verbose] Thinking budget: {budget} tokens (max_tokens {max_tokens})");
+        }
+    }
+    if interactive {
+        println!(
+            "Chat with Claude (type 'exit' or Ctrl-D to quit, '/system' to show prompt, '/fork' to branch, '/branches' to list forks, '/stats' for tool stats, '/show' to toggle tool results, '/forget' to clear 'always' approvals)"
+        );
+    }
+    let idle_timeout = cli.idle_timeout.map(Duration::from_secs);
+    let mut conversation: Vec<Message> = Vec::new();
+    if let Some(dir) = &cli.resume {
+        let (loaded, warnings) = session::load_conversation(dir).unwrap_or_else(|e| {
+            diag!("Error: --resume: {e}");
+            std::process::exit(1);
+        });
+        for warning in warnings {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!("{c}[warning]{r} --resume: {warning}");
+        }
+        conversation = loaded;
+        coalesce_roles(&mut conversation);
+        if let Err(e) = validate_conversation(&conversation) {
+            diag!("Error: --resume: {e}");
+            std::process::exit(1);
+        }
+        session.replay(&conversation);
+    }
+    if let (Some(retention), Some(root)) = (cli.session_retention, session.dir().parent()) {
+        let mut keep = vec![session.dir()];
+        keep.extend(cli.resume.as_deref());
+        let (archived, warnings) =
+            session::sweep_sessions(root, retention, &keep, std::time::SystemTime::now());
+        let (c, r) = (&theme().warning, &theme().reset);
+        for warning in warnings {
+            diag!("{c}[warning]{r} --session-retention: {warning}");
+        }
+        if !archived.is_empty() {
+            diag!(
+                "[sessions] Archived {} old session(s) to .tar.gz",
+                archived.len()
+            );
+        }
+    }
+    if let Some(dir) = &cli.resume
+        && cli.verbose > 0
+    {
+        let n = conversation.len();
+        diag!("[verbose] Resumed {n} messages from {}", dir.display());
+    }
+    let piped = if !stdin_is_tty {
+        let mut buf = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut buf).ok();
+        Some(buf)
+    } else {
+        None
+    };
+    let mut queued = initial_turns(&cli.prompt, piped.as_deref(), cli.split_prompts).into_iter();
+    // Piped stdin was consumed above; only a terminal needs the line reader
+    let stdin_lines = Rc::new(if interactive {
+        spawn_line_reader(std::io::BufReader::new(std::io::stdin()))
+    } else {
+        mpsc::channel().1
+    });
+    if cli.review_edits && !interactive && cli.verbose > 0 {
+        diag!("[verbose] --review-edits ignored: not an interactive session");
+    }
+    if cli.review_edits && interactive {
+        let lines = Rc::clone(&stdin_lines);
+        let interrupt = Arc::clone(&opts.interrupt);
+        opts.review = Some(Box::new(move |name, input, diff| {
+            let path = input["path"].as_str().unwrap_or(".");
+            eprintln!("{diff}");
+            eprint!("Apply {name} to {path}? [y]es / [n]o / [q]uit turn: ");
+            std::io::stderr().flush().ok();
+            match read_line_timeout(&lines, None, &interrupt.requested) {
+                ReadOutcome::Line(l) => parse_review_answer(&l),
+                _ => ReviewDecision::Quit,
+            }
+        }));
+    }
+    opts.fail_on_tool_error = cli.fail_on_tool_error && !interactive;
+    if cli.status_line && color_enabled() && !cli.deterministic {
+        opts.status = StatusLine::start(&cli.model);
+    }
+    install_interrupt_handler(
+        Arc::clone(&opts.interrupt),
+        interactive,
+        opts.status.is_some(),
+    );
+    if interactive {
+        let lines = Rc::clone(&stdin_lines);
+        let interrupt = Arc::clone(&opts.interrupt);
+        opts.approve_risky = Some(Box::new(move |command, pattern| {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!("{c}[risky]{r} {command}");
+            eprint!("Matches '{pattern}'. Run it? [y]es / [a]lways this session / [n]o: ");
+            std::io::stderr().flush().ok();
+            match read_line_timeout(&lines, None, &interrupt.requested) {
+                ReadOutcome::Line(l) => parse_risky_answer(&l),
+                _ => RiskyDecision::Decline,
+            }
+        }));
+    }
+    let mut turns = TurnLimit {
+        max: cli.max_turns,
+        used: 0,
+    };
+    let request = TurnRequest {
+        schemas: &schemas,
+        model: &cli.model,
+        system: &system_blocks,
+        max_tokens,
+        conversation_bytes: conversation_budget(max_tokens),
+        prefill: prefill.as_deref(),
+        tool_choice: cli.tool_choice.clone(),
+    };
+    if cli.dry_run_request {
+        let text = queued.next().unwrap_or_default();
+        conversation.push(Message {
+            role: Role::User,
+            content: vec![ContentBlock::Text { text }],
+        });
+        let body = first_request_body(&client, &conversation, &request);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&body).unwrap_or_default()
+        );
+        return;
+    }
+    let mut turn_limit_hit = false;
+    let mut tool_failed = false;
+    let mut deadline_hit = false;
+    loop {
+        if opts.tools.deadline_passed() {
+            deadline_hit = true;
+            break;
+        }
+        if opts.interrupt.quit.load(Ordering::SeqCst) {
+            break;
+        }
+        let input = match queued.next() {
+            Some(p) => p,
+            None if !interactive => break,
+            None => {
+                let (c, r) = (&theme().user, &theme().reset);
+                print!("{c}You{r}: ");
+                std::io::stdout().flush().ok();
+                let quit = &opts.interrupt.quit;
+                let line = match read_line_timeout(&stdin_lines, idle_timeout, quit) {
+                    ReadOutcome::Line(line) => line,
+                    ReadOutcome::Eof => break,
+                    ReadOutcome::Stopped => {
+                        println!();
+                        break;
+                    }
+                    ReadOutcome::TimedOut => {
+                        let secs = idle_timeout.unwrap_or_default().as_secs();
+                        println!("\nIdle for {secs}s, ending session. Goodbye!");
+                        break;
+                    }
+                };
+                let t = line.trim().to_string();
+                match t.as_str() {
+                    "" => continue,
+                    "exit" => break,
+                    "/system" => {
+                        println!("{system_prompt}");
+                        continue;
+                    }
+                    "/fork" => {
+                        let source = session.id().to_string();
+                        session = session.fork(&conversation);
+                        record_crash_state(&session, &conversation);
+                        branches.push(format!("{} (forked from {source})", session.id()));
+                        println!("Forked into session {}", session.id());
+                        continue;
+                    }
+                    "/stats" => {
+                        println!("{}", opts.stats.report());
+                        continue;
+                    }
+                    "/show" => {
+                        opts.show_results = !opts.show_results;
+                        let state = if opts.show_results { "on" } else { "off" };
+                        println!("Tool result display {state}");
+                        continue;
+                    }
+                    "/forget" => {
+                        let n = opts.approved_commands.len();
+                        opts.approved_commands.clear();
+                        println!("Forgot {n} remembered command approval(s)");
+                        continue;
+                    }
+                    "/branches" => {
+                        for (i, b) in branches.iter().enumerate() {
+                            let mark = if i + 1 == branches.len() { "*" } else { " " };
+                            println!("{mark} {b}");
+                        }
+                        continue;
+                    }
+                    _ => t,
+                }
+            }
+        };
+        if opts.verbose {
+            diag!("[verbose] User: {input}");
+        }
+        if let Some(tee) = &opts.transcript {
+            tee.write(&format!("You: {input}\n\n"));
+        }
+        conversation.push(Message {
+            role: Role::User,
+            content: vec![ContentBlock::Text { text: input }],
+        });
+        session.append_user_turn(conversation.last().unwrap());
+        record_crash_state(&session, &conversation);
+        opts.interrupt.begin_turn();
+        let outcome = run_turn(
+            &client,
+            &mut conversation,
+            &request,
+            &mut opts,
+            &mut session,
+            &mut turns,
+        )
+        .await;
+        opts.interrupt.end_turn();
+        if outcome.interrupted {
+            let (c, r) = (&theme().warning, &theme().reset);
+            diag!(
+                "{c}[interrupt]{r} Turn stopped; the conversation is back at its last complete exchange"
+            );
+        }
+        record_crash_state(&session, &conversation);
+        if outcome.turn_limit_hit {
+            turn_limit_hit = true;
+            break;
+        }
+        if outcome.tool_failure.is_some() {
+            tool_failed = true;
+            break;
+        }
+        if outcome.deadline_hit || opts.tools.deadline_passed() {
+            deadline_hit = true;
+            break;
+        }
+    }
+    if deadline_hit {
+        let (c, r) = (&theme().warning, &theme().reset);
+        diag!("{c}[deadline]{r} --deadline reached; ending the run");
+    }
+    shutdown(&mut opts, &session, &conversation);
+    if opts.stats.total().calls > 0 {
+        eprintln!("{}", opts.stats.report());
+    }
+    if let Some(path) = &cli.final_answer
+        && let Err(e) = std::fs::write(path, final_answer(&conversation))
+    {
+        diag!("Error: --final-answer {}: {e}", path.display());
+    }
+    if turn_limit_hit && !interactive {
+        std::process::exit(2);
+    }
+    if tool_failed {
+        std::process::exit(3);
+    }
+    if deadline_hit {
+        std::process::exit(4);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn user_text(s: &str) -> Message {
+        Message {
+            role: Role::User,
+            content: vec![ContentBlock::Text {
+                text: s.to_string(),
+            }],
         }
     }
 
@@ -370,371 +2409,1455 @@ mod tests {
         }
     }
 
-    fn assistant_tool_use() -> Message {
-        Message {
+    fn assistant_tool_use() -> Message {
+        Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::ToolUse {
+                id: "t1".into(),
+                name: "Bash".into(),
+                input: serde_json::json!({"command": "ls"}),
+            }],
+        }
+    }
+
+    fn user_tool_result(content: &str) -> Message {
+        Message {
+            role: Role::User,
+            content: vec![ContentBlock::ToolResult {
+                tool_use_id: "t1".into(),
+                content: content.to_string(),
+                is_error: None,
+            }],
+        }
+    }
+
+    fn conversation_bytes(msgs: &[Message]) -> usize {
+        msgs.iter()
+            .map(|m| serde_json::to_string(m).unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn compaction_plans_only_past_the_threshold_and_keeps_conclusions() {
+        let mut conv = vec![
+            user_text("first question"),
+            assistant_tool_use(),
+            user_tool_result(&"a".repeat(20_000)),
+            assistant_text("the config lives in forge.toml"),
+            user_text("second question"),
+            assistant_tool_use(),
+            user_tool_result(&"b".repeat(20_000)),
+        ];
+        let sizes: Vec<usize> = conv.iter().map(message_bytes).collect();
+        let total: usize = sizes.iter().sum();
+        assert!(plan_compaction(&conv, &sizes, total).is_empty());
+        assert!(plan_compaction(&conv, &sizes, total + 1).is_empty());
+
+        // Over the threshold: the resolved exchange goes, the current one never does
+        let plan = plan_compaction(&conv, &sizes, total - 1);
+        assert_eq!(plan, [(2, 0)]);
+        assert_eq!(plan_compaction(&conv, &sizes, 0), [(2, 0)]);
+        assert_eq!(apply_compaction(&mut conv, &plan), 20_000);
+        assert!(conversation_bytes(&conv) < total - 19_000);
+        assert!(matches!(
+            &conv[2].content[0],
+            ContentBlock::ToolResult { content, .. } if content.starts_with("[earlier tool output elided")
+        ));
+        assert_eq!(final_answer(&conv[..4]), "the config lives in forge.toml");
+        // Already stubbed: nothing left worth eliding
+        let sizes: Vec<usize> = conv.iter().map(message_bytes).collect();
+        assert!(plan_compaction(&conv, &sizes, 0).is_empty());
+    }
+
+    #[test]
+    fn cached_sizes_match_fresh_sizes_after_mutations() {
+        let fresh = |conv: &[Message]| conv.iter().map(message_bytes).collect::<Vec<_>>();
+        let mut cache = MessageSizes::default();
+        let mut conv = vec![
+            user_text("first question"),
+            assistant_tool_use(),
+            user_tool_result(&"x".repeat(40_000)),
+            assistant_text("first answer"),
+        ];
+        assert_eq!(cache.measure(&conv), fresh(&conv));
+        conv.push(user_text("second question"));
+        conv.push(assistant_text("second answer"));
+        assert_eq!(cache.measure(&conv), fresh(&conv));
+        // A same-length rewrite plus a flag change: only the explicit invalidation catches it
+        if let ContentBlock::ToolResult {
+            content, is_error, ..
+        } = &mut conv[2].content[0]
+        {
+            *content = "y".repeat(40_000);
+            *is_error = Some(true);
+        }
+        cache.invalidate(2);
+        assert_eq!(cache.measure(&conv), fresh(&conv));
+        truncate_oversized_blocks(&mut conv, 20_000);
+        cache.invalidate(0);
+        assert_eq!(cache.measure(&conv), fresh(&conv));
+        conv.pop();
+        conv.push(user_text("merged"));
+        let merged = coalesce_roles(&mut conv);
+        assert_eq!(merged, Some(4));
+        cache.invalidate(4);
+        assert_eq!(cache.measure(&conv), fresh(&conv));
+
+        // The cached trim drops the same messages as an uncached one, and stays aligned
+        let budget = conversation_bytes(&conv) - 1;
+        let mut uncached = conv.clone();
+        trim_conversation(&mut uncached, budget);
+        trim_conversation_cached(&mut conv, budget, &mut cache);
+        assert_eq!(conv.len(), uncached.len());
+        assert_eq!(
+            serde_json::to_string(&conv).unwrap(),
+            serde_json::to_string(&uncached).unwrap()
+        );
+        assert_eq!(cache.entries.len(), conv.len());
+        assert_eq!(cache.measure(&conv), fresh(&conv));
+    }
+
+    #[test]
+    fn trim_no_op_when_under_budget() {
+        let mut conv = vec![user_text("hello"), assistant_text("hi")];
+        let original_len = conv.len();
+        trim_conversation(&mut conv, 100_000);
+        assert_eq!(conv.len(), original_len);
+    }
+
+    #[test]
+    fn trim_removes_oldest_exchange() {
+        let mut conv = vec![
+            user_text("first question"),
+            assistant_text("first answer"),
+            user_text("second question"),
+            assistant_text("second answer"),
+            user_text("third question"),
+            assistant_text("third answer"),
+        ];
+        // Set budget to fit 2 exchanges but not 3
+        let two_exchange_size = conversation_bytes(&conv[2..]);
+        trim_conversation(&mut conv, two_exchange_size);
+        assert_eq!(conv.len(), 4); // exchanges 2 and 3 remain
+        assert!(
+            matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "second question")
+        );
+    }
+
+    #[test]
+    fn trim_preserves_tool_use_pairing() {
+        // Exchange 1: user text -> assistant tool_use -> user tool_result -> assistant text
+        // Exchange 2: user text -> assistant text
+        let mut conv = vec![
+            user_text("read the file"),
+            assistant_tool_use(),
+            user_tool_result("file contents here"),
+            assistant_text("I see the file"),
+            user_text("thanks"),
+            assistant_text("you're welcome"),
+        ];
+        // Budget fits only exchange 2
+        let last_exchange_size = conversation_bytes(&conv[4..]);
+        trim_conversation(&mut conv, last_exchange_size);
+        assert_eq!(conv.len(), 2);
+        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "thanks"));
+    }
+
+    #[test]
+    fn trim_never_splits_tool_exchange() {
+        // Ensure tool_use and tool_result stay together
+        let mut conv = vec![
+            user_text("q1"),
+            assistant_tool_use(),
+            user_tool_result("result1"),
+            assistant_text("a1"),
+            user_text("q2"),
+            assistant_tool_use(),
+            user_tool_result("result2"),
+            assistant_text("a2"),
+        ];
+        // Budget fits exchange 2 but not both
+        let exchange2_size = conversation_bytes(&conv[4..]);
+        trim_conversation(&mut conv, exchange2_size);
+        // Should cut at index 4 (user text "q2"), keeping tool pair intact
+        assert_eq!(conv.len(), 4);
+        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "q2"));
+        assert!(matches!(&conv[1].content[0], ContentBlock::ToolUse { .. }));
+        assert!(matches!(
+            &conv[2].content[0],
+            ContentBlock::ToolResult { .. }
+        ));
+    }
+
+    #[test]
+    fn trim_single_exchange_untouched() {
+        let mut conv = vec![
+            user_text(&"x".repeat(10_000)),
+            assistant_text(&"y".repeat(10_000)),
+        ];
+        // Budget smaller than the single exchange — can't trim further
+        trim_conversation(&mut conv, 100);
+        assert_eq!(conv.len(), 2); // preserved, nothing to cut
+    }
+
+    #[test]
+    fn trim_large_tool_result_triggers_trim() {
+        let big_result = "x".repeat(500_000);
+        let mut conv = vec![
+            user_text("old question"),
+            assistant_text("old answer"),
+            user_text("read big file"),
+            assistant_tool_use(),
+            user_tool_result(&big_result),
+            assistant_text("that's a big file"),
+            user_text("now what"),
+            assistant_text("let me help"),
+        ];
+        // Budget that can't hold everything but can hold last 2 exchanges
+        let last_two_size = conversation_bytes(&conv[2..]);
+        let budget = last_two_size; // fits exchanges 2+3 but not 1+2+3
+        trim_conversation(&mut conv, budget);
+        assert!(conv.len() < 8); // something was trimmed
+        // First remaining message should be a user text (exchange boundary)
+        assert!(matches!(&conv[0].content[0], ContentBlock::Text { .. }));
+    }
+
+    #[test]
+    fn trim_fallback_when_everything_huge() {
+        let huge = "x".repeat(400_000);
+        let mut conv = vec![
+            user_text(&huge),
+            assistant_text(&huge),
+            user_text("small"),
+            assistant_text("small"),
+        ];
+        // Budget too small for even the last exchange of the big ones
+        trim_conversation(&mut conv, 1000);
+        // Should trim to last exchange
+        assert_eq!(conv.len(), 2);
+        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "small"));
+    }
+
+    #[test]
+    fn trim_empty_conversation() {
+        let mut conv: Vec<Message> = Vec::new();
+        trim_conversation(&mut conv, 100); // should not panic
+        assert!(conv.is_empty());
+    }
+
+    #[test]
+    fn truncate_oversized_single_exchange() {
+        // When a single exchange has a huge tool result that exceeds the budget,
+        // trim_conversation falls back to truncating content blocks
+        let huge_result = "x".repeat(800_000); // 800KB > 720KB budget
+        let mut conv = vec![
+            user_text("read the big file"),
+            assistant_tool_use(),
+            user_tool_result(&huge_result),
+            assistant_text("got it"),
+        ];
+        // Budget much smaller than the huge result
+        let budget = 100_000;
+        trim_conversation(&mut conv, budget);
+        // Conversation should still have all 4 messages (single exchange can't be split)
+        assert_eq!(conv.len(), 4);
+        // But the huge tool result should be truncated
+        if let ContentBlock::ToolResult { content, .. } = &conv[2].content[0] {
+            assert!(
+                content.len() < huge_result.len(),
+                "content should be truncated"
+            );
+            assert!(
+                content.contains("truncated to fit context window"),
+                "should have truncation marker"
+            );
+        } else {
+            panic!("expected ToolResult");
+        }
+    }
+
+    #[test]
+    fn truncate_oversized_skips_small_blocks() {
+        // Only blocks >10KB should be eligible for truncation
+        let mut conv = vec![
+            user_text("small text that should not be truncated"),
+            assistant_text(&"y".repeat(5_000)), // 5KB — below threshold
+        ];
+        // Even with tiny budget, small blocks should not be truncated
+        truncate_oversized_blocks(&mut conv, 100);
+        assert!(
+            matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "small text that should not be truncated")
+        );
+    }
+
+    #[test]
+    fn truncate_oversized_multibyte_char_boundary() {
+        // Regression: truncate_oversized_blocks used to panic when `keep` fell on the
+        // second byte of a multi-byte UTF-8 character. 'é' is 2 bytes (0xC3 0xA9);
+        // if the computed keep position lands on 0xA9, String::truncate panics.
+        // The fix uses floor_char_boundary to snap to the nearest valid boundary.
+        let text = "é".repeat(6_000); // 12KB of 2-byte chars → eligible (>10KB)
+        let mut conv = vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::Text { text }],
+        }];
+        // Budget tiny enough to force truncation, should not panic
+        truncate_oversized_blocks(&mut conv, 100);
+        if let ContentBlock::Text { text } = &conv[0].content[0] {
+            assert!(text.contains("truncated"), "should have truncation marker");
+            // Verify the truncated text is valid UTF-8 (no panic on iteration)
+            assert!(text.chars().count() > 0);
+        } else {
+            panic!("expected Text block");
+        }
+    }
+
+    #[test]
+    fn partial_tool_use_filtered_on_truncation() {
+        // Simulates what happens when MaxTokens truncates mid-tool_use:
+        // the ToolUse block has input: Value::Null because content_block_stop never fired
+        let mut msg = Message {
             role: Role::Assistant,
-            content: vec![ContentBlock::ToolUse {
-                id: "t1".into(),
-                name: "Bash".into(),
-                input: serde_json::json!({"command": "ls"}),
-            }],
+            content: vec![
+                ContentBlock::Text {
+                    text: "Let me check".into(),
+                },
+                ContentBlock::ToolUse {
+                    id: "t1".into(),
+                    name: "Bash".into(),
+                    input: serde_json::json!({"command": "ls"}),
+                },
+                ContentBlock::ToolUse {
+                    id: "t2".into(),
+                    name: "Read".into(),
+                    input: Value::Null, // partial — never completed
+                },
+            ],
+        };
+        drop_partial_tool_uses(&mut msg, &all_tool_schemas());
+        assert_eq!(msg.content.len(), 2);
+        assert!(matches!(&msg.content[0], ContentBlock::Text { .. }));
+        assert!(matches!(&msg.content[1], ContentBlock::ToolUse { name, .. } if name == "Bash"));
+    }
+
+    #[test]
+    fn schema_invalid_partial_tool_use_filtered_on_truncation() {
+        // Cut off mid-input, the fragment still parsed to something: an Edit missing
+        // new_str, and a Read whose offset arrived as a string
+        let mut msg = Message {
+            role: Role::Assistant,
+            content: vec![
+                ContentBlock::ToolUse {
+                    id: "t1".into(),
+                    name: "Edit".into(),
+                    input: serde_json::json!({"path": "src/main.rs", "old_str": "fn main"}),
+                },
+                ContentBlock::ToolUse {
+                    id: "t2".into(),
+                    name: "Read".into(),
+                    input: serde_json::json!({"path": "a.rs", "start_byte": "12"}),
+                },
+                ContentBlock::ToolUse {
+                    id: "t3".into(),
+                    name: "Glob".into(),
+                    input: serde_json::json!({"pattern": "*.rs"}),
+                },
+                ContentBlock::ToolUse {
+                    id: "t4".into(),
+                    name: "mcp_lookup".into(),
+                    input: serde_json::json!({"q": 1}),
+                },
+            ],
+        };
+        drop_partial_tool_uses(&mut msg, &all_tool_schemas());
+        let ids: Vec<&str> = msg
+            .content
+            .iter()
+            .filter_map(|b| match b {
+                ContentBlock::ToolUse { id, .. } => Some(id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, ["t3", "t4"]);
+    }
+
+    #[test]
+    fn api_error_recovery_pops_dangling_user_text() {
+        // send_message fails on first inner-loop iteration: pop the user's text message
+        let mut conv = vec![
+            user_text("first question"),
+            assistant_text("first answer"),
+            user_text("second question"),
+        ];
+        recover_conversation(&mut conv);
+        assert_eq!(conv.len(), 2);
+        assert!(matches!(conv.last().unwrap().role, Role::Assistant));
+    }
+
+    #[test]
+    fn api_error_recovery_pops_tool_results_and_orphaned_tool_use() {
+        // send_message fails mid-tool-loop: pop tool_results AND the orphaned tool_use.
+        // Without this, the API rejects because tool_use has no matching tool_result.
+        let mut conv = vec![
+            user_text("do something"),
+            assistant_tool_use(),
+            user_tool_result("tool output"),
+        ];
+        recover_conversation(&mut conv);
+        assert_eq!(conv.len(), 1);
+        assert!(
+            matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "do something")
+        );
+    }
+
+    #[test]
+    fn api_error_recovery_noop_when_last_is_assistant() {
+        let mut conv = vec![user_text("hello"), assistant_text("hi")];
+        recover_conversation(&mut conv);
+        assert_eq!(conv.len(), 2);
+        assert!(matches!(conv.last().unwrap().role, Role::Assistant));
+    }
+
+    #[test]
+    fn api_error_recovery_empty_conversation() {
+        let mut conv: Vec<Message> = Vec::new();
+        recover_conversation(&mut conv); // should not panic
+        assert!(conv.is_empty());
+    }
+
+    #[test]
+    fn trim_all_tool_result_exchanges_falls_through() {
+        // When every User message starts with ToolResult (no text boundaries after index 0),
+        // trim_conversation should fall through to truncate_oversized_blocks since there
+        // are no safe exchange boundaries to cut at (except the very first message).
+        let mut conv = vec![
+            user_text("initial question"),
+            assistant_tool_use(),
+            user_tool_result("result 1"),
+            assistant_tool_use(),
+            user_tool_result("result 2"),
+            assistant_text("done"),
+        ];
+        // Set budget too small to hold everything
+        let budget = conversation_bytes(&conv[..2]); // only fits first exchange
+        trim_conversation(&mut conv, budget);
+        // The only boundary is at index 0; keep_last = 0 → falls through to truncation
+        // Conversation should be preserved (single boundary can't trim)
+        assert!(!conv.is_empty());
+    }
+
+    #[test]
+    fn rg_path_warning_only_for_missing_explicit_paths() {
+        use std::path::Path;
+        assert_eq!(rg_path_warning(Path::new("rg"), SearchEngine::Rg), None);
+        let missing = Path::new("/nonexistent/bin/rg");
+        assert!(
+            rg_path_warning(missing, SearchEngine::Rg)
+                .unwrap()
+                .contains("does not exist")
+        );
+        assert_eq!(rg_path_warning(missing, SearchEngine::Builtin), None);
+        let sh = Path::new("/bin/sh");
+        assert_eq!(rg_path_warning(sh, SearchEngine::Rg), None);
+    }
+
+    #[test]
+    fn turn_limit_stops_driver_after_n_responses() {
+        // Drive a loop whose every response asks for more tools, as an open-ended task would
+        let mut turns = TurnLimit {
+            max: Some(3),
+            used: 0,
+        };
+        let mut responses = 0;
+        loop {
+            responses += 1;
+            if turns.record() {
+                break;
+            }
+            assert!(responses < 100, "limit never triggered");
         }
+        assert_eq!((responses, turns.used), (3, 3));
+        let mut unlimited = TurnLimit::default();
+        assert!((0..500).all(|_| !unlimited.record()));
     }
 
-    fn user_tool_result(content: &str) -> Message {
-        Message {
-            role: Role::User,
-            content: vec![ContentBlock::ToolResult {
-                tool_use_id: "t1".into(),
-                content: content.to_string(),
-                is_error: None,
+    #[test]
+    fn edit_diff_colors_only_when_enabled() {
+        let input = serde_json::json!({"path": "a.rs", "old_str": "let x = 1;", "new_str": "let x = 2;\nlet y = 3;"});
+        let colored = render_edit_diff(&input, &Theme::default());
+        assert_eq!(
+            colored,
+            "\x1b[91m- let x = 1;\x1b[0m\n\x1b[92m+ let x = 2;\x1b[0m\n\x1b[92m+ let y = 3;\x1b[0m"
+        );
+        let plain = render_edit_diff(&input, &Theme::plain());
+        assert_eq!(plain, "- let x = 1;\n+ let x = 2;\n+ let y = 3;");
+        assert!(!plain.contains('\x1b'));
+        let multi = serde_json::json!({"path": "a.rs", "edits": [
+            {"old_str": "a", "new_str": "b"}, {"old_str": "c", "new_str": "d"}
+        ]});
+        assert_eq!(
+            render_edit_diff(&multi, &Theme::plain()),
+            "- a\n+ b\n- c\n+ d"
+        );
+    }
+
+    #[test]
+    fn coalesce_roles_merges_consecutive_user_messages() {
+        let mut conv = vec![
+            user_text("first"),
+            assistant_tool_use(),
+            user_text("interjection"),
+            user_tool_result("ran"),
+        ];
+        coalesce_roles(&mut conv);
+        assert_eq!(conv.len(), 3);
+        assert_eq!(conv[2].role, Role::User);
+        assert!(
+            matches!(&conv[2].content[0], ContentBlock::ToolResult { content, .. } if content == "ran")
+        );
+        assert!(
+            matches!(&conv[2].content[1], ContentBlock::Text { text } if text == "interjection")
+        );
+    }
+
+    #[test]
+    fn coalesce_roles_leaves_alternating_conversation_alone() {
+        let mut conv = vec![
+            user_text("hi"),
+            assistant_tool_use(),
+            user_tool_result("ok"),
+            assistant_text("done"),
+        ];
+        let before = serde_json::to_string(&conv).unwrap();
+        coalesce_roles(&mut conv);
+        assert_eq!(serde_json::to_string(&conv).unwrap(), before);
+    }
+
+    #[test]
+    fn validate_conversation_accepts_well_formed_history() {
+        let conv = vec![
+            user_text("hi"),
+            assistant_tool_use(),
+            user_tool_result("ok"),
+            assistant_text("done"),
+            user_text("next"),
+        ];
+        assert_eq!(validate_conversation(&conv), Ok(()));
+        assert_eq!(validate_conversation(&[]), Ok(()));
+    }
+
+    #[test]
+    fn validate_conversation_rejects_malformed_shapes() {
+        let orphan = vec![
+            user_text("hi"),
+            assistant_tool_use(),
+            user_text("no result"),
+        ];
+        assert_eq!(
+            validate_conversation(&orphan).unwrap_err(),
+            "message 1 (assistant): tool_use t1 has no matching tool_result in message 2"
+        );
+        let trailing = vec![user_text("hi"), assistant_tool_use()];
+        assert!(
+            validate_conversation(&trailing)
+                .unwrap_err()
+                .contains("tool_use t1")
+        );
+        let empty = vec![
+            user_text("hi"),
+            Message {
+                role: Role::Assistant,
+                content: vec![],
+            },
+        ];
+        assert_eq!(
+            validate_conversation(&empty).unwrap_err(),
+            "message 1 (assistant): empty content"
+        );
+        let double = vec![user_text("hi"), assistant_text("a"), assistant_text("b")];
+        assert_eq!(
+            validate_conversation(&double).unwrap_err(),
+            "message 2 (assistant): follows another assistant message"
+        );
+        let stray = vec![user_text("hi"), assistant_text("a"), user_tool_result("x")];
+        assert!(
+            validate_conversation(&stray)
+                .unwrap_err()
+                .contains("tool_result t1 has no tool_use")
+        );
+        let starts_wrong = vec![assistant_text("a")];
+        assert!(
+            validate_conversation(&starts_wrong)
+                .unwrap_err()
+                .starts_with("message 0:")
+        );
+    }
+
+    #[test]
+    fn show_results_echoes_content_plain_mode_only_counts() {
+        let plain = |s: String| crate::api::strip_ansi(&s);
+        let content = "1: fn main() {}\n2: }";
+        assert_eq!(
+            plain(render_tool_result(content, false, false, false)),
+            "result: 20 chars"
+        );
+        assert_eq!(
+            plain(render_tool_result(content, false, false, true)),
+            "result: 20 chars\n1: fn main() {}\n2: }"
+        );
+        // Errors are shown the same way with or without the flag
+        assert_eq!(
+            plain(render_tool_result("no such file", true, false, true)),
+            "error: no such file"
+        );
+        let long = "x".repeat(MAX_SHOWN_RESULT + 5);
+        let shown = plain(render_tool_result(&long, false, false, true));
+        assert!(shown.ends_with("\n... (5 more chars)"), "{shown}");
+    }
+
+    #[test]
+    fn tool_plan_lists_queued_calls_in_order() {
+        let call = |name: &str, input: Value| ContentBlock::ToolUse {
+            id: format!("id-{name}"),
+            name: name.into(),
+            input,
+        };
+        let content = vec![
+            ContentBlock::Text {
+                text: "Let me look.".into(),
+            },
+            call("read_file", serde_json::json!({"path": "src/a.rs"})),
+            call("Grep", serde_json::json!({"pattern": "foo"})),
+            call("Bash", serde_json::json!({"command": "cargo test"})),
+        ];
+        assert_eq!(
+            tool_plan(&content).unwrap(),
+            r#"Will run: Read path=src/a.rs; Grep pattern=foo; Bash command="cargo test""#
+        );
+        assert_eq!(tool_plan(&assistant_text("done").content), None);
+    }
+
+    #[test]
+    fn summarize_tool_input_is_compact() {
+        let edit =
+            serde_json::json!({"path": "src/foo.rs", "old_str": "a\nb", "new_str": "a\nb\nc"});
+        assert_eq!(
+            summarize_tool_input("Edit", &edit),
+            "Edit path=src/foo.rs (old 2 lines → new 3 lines)"
+        );
+        let bash = serde_json::json!({"command": "cargo test --workspace", "cwd": "sub"});
+        assert_eq!(
+            summarize_tool_input("Bash", &bash),
+            r#"Bash command="cargo test --workspace" cwd=sub"#
+        );
+        let long = serde_json::json!({"command": "x".repeat(100)});
+        let s = summarize_tool_input("Bash", &long);
+        assert!(s.ends_with('…') && s.chars().count() < 80, "{s}");
+        let script = serde_json::json!({"command": "set -e\nmake\nmake test"});
+        assert_eq!(
+            summarize_tool_input("Bash", &script),
+            "Bash command=<3 lines>"
+        );
+        let read = serde_json::json!({"path": "src/a.rs", "force_text": true});
+        assert_eq!(
+            summarize_tool_input("Read", &read),
+            "Read force_text=true path=src/a.rs"
+        );
+    }
+
+    #[test]
+    fn budget_note_appended_only_at_thresholds() {
+        let results = || {
+            vec![
+                ContentBlock::ToolResult {
+                    tool_use_id: "a".into(),
+                    content: "first".into(),
+                    is_error: None,
+                },
+                ContentBlock::ToolResult {
+                    tool_use_id: "b".into(),
+                    content: "second".into(),
+                    is_error: None,
+                },
+            ]
+        };
+        let content = |b: &ContentBlock| match b {
+            ContentBlock::ToolResult { content, .. } => content.clone(),
+            _ => unreachable!(),
+        };
+        let mut quiet = results();
+        append_budget_note(&mut quiet, 39);
+        assert_eq!(content(&quiet[1]), "second");
+        let mut crossed = results();
+        append_budget_note(&mut crossed, 40);
+        assert_eq!(content(&crossed[0]), "first");
+        assert_eq!(
+            content(&crossed[1]),
+            "second\n\n[note: 10 tool calls remaining before cutoff; wrap up soon]"
+        );
+    }
+
+    #[test]
+    fn tool_iteration_limit_recovery_cleans_trailing_tool_results() {
+        // When the tool loop hits MAX_TOOL_ITERATIONS, the last message is a User
+        // message with tool_results (no matching Assistant reply). recover_conversation
+        // must pop both the tool_results AND the orphaned tool_use to prevent
+        // consecutive User messages that the API would reject with 400.
+        let mut conv = vec![
+            user_text("start task"),
+            assistant_tool_use(),
+            user_tool_result("iteration result"),
+        ];
+        // Simulates what happens at the iteration limit break
+        recover_conversation(&mut conv);
+        assert_eq!(
+            conv.len(),
+            1,
+            "should pop tool_result and orphaned tool_use"
+        );
+        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "start task"));
+    }
+
+    #[test]
+    fn interrupted_tool_loop_recovers_to_the_last_complete_exchange() {
+        let second_call = || Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::ToolUse {
+                id: "t2".into(),
+                name: "Read".into(),
+                input: serde_json::json!({"path": "b.rs"}),
             }],
+        };
+        // Interrupted after the second call's results were collected
+        let mut conv = vec![
+            user_text("fix it"),
+            assistant_tool_use(),
+            user_tool_result("first"),
+            second_call(),
+            user_tool_result("skipped: the user interrupted this turn"),
+        ];
+        recover_conversation(&mut conv);
+        assert_eq!(conv.len(), 3);
+        // Interrupted before any result of the second call was recorded
+        let mut cut = conv.clone();
+        cut.push(second_call());
+        recover_conversation(&mut cut);
+        assert_eq!(cut.len(), 3);
+        // Either way the next prompt starts from a valid history
+        for mut conv in [conv, cut] {
+            conv.push(user_text("try another way"));
+            coalesce_roles(&mut conv);
+            validate_conversation(&conv).unwrap();
         }
     }
 
-    fn conversation_bytes(msgs: &[Message]) -> usize {
-        msgs.iter()
-            .map(|m| serde_json::to_string(m).unwrap().len())
-            .sum()
+    #[test]
+    fn tool_iteration_limit_recovery_with_assistant_text_only() {
+        // If the loop breaks when the last message is an Assistant text (no pending
+        // tool results), recover_conversation should be a no-op.
+        let mut conv = vec![user_text("question"), assistant_text("answer")];
+        recover_conversation(&mut conv);
+        assert_eq!(conv.len(), 2, "should not modify clean conversation");
     }
 
     #[test]
-    fn trim_no_op_when_under_budget() {
-        let mut conv = vec![user_text("hello"), assistant_text("hi")];
-        let original_len = conv.len();
-        trim_conversation(&mut conv, 100_000);
-        assert_eq!(conv.len(), original_len);
+    fn system_prompt_contains_environment_info() {
+        let prompt = build_system_prompt(&PromptConfig::default());
+        assert!(prompt.contains(std::env::consts::OS), "should contain OS");
+        assert!(
+            prompt.contains(std::env::consts::ARCH),
+            "should contain arch"
+        );
+        assert!(prompt.contains("Read(path"), "should list Read tool");
+        assert!(
+            prompt.contains("Edit(path"),
+            "should contain Edit tool guidance"
+        );
+        assert!(
+            prompt.contains("Never edit blind"),
+            "should contain safety rules"
+        );
     }
 
-    #[test]
-    fn trim_removes_oldest_exchange() {
-        let mut conv = vec![
-            user_text("first question"),
-            assistant_text("first answer"),
-            user_text("second question"),
-            assistant_text("second answer"),
-            user_text("third question"),
-            assistant_text("third answer"),
-        ];
-        // Set budget to fit 2 exchanges but not 3
-        let two_exchange_size = conversation_bytes(&conv[2..]);
-        trim_conversation(&mut conv, two_exchange_size);
-        assert_eq!(conv.len(), 4); // exchanges 2 and 3 remain
+    #[tokio::test]
+    async fn context_overflow_compacts_and_retries_once() {
+        let big = "x".repeat(50_000);
+        let mut conversation = Vec::new();
+        for i in 0..4 {
+            conversation.push(Message {
+                role: Role::User,
+                content: vec![ContentBlock::Text {
+                    text: format!("q{i} {big}"),
+                }],
+            });
+            conversation.push(Message {
+                role: Role::Assistant,
+                content: vec![ContentBlock::Text {
+                    text: format!("a{i}"),
+                }],
+            });
+        }
+        let mut sent = Vec::new();
+        let result = send_with_retry(
+            &mut conversation,
+            &mut MessageSizes::default(),
+            &[],
+            async |msgs: &[Message]| {
+                sent.push(msgs.len());
+                if sent.len() == 1 {
+                    Err(AgentError::ContextOverflow("prompt is too long".into()))
+                } else {
+                    Ok(msgs.len())
+                }
+            },
+        )
+        .await;
+        // First attempt saw everything, the retry a compacted history ending in the same turn
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], 8);
+        assert!(sent[1] < 8, "retry should send fewer messages: {sent:?}");
+        assert_eq!(result.unwrap(), conversation.len());
         assert!(
-            matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "second question")
+            matches!(&conversation.last().unwrap().content[0], ContentBlock::Text { text } if text == "a3")
         );
     }
 
-    #[test]
-    fn trim_preserves_tool_use_pairing() {
-        // Exchange 1: user text -> assistant tool_use -> user tool_result -> assistant text
-        // Exchange 2: user text -> assistant text
-        let mut conv = vec![
-            user_text("read the file"),
+    #[tokio::test]
+    async fn payload_too_large_shrinks_the_largest_blocks_and_retries_once() {
+        let too_large = || AgentError::Status {
+            status: reqwest::StatusCode::PAYLOAD_TOO_LARGE,
+            retry_after: String::new(),
+            body: "request entity too large".into(),
+        };
+        let body_len = |msgs: &[Message]| serde_json::to_string(msgs).unwrap().len();
+        let mut conversation = vec![
+            user_text("read the log"),
             assistant_tool_use(),
-            user_tool_result("file contents here"),
-            assistant_text("I see the file"),
-            user_text("thanks"),
-            assistant_text("you're welcome"),
+            user_tool_result(&"log line\n".repeat(20_000)),
         ];
-        // Budget fits only exchange 2
-        let last_exchange_size = conversation_bytes(&conv[4..]);
-        trim_conversation(&mut conv, last_exchange_size);
-        assert_eq!(conv.len(), 2);
-        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "thanks"));
+        let mut sent = Vec::new();
+        let result = send_with_retry(
+            &mut conversation,
+            &mut MessageSizes::default(),
+            &[],
+            async |msgs: &[Message]| {
+                sent.push(body_len(msgs));
+                if sent.len() == 1 {
+                    Err(too_large())
+                } else {
+                    Ok(msgs.len())
+                }
+            },
+        )
+        .await;
+        // Same messages, a smaller body: history kept, the huge result cut
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1] <= sent[0] / 2 + 200, "{sent:?}");
+        assert!(
+            matches!(&conversation[0].content[0], ContentBlock::Text { text } if text == "read the log")
+        );
+        assert!(
+            matches!(&conversation[2].content[0], ContentBlock::ToolResult { content, .. }
+            if content.ends_with("(truncated: request exceeded the API's size limit)"))
+        );
+
+        // Only once: a second 413 is returned
+        let mut calls = 0;
+        let result: Result<(), _> = send_with_retry(
+            &mut conversation,
+            &mut MessageSizes::default(),
+            &[],
+            async |_: &[Message]| {
+                calls += 1;
+                Err(too_large())
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    async fn non_retryable_errors_are_not_retried() {
+        let mut conversation = Vec::new();
+        let mut calls = 0;
+        let result: Result<(), _> = send_with_retry(
+            &mut conversation,
+            &mut MessageSizes::default(),
+            &[Duration::ZERO],
+            async |_: &[Message]| {
+                calls += 1;
+                Err(AgentError::Status {
+                    status: reqwest::StatusCode::UNAUTHORIZED,
+                    retry_after: String::new(),
+                    body: "invalid x-api-key".into(),
+                })
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_until_backoff_runs_out() {
+        let overloaded = || AgentError::Status {
+            status: reqwest::StatusCode::from_u16(529).unwrap(),
+            retry_after: String::new(),
+            body: "overloaded_error".into(),
+        };
+        let mut conversation = Vec::new();
+        let mut calls = 0;
+        let result = send_with_retry(
+            &mut conversation,
+            &mut MessageSizes::default(),
+            &[Duration::ZERO; 2],
+            async |_: &[Message]| {
+                calls += 1;
+                if calls < 3 {
+                    Err(overloaded())
+                } else {
+                    Ok(calls)
+                }
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap(), 3);
+        calls = 0;
+        let result: Result<(), _> = send_with_retry(
+            &mut conversation,
+            &mut MessageSizes::default(),
+            &[Duration::ZERO; 2],
+            async |_: &[Message]| {
+                calls += 1;
+                Err(overloaded())
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
     }
 
     #[test]
-    fn trim_never_splits_tool_exchange() {
-        // Ensure tool_use and tool_result stay together
-        let mut conv = vec![
-            user_text("q1"),
-            assistant_tool_use(),
-            user_tool_result("result1"),
-            assistant_text("a1"),
-            user_text("q2"),
-            assistant_tool_use(),
-            user_tool_result("result2"),
-            assistant_text("a2"),
-        ];
-        // Budget fits exchange 2 but not both
-        let exchange2_size = conversation_bytes(&conv[4..]);
-        trim_conversation(&mut conv, exchange2_size);
-        // Should cut at index 4 (user text "q2"), keeping tool pair intact
-        assert_eq!(conv.len(), 4);
-        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "q2"));
-        assert!(matches!(&conv[1].content[0], ContentBlock::ToolUse { .. }));
-        assert!(matches!(
-            &conv[2].content[0],
-            ContentBlock::ToolResult { .. }
-        ));
+    fn prompt_flag_seeds_first_user_turn() {
+        let cli = Cli::try_parse_from(["forgeflare", "--prompt", "hello"]).unwrap();
+        assert_eq!(initial_turns(&cli.prompt, None, false), ["hello"]);
+        // Repeated prompts keep order and piped stdin follows them
+        let cli =
+            Cli::try_parse_from(["forgeflare", "--prompt", "one", "--prompt", " two "]).unwrap();
+        assert_eq!(
+            initial_turns(&cli.prompt, Some("from stdin\n"), false),
+            ["one", "two", "from stdin"]
+        );
+        assert!(initial_turns(&[], Some("  \n"), false).is_empty());
     }
 
     #[test]
-    fn trim_single_exchange_untouched() {
-        let mut conv = vec![
-            user_text(&"x".repeat(10_000)),
-            assistant_text(&"y".repeat(10_000)),
-        ];
-        // Budget smaller than the single exchange — can't trim further
-        trim_conversation(&mut conv, 100);
-        assert_eq!(conv.len(), 2); // preserved, nothing to cut
+    fn split_prompts_turns_delimited_stdin_into_separate_turns() {
+        let piped = "first\nstill first\n---\n\n---\nsecond\n--- not a delimiter\n---  \nthird";
+        assert_eq!(
+            initial_turns(&["seed".into()], Some(piped), true),
+            [
+                "seed",
+                "first\nstill first",
+                "second\n--- not a delimiter",
+                "third"
+            ]
+        );
+        // Without the flag the delimiter is just text
+        assert_eq!(initial_turns(&[], Some("a\n---\nb"), false), ["a\n---\nb"]);
     }
 
     #[test]
-    fn trim_large_tool_result_triggers_trim() {
-        let big_result = "x".repeat(500_000);
-        let mut conv = vec![
-            user_text("old question"),
-            assistant_text("old answer"),
-            user_text("read big file"),
-            assistant_tool_use(),
-            user_tool_result(&big_result),
-            assistant_text("that's a big file"),
-            user_text("now what"),
-            assistant_text("let me help"),
-        ];
-        // Budget that can't hold everything but can hold last 2 exchanges
-        let last_two_size = conversation_bytes(&conv[2..]);
-        let budget = last_two_size; // fits exchanges 2+3 but not 1+2+3
-        trim_conversation(&mut conv, budget);
-        assert!(conv.len() < 8); // something was trimmed
-        // First remaining message should be a user text (exchange boundary)
-        assert!(matches!(&conv[0].content[0], ContentBlock::Text { .. }));
+    fn read_line_timeout_reports_lines_timeout_and_eof() {
+        let go = AtomicBool::new(false);
+        let (tx, rx) = mpsc::channel();
+        tx.send("hi\n".to_string()).unwrap();
+        let short = Some(Duration::from_millis(50));
+        assert_eq!(
+            read_line_timeout(&rx, short, &go),
+            ReadOutcome::Line("hi\n".into())
+        );
+        let start = std::time::Instant::now();
+        assert_eq!(read_line_timeout(&rx, short, &go), ReadOutcome::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // Ctrl-C ends an open-ended wait
+        let stop = AtomicBool::new(true);
+        assert_eq!(read_line_timeout(&rx, None, &stop), ReadOutcome::Stopped);
+        drop(tx);
+        assert_eq!(read_line_timeout(&rx, short, &go), ReadOutcome::Eof);
+        assert_eq!(read_line_timeout(&rx, None, &go), ReadOutcome::Eof);
     }
 
     #[test]
-    fn trim_fallback_when_everything_huge() {
-        let huge = "x".repeat(400_000);
-        let mut conv = vec![
-            user_text(&huge),
-            assistant_text(&huge),
-            user_text("small"),
-            assistant_text("small"),
-        ];
-        // Budget too small for even the last exchange of the big ones
-        trim_conversation(&mut conv, 1000);
-        // Should trim to last exchange
-        assert_eq!(conv.len(), 2);
-        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "small"));
+    fn line_reader_forwards_lines_until_eof() {
+        let go = AtomicBool::new(false);
+        let rx = spawn_line_reader(std::io::Cursor::new("a\nb\n"));
+        assert_eq!(
+            read_line_timeout(&rx, None, &go),
+            ReadOutcome::Line("a\n".into())
+        );
+        assert_eq!(
+            read_line_timeout(&rx, None, &go),
+            ReadOutcome::Line("b\n".into())
+        );
+        assert_eq!(read_line_timeout(&rx, None, &go), ReadOutcome::Eof);
     }
 
     #[test]
-    fn trim_empty_conversation() {
-        let mut conv: Vec<Message> = Vec::new();
-        trim_conversation(&mut conv, 100); // should not panic
-        assert!(conv.is_empty());
+    fn formatter_overrides_replace_defaults() {
+        assert_eq!(
+            parse_formatter(".rs=cargo fmt --"),
+            Ok(("rs".into(), "cargo fmt --".into()))
+        );
+        assert!(parse_formatter("rs").is_err());
+        assert!(parse_formatter("rs= ").is_err());
+        let map = formatter_map(&[("rs".into(), "stubfmt".into())]);
+        let rs: Vec<_> = map.iter().filter(|(e, _)| e == "rs").collect();
+        assert_eq!(rs, [&("rs".to_string(), "stubfmt".to_string())]);
+        assert!(map.iter().any(|(e, _)| e == "go"));
     }
 
     #[test]
-    fn truncate_oversized_single_exchange() {
-        // When a single exchange has a huge tool result that exceeds the budget,
-        // trim_conversation falls back to truncating content blocks
-        let huge_result = "x".repeat(800_000); // 800KB > 720KB budget
-        let mut conv = vec![
-            user_text("read the big file"),
-            assistant_tool_use(),
-            user_tool_result(&huge_result),
-            assistant_text("got it"),
-        ];
-        // Budget much smaller than the huge result
-        let budget = 100_000;
-        trim_conversation(&mut conv, budget);
-        // Conversation should still have all 4 messages (single exchange can't be split)
-        assert_eq!(conv.len(), 4);
-        // But the huge tool result should be truncated
-        if let ContentBlock::ToolResult { content, .. } = &conv[2].content[0] {
-            assert!(
-                content.len() < huge_result.len(),
-                "content should be truncated"
-            );
-            assert!(
-                content.contains("truncated to fit context window"),
-                "should have truncation marker"
-            );
-        } else {
-            panic!("expected ToolResult");
-        }
+    fn system_prompt_docs_are_identical_across_cwds() {
+        let at = |cwd: &str| PromptConfig {
+            cwd: Some(cwd.into()),
+            ..Default::default()
+        };
+        let (docs_a, env_a) = system_prompt_parts(&at("/work/alpha"));
+        let (docs_b, env_b) = system_prompt_parts(&at("/home/b/beta"));
+        assert_eq!(docs_a, docs_b, "static docs must be a stable cache prefix");
+        assert_ne!(env_a, env_b);
+        assert!(!docs_a.contains("/work/alpha"));
+        assert!(env_a.starts_with("# Environment\n\n/work/alpha on "));
+        let blocks = system_prompt_blocks(&at("/work/alpha"));
+        assert_eq!(blocks[0]["text"], docs_a.as_str());
+        assert_eq!(blocks[0]["cache_control"]["type"], "ephemeral");
+        assert_eq!(blocks[1]["text"], env_a.as_str());
+        assert!(blocks[1].get("cache_control").is_none());
     }
 
     #[test]
-    fn truncate_oversized_skips_small_blocks() {
-        // Only blocks >10KB should be eligible for truncation
-        let mut conv = vec![
-            user_text("small text that should not be truncated"),
-            assistant_text(&"y".repeat(5_000)), // 5KB — below threshold
-        ];
-        // Even with tiny budget, small blocks should not be truncated
-        truncate_oversized_blocks(&mut conv, 100);
-        assert!(
-            matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "small text that should not be truncated")
-        );
+    fn system_prompt_describes_cwd_mode() {
+        let default = build_system_prompt(&PromptConfig::default());
+        assert!(default.contains("Working directory resets each call"));
+        let persistent = build_system_prompt(&PromptConfig {
+            persist_cwd: true,
+            ..Default::default()
+        });
+        assert!(persistent.contains("Working directory persists across calls"));
     }
 
     #[test]
-    fn truncate_oversized_multibyte_char_boundary() {
-        // Regression: truncate_oversized_blocks used to panic when `keep` fell on the
-        // second byte of a multi-byte UTF-8 character. 'é' is 2 bytes (0xC3 0xA9);
-        // if the computed keep position lands on 0xA9, String::truncate panics.
-        // The fix uses floor_char_boundary to snap to the nearest valid boundary.
-        let text = "é".repeat(6_000); // 12KB of 2-byte chars → eligible (>10KB)
-        let mut conv = vec![Message {
-            role: Role::User,
-            content: vec![ContentBlock::Text { text }],
-        }];
-        // Budget tiny enough to force truncation, should not panic
-        truncate_oversized_blocks(&mut conv, 100);
-        if let ContentBlock::Text { text } = &conv[0].content[0] {
-            assert!(text.contains("truncated"), "should have truncation marker");
-            // Verify the truncated text is valid UTF-8 (no panic on iteration)
-            assert!(text.chars().count() > 0);
-        } else {
-            panic!("expected Text block");
-        }
+    fn system_prompt_includes_custom_addition() {
+        // --print-system-prompt and /system print this exact string, so both the
+        // built-in tool docs and the appended instructions must be present
+        let prompt = build_system_prompt(&PromptConfig {
+            append: Some("Always answer in haiku.".into()),
+            ..Default::default()
+        });
+        assert!(prompt.contains("Grep(pattern"), "should keep tool docs");
+        assert!(prompt.contains("# Additional Instructions"));
+        assert!(prompt.ends_with("Always answer in haiku."));
+        let blank = PromptConfig {
+            append: Some("  ".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_system_prompt(&blank),
+            build_system_prompt(&PromptConfig::default())
+        );
     }
 
     #[test]
-    fn partial_tool_use_filtered_on_truncation() {
-        // Simulates what happens when MaxTokens truncates mid-tool_use:
-        // the ToolUse block has input: Value::Null because content_block_stop never fired
-        let mut msg = Message {
-            role: Role::Assistant,
-            content: vec![
-                ContentBlock::Text {
-                    text: "Let me check".into(),
-                },
-                ContentBlock::ToolUse {
-                    id: "t1".into(),
-                    name: "Bash".into(),
-                    input: serde_json::json!({"command": "ls"}),
-                },
-                ContentBlock::ToolUse {
-                    id: "t2".into(),
-                    name: "Read".into(),
-                    input: Value::Null, // partial — never completed
-                },
-            ],
+    fn pinned_files_appear_in_system_prompt() {
+        let dir = tempfile::tempdir().unwrap();
+        let standards = dir.path().join("STANDARDS.md");
+        std::fs::write(&standards, "Use snake_case everywhere.\n").unwrap();
+        let big = dir.path().join("schema.sql");
+        std::fs::write(&big, "x".repeat(MAX_PINNED_BYTES + 10)).unwrap();
+        let missing = dir.path().join("missing.md");
+        let (pinned, warnings) = load_pinned_files(&[standards.clone(), big, missing]);
+        assert_eq!(pinned.len(), 2);
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("only the first"), "{warnings:?}");
+        assert!(warnings[1].contains("missing.md") && warnings[1].ends_with("not pinned"));
+        assert!(pinned[1].1.ends_with(&format!(
+            "[truncated: first {MAX_PINNED_BYTES} of {} bytes pinned]",
+            MAX_PINNED_BYTES + 10
+        )));
+
+        let config = PromptConfig {
+            pinned,
+            ..Default::default()
         };
-        // The same filter used in the MaxTokens handler
-        msg.content
-            .retain(|b| !matches!(b, ContentBlock::ToolUse { input, .. } if input.is_null()));
-        assert_eq!(msg.content.len(), 2);
-        assert!(matches!(&msg.content[0], ContentBlock::Text { .. }));
-        assert!(matches!(&msg.content[1], ContentBlock::ToolUse { name, .. } if name == "Bash"));
+        let prompt = build_system_prompt(&config);
+        assert!(prompt.contains(&format!(
+            "# Pinned Context\n\n## {}\n\nUse snake_case everywhere.\n",
+            standards.display()
+        )));
+        // Pinned text is in the cached block, ahead of the environment section
+        let blocks = system_prompt_blocks(&config);
+        assert!(
+            blocks[0]["text"]
+                .as_str()
+                .unwrap()
+                .contains("Use snake_case everywhere.")
+        );
+        assert!(prompt.find("# Pinned Context") < prompt.find("# Environment"));
     }
 
     #[test]
-    fn api_error_recovery_pops_dangling_user_text() {
-        // send_message fails on first inner-loop iteration: pop the user's text message
-        let mut conv = vec![
-            user_text("first question"),
-            assistant_text("first answer"),
-            user_text("second question"),
+    fn misnamed_tool_runs_the_intended_one_with_a_note() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+        let blocks = vec![
+            ContentBlock::ToolUse {
+                id: "t1".into(),
+                name: "read-file".into(),
+                input: serde_json::json!({"path": path.to_str().unwrap()}),
+            },
+            ContentBlock::ToolUse {
+                id: "t2".into(),
+                name: "launch_rockets".into(),
+                input: serde_json::json!({}),
+            },
         ];
-        recover_conversation(&mut conv);
-        assert_eq!(conv.len(), 2);
-        assert!(matches!(conv.last().unwrap().role, Role::Assistant));
+        let results = dispatch_tool_uses(&blocks, &mut DispatchOptions::default());
+        let ContentBlock::ToolResult {
+            content, is_error, ..
+        } = &results[0]
+        else {
+            panic!("expected ToolResult");
+        };
+        assert_eq!(*is_error, None);
+        assert!(content.contains("hello"), "{content}");
+        assert!(
+            content.ends_with(
+                "[note: there is no tool named 'read-file'; ran Read instead. Use the exact name]"
+            ),
+            "{content}"
+        );
+        assert!(matches!(
+            &results[1],
+            ContentBlock::ToolResult { content, is_error: Some(true), .. } if content == "tool 'launch_rockets' not found"
+        ));
     }
 
     #[test]
-    fn api_error_recovery_pops_tool_results_and_orphaned_tool_use() {
-        // send_message fails mid-tool-loop: pop tool_results AND the orphaned tool_use.
-        // Without this, the API rejects because tool_use has no matching tool_result.
-        let mut conv = vec![
-            user_text("do something"),
-            assistant_tool_use(),
-            user_tool_result("tool output"),
+    fn fail_fast_skips_tool_uses_after_bash_error() {
+        let blocks = vec![
+            ContentBlock::ToolUse {
+                id: "t1".into(),
+                name: "Bash".into(),
+                input: serde_json::json!({"command": "false"}),
+            },
+            ContentBlock::ToolUse {
+                id: "t2".into(),
+                name: "Bash".into(),
+                input: serde_json::json!({"command": "echo should_not_run"}),
+            },
         ];
-        recover_conversation(&mut conv);
-        assert_eq!(conv.len(), 1);
+        let mut opts = DispatchOptions {
+            fail_fast: true,
+            ..Default::default()
+        };
+        let results = dispatch_tool_uses(&blocks, &mut opts);
+        assert_eq!(results.len(), 2, "every tool_use needs a tool_result");
+        let ContentBlock::ToolResult {
+            tool_use_id,
+            content,
+            is_error,
+        } = &results[1]
+        else {
+            panic!("expected ToolResult");
+        };
+        assert_eq!(tool_use_id, "t2");
         assert!(
-            matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "do something")
+            content.contains("skipped due to prior failure"),
+            "{content}"
         );
-    }
+        assert_eq!(*is_error, Some(true));
 
-    #[test]
-    fn api_error_recovery_noop_when_last_is_assistant() {
-        let mut conv = vec![user_text("hello"), assistant_text("hi")];
-        recover_conversation(&mut conv);
-        assert_eq!(conv.len(), 2);
-        assert!(matches!(conv.last().unwrap().role, Role::Assistant));
+        // Without the flag the second command still runs
+        let results = dispatch_tool_uses(&blocks, &mut DispatchOptions::default());
+        assert!(
+            matches!(&results[1], ContentBlock::ToolResult { content, .. } if content == "should_not_run")
+        );
     }
 
     #[test]
-    fn api_error_recovery_empty_conversation() {
-        let mut conv: Vec<Message> = Vec::new();
-        recover_conversation(&mut conv); // should not panic
-        assert!(conv.is_empty());
+    fn tool_uses_over_the_cap_get_error_results() {
+        let blocks: Vec<ContentBlock> = (1..=5)
+            .map(|i| ContentBlock::ToolUse {
+                id: format!("t{i}"),
+                name: "Bash".into(),
+                input: serde_json::json!({"command": format!("echo {i}")}),
+            })
+            .collect();
+        let mut opts = DispatchOptions {
+            max_tool_uses: Some(3),
+            ..Default::default()
+        };
+        let results = dispatch_tool_uses(&blocks, &mut opts);
+        assert_eq!(results.len(), 5, "every tool_use needs a tool_result");
+        for (i, result) in results.iter().enumerate() {
+            let ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } = result
+            else {
+                panic!("expected ToolResult");
+            };
+            assert_eq!(tool_use_id, &format!("t{}", i + 1));
+            if i < 3 {
+                assert_eq!(content, &(i + 1).to_string());
+            } else {
+                assert!(
+                    content.starts_with("too many tool calls in one turn"),
+                    "{content}"
+                );
+                assert_eq!(*is_error, Some(true));
+            }
+        }
     }
 
     #[test]
-    fn trim_all_tool_result_exchanges_falls_through() {
-        // When every User message starts with ToolResult (no text boundaries after index 0),
-        // trim_conversation should fall through to truncate_oversized_blocks since there
-        // are no safe exchange boundaries to cut at (except the very first message).
-        let mut conv = vec![
-            user_text("initial question"),
-            assistant_tool_use(),
-            user_tool_result("result 1"),
-            assistant_tool_use(),
-            user_tool_result("result 2"),
-            assistant_text("done"),
+    fn review_decides_whether_edits_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.txt");
+        std::fs::write(&path, "one\n").unwrap();
+        let p = path.to_str().unwrap().to_string();
+        let edit = |id: &str, old: &str, new: &str| ContentBlock::ToolUse {
+            id: id.into(),
+            name: "Edit".into(),
+            input: serde_json::json!({"path": p, "old_str": old, "new_str": new}),
+        };
+        let mut answers = vec!["q", "n", "y"];
+        let mut opts = DispatchOptions {
+            review: Some(Box::new(move |_, _, _| {
+                parse_review_answer(answers.pop().unwrap())
+            })),
+            ..Default::default()
+        };
+        let blocks = [
+            edit("t1", "one", "two"),
+            edit("t2", "two", "three"),
+            edit("t3", "two", "four"),
+            edit("t4", "two", "five"),
         ];
-        // Set budget too small to hold everything
-        let budget = conversation_bytes(&conv[..2]); // only fits first exchange
-        trim_conversation(&mut conv, budget);
-        // The only boundary is at index 0; keep_last = 0 → falls through to truncation
-        // Conversation should be preserved (single boundary can't trim)
-        assert!(!conv.is_empty());
+        let results = dispatch_tool_uses(&blocks, &mut opts);
+        let contents: Vec<(&str, Option<bool>)> = results
+            .iter()
+            .map(|b| match b {
+                ContentBlock::ToolResult {
+                    content, is_error, ..
+                } => (content.as_str(), *is_error),
+                _ => panic!("expected ToolResult"),
+            })
+            .collect();
+        assert!(contents[0].0.starts_with("OK"), "{contents:?}");
+        assert_eq!(
+            contents[1..],
+            [
+                ("user rejected edit; do not retry it unchanged", Some(true)),
+                ("user rejected edit and stopped this turn", Some(true)),
+                ("skipped: the user stopped this turn", Some(true)),
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "two\n");
+        assert_eq!(parse_review_answer(" YES\n"), ReviewDecision::Apply);
+        assert_eq!(parse_review_answer(""), ReviewDecision::Skip);
     }
 
     #[test]
-    fn tool_iteration_limit_recovery_cleans_trailing_tool_results() {
-        // When the tool loop hits MAX_TOOL_ITERATIONS, the last message is a User
-        // message with tool_results (no matching Assistant reply). recover_conversation
-        // must pop both the tool_results AND the orphaned tool_use to prevent
-        // consecutive User messages that the API would reject with 400.
-        let mut conv = vec![
-            user_text("start task"),
-            assistant_tool_use(),
-            user_tool_result("iteration result"),
-        ];
-        // Simulates what happens at the iteration limit break
-        recover_conversation(&mut conv);
+    fn review_shows_and_can_reject_a_replace_in_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.rs");
+        std::fs::write(&path, "fn old_name() {}\n").unwrap();
+        let shown = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = Rc::clone(&shown);
+        let mut opts = DispatchOptions {
+            review: Some(Box::new(move |name, _, diff| {
+                log.borrow_mut().push(format!("{name}: {diff}"));
+                ReviewDecision::Skip
+            })),
+            ..Default::default()
+        };
+        let replace = |id: &str, dry_run: bool| ContentBlock::ToolUse {
+            id: id.into(),
+            name: "ReplaceInFiles".into(),
+            input: serde_json::json!({"pattern": "old_name", "replacement": "new_name",
+                "path": dir.path().to_str().unwrap(), "dry_run": dry_run}),
+        };
+        let results = dispatch_tool_uses(&[replace("t1", false), replace("t2", true)], &mut opts);
+        assert!(
+            matches!(&results[0], ContentBlock::ToolResult { content, is_error: Some(true), .. }
+            if content == "user rejected edit; do not retry it unchanged")
+        );
         assert_eq!(
-            conv.len(),
+            std::fs::read_to_string(&path).unwrap(),
+            "fn old_name() {}\n"
+        );
+        let shown = shown.borrow();
+        assert_eq!(
+            shown.len(),
             1,
-            "should pop tool_result and orphaned tool_use"
+            "a dry run writes nothing, so is not reviewed"
+        );
+        assert!(
+            shown[0].contains("  -1: fn old_name() {}\n  +1: fn new_name() {}"),
+            "{}",
+            shown[0]
+        );
+        assert!(
+            matches!(&results[1], ContentBlock::ToolResult { content, is_error: None, .. }
+            if content.starts_with("Would replace 1 occurrences"))
         );
-        assert!(matches!(&conv[0].content[0], ContentBlock::Text { text } if text == "start task"));
     }
 
     #[test]
-    fn tool_iteration_limit_recovery_with_assistant_text_only() {
-        // If the loop breaks when the last message is an Assistant text (no pending
-        // tool results), recover_conversation should be a no-op.
-        let mut conv = vec![user_text("question"), assistant_text("answer")];
-        recover_conversation(&mut conv);
-        assert_eq!(conv.len(), 2, "should not modify clean conversation");
+    fn risky_commands_need_approval_or_carry_a_caution() {
+        // echo's argument trips the "git reset --hard" pattern without doing anything
+        let bash = |id: &str| ContentBlock::ToolUse {
+            id: id.into(),
+            name: "Bash".into(),
+            input: serde_json::json!({"command": "echo 'git reset --hard'"}),
+        };
+        let asked = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = Rc::clone(&asked);
+        let mut answers = vec![RiskyDecision::Run, RiskyDecision::Decline];
+        let mut opts = DispatchOptions {
+            approve_risky: Some(Box::new(move |cmd, pat| {
+                log.borrow_mut().push((cmd.to_string(), pat.to_string()));
+                answers.pop().unwrap()
+            })),
+            ..Default::default()
+        };
+        let results = dispatch_tool_uses(&[bash("t1"), bash("t2")], &mut opts);
+        let content = |b: &ContentBlock| match b {
+            ContentBlock::ToolResult {
+                content, is_error, ..
+            } => (content.clone(), *is_error),
+            _ => panic!("expected ToolResult"),
+        };
+        assert_eq!(
+            content(&results[0]),
+            (
+                "user declined risky command (matches 'git reset --hard'); do not retry it unchanged"
+                    .into(),
+                Some(true)
+            )
+        );
+        assert_eq!(content(&results[1]), ("git reset --hard".into(), None));
+        assert_eq!(asked.borrow().len(), 2);
+        assert_eq!(asked.borrow()[0].1, "git reset --hard");
+
+        let mut unattended = DispatchOptions::default();
+        let results = dispatch_tool_uses(&[bash("t3")], &mut unattended);
+        let (text, is_error) = content(&results[0]);
+        assert_eq!(is_error, None);
+        assert!(
+            text.starts_with("git reset --hard\ncaution: this command matches risky pattern"),
+            "{text}"
+        );
     }
 
     #[test]
-    fn system_prompt_contains_environment_info() {
-        let prompt = build_system_prompt();
-        assert!(prompt.contains(std::env::consts::OS), "should contain OS");
-        assert!(
-            prompt.contains(std::env::consts::ARCH),
-            "should contain arch"
+    fn always_approved_commands_skip_the_prompt_until_forgotten() {
+        let bash = |id: &str, command: &str| ContentBlock::ToolUse {
+            id: id.into(),
+            name: "Bash".into(),
+            input: serde_json::json!({"command": command}),
+        };
+        let asked = Rc::new(std::cell::Cell::new(0));
+        let count = Rc::clone(&asked);
+        let mut opts = DispatchOptions {
+            approve_risky: Some(Box::new(move |_, _| {
+                count.set(count.get() + 1);
+                RiskyDecision::Always
+            })),
+            ..Default::default()
+        };
+        let uses = [
+            bash("t1", "echo 'git reset --hard'"),
+            // Same command modulo whitespace: remembered
+            bash("t2", "echo  'git reset --hard' "),
+        ];
+        let results = dispatch_tool_uses(&uses, &mut opts);
+        assert_eq!(asked.get(), 1);
+        assert!(results.iter().all(|b| matches!(b,
+            ContentBlock::ToolResult { content, is_error: None, .. } if content == "git reset --hard")));
+        // A different risky command is still asked about
+        dispatch_tool_uses(&[bash("t3", "echo 'git stash drop'")], &mut opts);
+        assert_eq!(asked.get(), 2);
+
+        // What /forget does
+        opts.approved_commands.clear();
+        dispatch_tool_uses(&[bash("t4", "echo 'git reset --hard'")], &mut opts);
+        assert_eq!(asked.get(), 3);
+        assert_eq!(parse_risky_answer(" Always\n"), RiskyDecision::Always);
+        assert_eq!(parse_risky_answer("n"), RiskyDecision::Decline);
+    }
+
+    #[test]
+    fn final_answer_is_last_assistant_text_only() {
+        let mut last = assistant_tool_use();
+        last.content.insert(
+            0,
+            ContentBlock::Text {
+                text: "Done.".into(),
+            },
         );
-        assert!(prompt.contains("Read(path)"), "should list Read tool");
+        last.content.push(ContentBlock::Text {
+            text: "All tests pass.".into(),
+        });
+        let conversation = vec![
+            user_text("fix it"),
+            assistant_text("Looking."),
+            user_tool_result("ok"),
+            last,
+            user_tool_result("ok"),
+        ];
+        assert_eq!(final_answer(&conversation), "Done.\nAll tests pass.");
+        assert_eq!(final_answer(&[user_text("hi")]), "");
+    }
+
+    #[test]
+    fn oversized_tool_result_is_saved_and_referenced() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = "x".repeat(MAX_TOOL_RESULT_BYTES + 1);
+        let mut results = vec![
+            ContentBlock::ToolResult {
+                tool_use_id: "t1".into(),
+                content: big.clone(),
+                is_error: None,
+            },
+            ContentBlock::ToolResult {
+                tool_use_id: "t2".into(),
+                content: "small".into(),
+                is_error: None,
+            },
+        ];
+        spill_oversized_results(&mut results, dir.path());
+        let saved = dir.path().join("tool-t1.txt");
+        assert_eq!(std::fs::read_to_string(&saved).unwrap(), big);
+        let ContentBlock::ToolResult { content, .. } = &results[0] else {
+            panic!("expected ToolResult");
+        };
+        assert!(content.len() < SPILLED_PREVIEW_BYTES + 500);
         assert!(
-            prompt.contains("Edit(path"),
-            "should contain Edit tool guidance"
+            content.contains(&format!("full output saved to {}", saved.display())),
+            "{}",
+            &content[SPILLED_PREVIEW_BYTES..]
         );
         assert!(
-            prompt.contains("Never edit blind"),
-            "should contain safety rules"
+            matches!(&results[1], ContentBlock::ToolResult { content, .. } if content == "small")
         );
+        assert!(!dir.path().join("tool-t2.txt").exists());
     }
 
     #[test]
@@ -816,4 +3939,732 @@ mod tests {
         assert_eq!(msg.content.len(), 1, "should have placeholder");
         assert!(matches!(&msg.content[0], ContentBlock::Text { text } if text == "(truncated)"));
     }
+
+    /// Answer each POST with the next canned JSON body, returning the request bodies seen.
+    async fn canned_api(responses: Vec<Value>) -> (String, tokio::task::JoinHandle<Vec<Value>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for response in responses {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 8192];
+                let body_start = loop {
+                    let n = sock.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break i + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&buf[..body_start]).to_lowercase();
+                let len: usize = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length:"))
+                    .map_or(0, |v| v.trim().parse().unwrap());
+                while buf.len() < body_start + len {
+                    let n = sock.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                seen.push(serde_json::from_slice(&buf[body_start..]).unwrap());
+                let body = response.to_string();
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                sock.write_all(reply.as_bytes()).await.unwrap();
+            }
+            seen
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn deadline_cuts_the_running_tool_and_ends_the_turn() {
+        let (url, server) = canned_api(vec![serde_json::json!({
+            "content": [{"type": "tool_use", "id": "t1", "name": "Bash", "input": {"command": "sleep 5"}}],
+            "stop_reason": "tool_use", "usage": {"input_tokens": 10, "output_tokens": 5},
+        })])
+        .await;
+        let client = AnthropicClient::new(&url).unwrap().with_streaming(false);
+        let mut opts = DispatchOptions::default();
+        opts.tools.deadline = Some(std::time::Instant::now() + Duration::from_millis(300));
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let mut conversation = vec![user_text("wait")];
+        let system = serde_json::json!("system");
+        let request = TurnRequest {
+            schemas: &[],
+            model: "test-model",
+            system: &system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: None,
+            tool_choice: ToolChoice::Auto,
+        };
+        let started = std::time::Instant::now();
+        let outcome = run_turn(
+            &client,
+            &mut conversation,
+            &request,
+            &mut opts,
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        assert!(
+            started.elapsed() < Duration::from_secs(4),
+            "sleep was not cut short"
+        );
+        assert!(outcome.deadline_hit);
+        // The tool result is recorded but not sent: no second request after the deadline
+        assert_eq!(server.await.unwrap().len(), 1);
+        let Some(ContentBlock::ToolResult { content, .. }) = conversation[2].content.first() else {
+            panic!("expected the tool result");
+        };
+        assert!(
+            content.starts_with("Command was killed at the run deadline"),
+            "{content}"
+        );
+        // Later tool calls in the same run are not started at all
+        let calls = [assistant_tool_use().content.remove(0)];
+        let skipped = dispatch_tool_uses(&calls, &mut opts);
+        assert!(
+            matches!(&skipped[0], ContentBlock::ToolResult { content, .. } if content.contains("run deadline"))
+        );
+    }
+
+    #[tokio::test]
+    async fn prefill_is_sent_last_and_recorded_with_the_continuation() {
+        let (url, server) = canned_api(vec![serde_json::json!({
+            "content": [{"type": "text", "text": "\"ok\": true}"}],
+            "stop_reason": "end_turn", "usage": {"input_tokens": 10, "output_tokens": 5},
+        })])
+        .await;
+        let client = AnthropicClient::new(&url).unwrap().with_streaming(false);
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let mut conversation = vec![user_text("status as JSON")];
+        let system = serde_json::json!("system");
+        let request = TurnRequest {
+            schemas: &[],
+            model: "test-model",
+            system: &system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: Some("{"),
+            tool_choice: ToolChoice::Auto,
+        };
+        let outcome = run_turn(
+            &client,
+            &mut conversation,
+            &request,
+            &mut DispatchOptions::default(),
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        let sent = server.await.unwrap();
+        let messages = sent[0]["messages"].as_array().unwrap();
+        assert_eq!(
+            messages.last().unwrap(),
+            &serde_json::json!({"role": "assistant", "content": [{"type": "text", "text": "{"}]})
+        );
+        assert_eq!(outcome.text, "{\"ok\": true}");
+        assert_eq!(conversation.len(), 2);
+        assert_eq!(final_answer(&conversation), "{\"ok\": true}");
+        let transcript = std::fs::read_to_string(dir.path().join("full.jsonl")).unwrap();
+        assert!(transcript.contains(r#"{\"ok\": true}"#), "{transcript}");
+
+        assert_eq!(
+            check_prefill(Some("{ \n"), false).unwrap().as_deref(),
+            Some("{")
+        );
+        assert_eq!(check_prefill(None, true).unwrap(), None);
+        assert!(check_prefill(Some("  "), false).is_err());
+        assert!(
+            check_prefill(Some("{"), true)
+                .unwrap_err()
+                .contains("--effort")
+        );
+    }
+
+    #[test]
+    fn dry_run_body_has_system_prompt_tools_and_user_message() {
+        let client = AnthropicClient::new("http://127.0.0.1:9").unwrap();
+        let system = system_prompt_blocks(&PromptConfig::default());
+        let schemas = all_tool_schemas();
+        let request = TurnRequest {
+            schemas: &schemas,
+            model: "test-model",
+            system: &system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: None,
+            tool_choice: ToolChoice::Auto,
+        };
+        let body = first_request_body(&client, &[user_text("explain main.rs")], &request);
+        let text = serde_json::to_string_pretty(&body).unwrap();
+        assert!(text.contains("cache_control"), "{text}");
+        assert_eq!(body["system"], system);
+        let names: Vec<&str> = body["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|t| t["name"].as_str())
+            .collect();
+        for tool in ["Read", "Glob", "Bash", "Edit", "Grep"] {
+            assert!(names.contains(&tool), "{names:?}");
+        }
+        assert_eq!(names.len(), schemas.len());
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([{"role": "user", "content": [{"type": "text", "text": "explain main.rs"}]}])
+        );
+        assert_eq!(body["model"], "test-model");
+        assert!(body.get("tool_choice").is_none());
+    }
+
+    #[tokio::test]
+    async fn tool_choice_is_sent_on_the_first_request_only() {
+        let (url, server) = canned_api(vec![
+            serde_json::json!({
+                "content": [{"type": "tool_use", "id": "t1", "name": "Glob", "input": {"pattern": "*.no-such-ext"}}],
+                "stop_reason": "tool_use", "usage": {"input_tokens": 10, "output_tokens": 5},
+            }),
+            serde_json::json!({
+                "content": [{"type": "text", "text": "nothing there"}],
+                "stop_reason": "end_turn", "usage": {"input_tokens": 10, "output_tokens": 5},
+            }),
+        ])
+        .await;
+        let client = AnthropicClient::new(&url).unwrap().with_streaming(false);
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let mut conversation = vec![user_text("find the configs")];
+        let system = serde_json::json!("system");
+        let request = TurnRequest {
+            schemas: &[],
+            model: "test-model",
+            system: &system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: None,
+            tool_choice: ToolChoice::Tool("Glob".into()),
+        };
+        run_turn(
+            &client,
+            &mut conversation,
+            &request,
+            &mut DispatchOptions::default(),
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        let sent = server.await.unwrap();
+        assert_eq!(
+            sent[0]["tool_choice"],
+            serde_json::json!({"type": "tool", "name": "Glob"})
+        );
+        // Forcing again after the results would leave the model no way to answer
+        assert!(sent[1].get("tool_choice").is_none(), "{}", sent[1]);
+
+        let parse = |s| ToolChoice::parse(s).unwrap();
+        assert_eq!(parse("auto").to_request(true), None);
+        assert_eq!(
+            parse("none").to_request(false),
+            Some(serde_json::json!({"type": "none"}))
+        );
+        assert_eq!(
+            parse("any").to_request(true),
+            Some(serde_json::json!({"type": "any"}))
+        );
+        assert_eq!(parse("any").to_request(false), None);
+        assert_eq!(
+            parse("Grep").to_request(true),
+            Some(serde_json::json!({"type": "tool", "name": "Grep"}))
+        );
+
+        let schemas = all_tool_schemas();
+        assert!(check_tool_choice(&parse("Grep"), &schemas, false, false).is_ok());
+        let err = check_tool_choice(&parse("grepp"), &schemas, false, false).unwrap_err();
+        assert!(err.contains("did you mean Grep?"), "{err}");
+        assert!(check_tool_choice(&parse("any"), &schemas, true, false).is_err());
+        assert!(check_tool_choice(&parse("Read"), &schemas, false, true).is_err());
+        assert!(check_tool_choice(&parse("none"), &schemas, true, true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn fail_on_tool_error_ends_the_turn_at_a_failed_bash() {
+        let (url, server) = canned_api(vec![serde_json::json!({
+            "content": [{"type": "tool_use", "id": "t1", "name": "Bash", "input": {"command": "echo broken >&2; exit 1"}}],
+            "stop_reason": "tool_use", "usage": {"input_tokens": 10, "output_tokens": 5},
+        })])
+        .await;
+        let client = AnthropicClient::new(&url).unwrap().with_streaming(false);
+        let mut opts = DispatchOptions {
+            fail_on_tool_error: true,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let mut conversation = vec![user_text("build it")];
+        let system = serde_json::json!("system");
+        let request = TurnRequest {
+            schemas: &[],
+            model: "test-model",
+            system: &system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: None,
+            tool_choice: ToolChoice::Auto,
+        };
+        let outcome = run_turn(
+            &client,
+            &mut conversation,
+            &request,
+            &mut opts,
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        let failure = outcome.tool_failure.expect("run should abort");
+        assert!(failure.starts_with("Bash: "), "{failure}");
+        assert_eq!(outcome.tools_run, 1);
+        // The failed result is kept, but never sent back for another attempt
+        assert_eq!(conversation.len(), 3);
+        assert_eq!(server.await.unwrap().len(), 1);
+
+        let calls = [ContentBlock::ToolUse {
+            id: "t1".into(),
+            name: "Read".into(),
+            input: serde_json::json!({}),
+        }];
+        let ok = [ContentBlock::ToolResult {
+            tool_use_id: "t1".into(),
+            content: "fine".into(),
+            is_error: None,
+        }];
+        assert_eq!(first_tool_error(&calls, &ok), None);
+        let failed = [ContentBlock::ToolResult {
+            tool_use_id: "t1".into(),
+            content: "a.rs: not found\nmore".into(),
+            is_error: Some(true),
+        }];
+        assert_eq!(
+            first_tool_error(&calls, &failed).as_deref(),
+            Some("Read: a.rs: not found")
+        );
+    }
+
+    #[tokio::test]
+    async fn run_turn_dispatches_tools_until_end_turn() {
+        let usage = |n| serde_json::json!({"input_tokens": n, "output_tokens": 5});
+        let (url, server) = canned_api(vec![
+            serde_json::json!({
+                "content": [{"type": "tool_use", "id": "t1", "name": "Bash", "input": {"command": "echo hi"}}],
+                "stop_reason": "tool_use", "usage": usage(10),
+            }),
+            serde_json::json!({
+                "content": [{"type": "text", "text": "It printed hi."}],
+                "stop_reason": "end_turn", "usage": usage(20),
+            }),
+        ])
+        .await;
+        let client = AnthropicClient::new(&url).unwrap().with_streaming(false);
+        let mut opts = DispatchOptions::default();
+        opts.tools.fixtures = ToolFixtures::parse(
+            r#"{"tool": "Bash", "input": {"command": "echo hi"}, "content": "hi"}"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let mut conversation = vec![user_text("say hi")];
+        let system = serde_json::json!("system");
+        let request = TurnRequest {
+            schemas: &[],
+            model: "test-model",
+            system: &system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: None,
+            tool_choice: ToolChoice::Auto,
+        };
+        let outcome = run_turn(
+            &client,
+            &mut conversation,
+            &request,
+            &mut opts,
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        assert_eq!(outcome.text, "It printed hi.");
+        assert_eq!(outcome.stop_reason, Some(StopReason::EndTurn));
+        assert_eq!(outcome.tools_run, 1);
+        assert_eq!(outcome.usage.input_tokens, 30);
+        assert_eq!(outcome.usage.output_tokens, 10);
+        assert!(!outcome.turn_limit_hit);
+        assert_eq!(conversation.len(), 4);
+        let sent = server.await.unwrap();
+        assert_eq!(sent.len(), 2);
+        let result = &sent[1]["messages"][2]["content"][0];
+        assert_eq!(result["type"], "tool_result");
+        assert_eq!(result["tool_use_id"], "t1");
+        assert_eq!(result["content"], "hi");
+        let transcript = std::fs::read_to_string(dir.path().join("full.jsonl")).unwrap();
+        assert_eq!(
+            transcript.lines().count(),
+            3,
+            "assistant, tool result, assistant"
+        );
+    }
+
+    fn mock_turn_request(system: &Value) -> TurnRequest<'_> {
+        TurnRequest {
+            schemas: &[],
+            model: "test-model",
+            system,
+            max_tokens: 100,
+            conversation_bytes: MAX_CONVERSATION_BYTES,
+            prefill: None,
+            tool_choice: ToolChoice::Auto,
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_backend_drives_tool_loop_and_recovers_from_errors() {
+        let usage = |n| Usage {
+            input_tokens: n,
+            ..Usage::default()
+        };
+        let backend = api::MockBackend::new([
+            Ok((assistant_tool_use().content, StopReason::ToolUse, usage(10))),
+            Ok((
+                assistant_text("two files").content,
+                StopReason::EndTurn,
+                usage(20),
+            )),
+            Err(AgentError::Status {
+                status: reqwest::StatusCode::BAD_REQUEST,
+                retry_after: String::new(),
+                body: "bad request".into(),
+            }),
+        ]);
+        let mut opts = DispatchOptions::default();
+        opts.tools.fixtures = ToolFixtures::parse(
+            r#"{"tool": "Bash", "input": {"command": "ls"}, "content": "a\nb"}"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let system = serde_json::json!("system");
+        let mut turns = TurnLimit::default();
+        let mut conversation = vec![user_text("list files")];
+        let outcome = run_turn(
+            &backend,
+            &mut conversation,
+            &mock_turn_request(&system),
+            &mut opts,
+            &mut session,
+            &mut turns,
+        )
+        .await;
+        assert_eq!(outcome.text, "two files");
+        assert_eq!(outcome.stop_reason, Some(StopReason::EndTurn));
+        assert_eq!(outcome.tools_run, 1);
+        assert_eq!(outcome.usage.input_tokens, 30);
+        let roles: Vec<Role> = conversation.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(
+            roles,
+            [Role::User, Role::Assistant, Role::User, Role::Assistant]
+        );
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].len(), 1);
+        assert!(matches!(
+            &requests[1][2].content[0],
+            ContentBlock::ToolResult { tool_use_id, content, is_error: None }
+                if tool_use_id == "t1" && content == "a\nb"
+        ));
+
+        // A failed request drops the unanswered user message and leaves history intact
+        conversation.push(user_text("and now?"));
+        let outcome = run_turn(
+            &backend,
+            &mut conversation,
+            &mock_turn_request(&system),
+            &mut opts,
+            &mut session,
+            &mut turns,
+        )
+        .await;
+        assert_eq!(outcome.stop_reason, None);
+        assert_eq!(conversation.len(), 4);
+        assert_eq!(turns.used, 2);
+        let transcript = std::fs::read_to_string(dir.path().join("full.jsonl")).unwrap();
+        assert_eq!(transcript.lines().count(), 3);
+    }
+    #[tokio::test]
+    async fn truncated_tool_calls_never_reach_the_transcript() {
+        let backend = api::MockBackend::new([Ok((
+            vec![
+                ContentBlock::Text {
+                    text: "Let me read it".into(),
+                },
+                ContentBlock::ToolUse {
+                    id: "t1".into(),
+                    name: "Read".into(),
+                    input: Value::Null,
+                },
+            ],
+            StopReason::MaxTokens,
+            Usage::default(),
+        ))]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let system = serde_json::json!("system");
+        let mut conversation = vec![user_text("read main.rs")];
+        session.append_user_turn(&conversation[0]);
+        run_turn(
+            &backend,
+            &mut conversation,
+            &mock_turn_request(&system),
+            &mut DispatchOptions::default(),
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        let transcript = std::fs::read_to_string(dir.path().join("full.jsonl")).unwrap();
+        assert!(!transcript.contains("tool_use"), "{transcript}");
+        let (loaded, _) = session::load_conversation(dir.path()).unwrap();
+        assert_eq!(
+            loaded.len(),
+            2,
+            "resume keeps the reply, minus the cut-off call"
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupt_skips_pending_tool_calls_and_unwinds_the_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let bash = |id: &str, command: String| ContentBlock::ToolUse {
+            id: id.into(),
+            name: "Bash".into(),
+            input: serde_json::json!({"command": command}),
+        };
+        let backend = api::MockBackend::new([
+            Ok((
+                vec![bash("t1", "ls".into())],
+                StopReason::ToolUse,
+                Usage::default(),
+            )),
+            Ok((
+                vec![
+                    bash("t2", "echo 'git reset --hard'".into()),
+                    bash("t3", format!("touch {}", marker.display())),
+                ],
+                StopReason::ToolUse,
+                Usage::default(),
+            )),
+        ]);
+        let mut opts = DispatchOptions::default();
+        opts.tools.fixtures = ToolFixtures::parse(
+            r#"{"tool": "Bash", "input": {"command": "ls"}, "content": "a.rs"}"#,
+        )
+        .unwrap();
+        // Ctrl-C lands while t2 (a risky command, so the prompt gives a hook) is running
+        let interrupt = Arc::clone(&opts.interrupt);
+        opts.approve_risky = Some(Box::new(move |_, _| {
+            interrupt.requested.store(true, Ordering::SeqCst);
+            RiskyDecision::Run
+        }));
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let system = serde_json::json!("system");
+        let mut conversation = vec![user_text("list and clean")];
+        session.append_user_turn(&conversation[0]);
+        opts.interrupt.begin_turn();
+        let outcome = run_turn(
+            &backend,
+            &mut conversation,
+            &mock_turn_request(&system),
+            &mut opts,
+            &mut session,
+            &mut TurnLimit::default(),
+        )
+        .await;
+        assert!(outcome.interrupted);
+        assert_eq!(outcome.stop_reason, None);
+        assert!(!marker.exists(), "calls after the interrupt must not run");
+        assert_eq!(backend.requests().len(), 2, "nothing sent after Ctrl-C");
+        // The interrupted exchange is gone; the completed one stays, closed by a note
+        assert_eq!(conversation.len(), 4);
+        assert!(matches!(&conversation[2].content[0],
+            ContentBlock::ToolResult { content, .. } if content == "a.rs"));
+        assert!(validate_conversation(&conversation).is_ok());
+        // ...and the transcript (and so --resume) agrees
+        let (loaded, warnings) = session::load_conversation(session.dir()).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&conversation).unwrap()
+        );
+
+        // The next turn starts clean
+        opts.interrupt.begin_turn();
+        assert!(!opts.interrupt.requested());
+    }
+
+    #[test]
+    fn quitting_kills_background_jobs_and_writes_session_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opts = DispatchOptions {
+            tools: ToolContext::new(dir.path()),
+            ..Default::default()
+        };
+        let job = ContentBlock::ToolUse {
+            id: "t1".into(),
+            name: "Bash".into(),
+            input: serde_json::json!({"command": "sleep 30", "background": true}),
+        };
+        let results = dispatch_tool_uses(&[job], &mut opts);
+        let ContentBlock::ToolResult { content, .. } = &results[0] else {
+            panic!("expected a tool result");
+        };
+        let pid = content
+            .split("(pid ")
+            .nth(1)
+            .and_then(|rest| rest.split(')').next())
+            .unwrap()
+            .to_string();
+        let alive = |pid: &str| {
+            std::process::Command::new("kill")
+                .args(["-0", pid])
+                .stderr(std::process::Stdio::null())
+                .status()
+                .unwrap()
+                .success()
+        };
+        assert!(alive(&pid));
+
+        // Ctrl-C at the prompt ends the wait; the main loop then runs `shutdown`
+        opts.interrupt.quit.store(true, Ordering::SeqCst);
+        let (_tx, rx) = mpsc::channel();
+        let read = read_line_timeout(&rx, None, &opts.interrupt.quit);
+        assert_eq!(read, ReadOutcome::Stopped);
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let conversation = vec![user_text("start the server")];
+        session.append_user_turn(&conversation[0]);
+        shutdown(&mut opts, &session, &conversation);
+        assert!(!alive(&pid), "background job {pid} outlived the session");
+        assert!(dir.path().join("prompt.txt").is_file());
+    }
+
+    #[test]
+    fn crash_flush_writes_supporting_files_and_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::new("/test/project", "test-model").in_dir(dir.path());
+        let conversation = vec![user_text("fix the build"), assistant_tool_use()];
+        session.append_user_turn(&conversation[0]);
+        record_crash_state(&session, &conversation);
+        // What the panic hook calls after the default panic message
+        let saved = flush_crash_state("panicked at src/main.rs: boom").unwrap();
+        assert_eq!(saved, dir.path());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("prompt.txt")).unwrap(),
+            "fix the build"
+        );
+        let context = std::fs::read_to_string(dir.path().join("context.md")).unwrap();
+        assert!(context.contains("## Key Actions"), "{context}");
+        let transcript = std::fs::read_to_string(dir.path().join("full.jsonl")).unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(transcript.lines().last().unwrap()).unwrap();
+        assert_eq!(last["type"], "crashed");
+        assert_eq!(last["reason"], "panicked at src/main.rs: boom");
+        assert_eq!(flush_crash_state("again"), None, "state is written once");
+    }
+
+    #[test]
+    fn max_tokens_is_checked_against_the_model() {
+        assert_eq!(
+            check_max_tokens("claude-opus-4-6", 0, None),
+            Err("--max-tokens must be at least 1".into())
+        );
+        assert_eq!(
+            check_max_tokens("claude-sonnet-4-5", 16384, None),
+            Ok((None, 16384, None))
+        );
+        let (budget, max, warning) =
+            check_max_tokens("claude-3-5-haiku-latest", 1_000_000, None).unwrap();
+        assert_eq!((budget, max), (None, 8_192));
+        assert_eq!(
+            warning.as_deref(),
+            Some("max_tokens 1000000 exceeds claude-3-5-haiku-latest's limit of 8192; using 8192")
+        );
+        // --effort high wants 32k + 4096 on a 32k model: the budget shrinks to fit
+        let (budget, max, warning) =
+            check_max_tokens("claude-opus-4-1", 16384, Some(Effort::High)).unwrap();
+        assert_eq!((budget, max), (Some(32_000 - MIN_ANSWER_TOKENS), 32_000));
+        assert!(warning.is_some());
+        // Unknown models are passed through
+        assert_eq!(
+            check_max_tokens("my-proxy-model", 500_000, None),
+            Ok((None, 500_000, None))
+        );
+        assert_eq!(conversation_budget(16_384), MAX_CONVERSATION_BYTES);
+        assert_eq!(conversation_budget(128_000), 72_000 * 4);
+    }
+
+    #[tokio::test]
+    async fn deterministic_runs_write_identical_transcripts() {
+        async fn run(dir: &std::path::Path) -> (String, String) {
+            let backend = api::MockBackend::new([
+                Ok((
+                    assistant_tool_use().content,
+                    StopReason::ToolUse,
+                    Usage::default(),
+                )),
+                Ok((
+                    assistant_text("done").content,
+                    StopReason::EndTurn,
+                    Usage::default(),
+                )),
+            ]);
+            let mut opts = DispatchOptions {
+                deterministic: true,
+                ..Default::default()
+            };
+            opts.tools.fixtures = ToolFixtures::parse(
+                r#"{"tool": "Bash", "input": {"command": "ls"}, "content": "a\nb"}"#,
+            )
+            .unwrap();
+            let mut session = Session::deterministic("/test/project", "test-model").in_dir(dir);
+            let mut conversation = vec![user_text("list files")];
+            session.append_user_turn(&conversation[0]);
+            let system = serde_json::json!("system");
+            run_turn(
+                &backend,
+                &mut conversation,
+                &mock_turn_request(&system),
+                &mut opts,
+                &mut session,
+                &mut TurnLimit::default(),
+            )
+            .await;
+            session.write_supporting_files(&conversation);
+            let transcript = std::fs::read_to_string(dir.join("full.jsonl")).unwrap();
+            (transcript, opts.stats.report())
+        }
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let first = run(a.path()).await;
+        let second = run(b.path()).await;
+        assert_eq!(first.0.lines().count(), 4);
+        assert_eq!(first, second);
+        assert!(first.0.contains("\"timestamp\":\"2000-01-01T00:00:01Z\""));
+        assert!(first.1.contains("0.00s"), "{}", first.1);
+        let context = |d: &tempfile::TempDir| std::fs::read(d.path().join("context.md")).unwrap();
+        assert_eq!(context(&a), context(&b));
+    }
 }
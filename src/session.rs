@@ -1,9 +1,12 @@
 use crate::api::{ContentBlock, Message, Role, Usage};
+use crate::diag::diag;
 use serde::Serialize;
+use serde_json::Value;
 use std::{
     fs,
     io::Write,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 #[derive(Serialize)]
@@ -12,23 +15,43 @@ struct TranscriptLine<'a> {
     turn_type: &'a str,
     #[serde(rename = "sessionId")]
     session_id: &'a str,
-    uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
     #[serde(rename = "parentUuid")]
     parent_uuid: Option<String>,
     timestamp: String,
     cwd: &'a str,
     version: &'a str,
-    message: TranscriptMessage<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<TranscriptMessage<'a>>,
+    /// On a `rewind` line: how many preceding messages the session dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rewound: Option<usize>,
+    /// On a `crashed` line: the panic message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
 }
 
 #[derive(Serialize)]
 struct TranscriptMessage<'a> {
     role: &'a str,
-    content: &'a [ContentBlock],
+    content: Value,
     #[serde(skip_serializing_if = "Option::is_none")]
     usage: Option<&'a Usage>,
 }
 
+/// Tool results larger than this go to a sidecar file under --transcript-compact.
+pub const COMPACT_RESULT_BYTES: usize = 4 * 1024;
+/// Start of an externalized result kept inline, so the transcript stays readable.
+const COMPACT_PREVIEW_BYTES: usize = 200;
+/// Sidecar directory for externalized tool results, inside the session dir.
+const RESULTS_DIR: &str = "results";
+
+/// Produces the session id suffix and per-line transcript uuids.
+pub type IdSource = Box<dyn FnMut() -> String + Send>;
+/// Produces the timestamps written to the transcript.
+pub type Clock = Arc<dyn Fn() -> chrono::DateTime<chrono::Utc> + Send + Sync>;
+
 pub struct Session {
     session_id: String,
     cwd: String,
@@ -37,13 +60,48 @@ pub struct Session {
     first_prompt: Option<String>,
     model: String,
     start_time: String,
+    /// Source session id when this session was created by `fork`
+    forked_from: Option<String>,
+    /// Externalize tool results over this many bytes (--transcript-compact)
+    compact_results: Option<usize>,
+    next_id: IdSource,
+    clock: Clock,
 }
 
 impl Session {
     pub fn new(cwd: &str, model: &str) -> Self {
-        let now = chrono::Utc::now();
+        Self::with_clock_and_id(
+            cwd,
+            model,
+            Box::new(|| uuid::Uuid::new_v4().to_string()),
+            Arc::new(chrono::Utc::now),
+        )
+    }
+
+    /// `--deterministic`: counter uuids and a clock starting at 2000-01-01T00:00:00Z that
+    /// ticks one second per reading, so identical runs write identical transcripts.
+    pub fn deterministic(cwd: &str, model: &str) -> Self {
+        let mut n = 0u64;
+        let tick = std::sync::atomic::AtomicI64::new(0);
+        Self::with_clock_and_id(
+            cwd,
+            model,
+            Box::new(move || {
+                n += 1;
+                format!("00000000-0000-4000-8000-{n:012x}")
+            }),
+            Arc::new(move || {
+                let secs = tick.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                chrono::DateTime::from_timestamp(946_684_800 + secs, 0).unwrap_or_default()
+            }),
+        )
+    }
+
+    /// Like `new`, but with injected id and time sources so transcripts are reproducible.
+    pub fn with_clock_and_id(cwd: &str, model: &str, mut next_id: IdSource, clock: Clock) -> Self {
+        let now = clock();
         let date = now.format("%Y-%m-%d").to_string();
-        let id = uuid::Uuid::new_v4();
+        let id = next_id();
         let session_id = format!("{date}-{id}");
         let dir = Path::new(".entire").join("metadata").join(&session_id);
         Self {
@@ -54,9 +112,63 @@ impl Session {
             first_prompt: None,
             model: model.to_string(),
             start_time: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            forked_from: None,
+            compact_results: None,
+            next_id,
+            clock,
         }
     }
 
+    /// Store tool results over `bytes` in `results/` next to the transcript, leaving a
+    /// preview and a `content_ref` in the line (read back by `load_conversation`).
+    pub fn with_compact_results(mut self, bytes: usize) -> Self {
+        self.compact_results = Some(bytes);
+        self
+    }
+
+    /// Directory holding this session's transcript and supporting files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Write the transcript under `dir` instead of `.entire/metadata/` in the cwd.
+    #[cfg(test)]
+    pub fn in_dir(mut self, dir: &Path) -> Self {
+        self.dir = dir.to_path_buf();
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Close this session at the current point and start a sibling whose first
+    /// transcript line chains (via `parentUuid`) onto this session's last line.
+    pub fn fork(self, conversation: &[Message]) -> Session {
+        self.write_supporting_files(conversation);
+        let Session {
+            session_id,
+            cwd,
+            dir,
+            parent_uuid,
+            first_prompt,
+            model,
+            compact_results,
+            next_id,
+            clock,
+            ..
+        } = self;
+        let mut fork = Session::with_clock_and_id(&cwd, &model, next_id, clock);
+        if let Some(parent) = dir.parent() {
+            fork.dir = parent.join(&fork.session_id);
+        }
+        fork.parent_uuid = parent_uuid;
+        fork.first_prompt = first_prompt;
+        fork.forked_from = Some(session_id);
+        fork.compact_results = compact_results;
+        fork
+    }
+
     /// Append a user turn to the JSONL transcript.
     pub fn append_user_turn(&mut self, message: &Message) {
         if self.first_prompt.is_none()
@@ -72,51 +184,153 @@ impl Session {
         self.append_line("assistant", message, Some(usage));
     }
 
+    /// Record a conversation loaded by `--resume` at the start of this transcript, so this
+    /// session (and anything forked from it) resumes with the history it started from.
+    pub fn replay(&mut self, conversation: &[Message]) {
+        for message in conversation {
+            match message.role {
+                Role::User => self.append_user_turn(message),
+                Role::Assistant => self.append_line("assistant", message, None),
+            }
+        }
+    }
+
     fn append_line(&mut self, turn_type: &str, message: &Message, usage: Option<&Usage>) {
-        let uuid = uuid::Uuid::new_v4().to_string();
+        let uuid = (self.next_id)();
         let role = match message.role {
             Role::User => "user",
             Role::Assistant => "assistant",
         };
+        let mut content = serde_json::to_value(&message.content).unwrap_or_default();
+        if let Some(max) = self.compact_results
+            && let Err(e) = externalize_results(&mut content, &self.dir, max)
+        {
+            diag!("[session] results sidecar error: {e}");
+        }
         let line = TranscriptLine {
             turn_type,
             session_id: &self.session_id,
-            uuid: uuid.clone(),
+            uuid: Some(uuid.clone()),
             parent_uuid: self.parent_uuid.take(),
-            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            timestamp: (self.clock)().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
             cwd: &self.cwd,
             version: env!("CARGO_PKG_VERSION"),
-            message: TranscriptMessage {
+            message: Some(TranscriptMessage {
                 role,
-                content: &message.content,
+                content,
                 usage,
-            },
+            }),
+            rewound: None,
+            reason: None,
         };
         self.parent_uuid = Some(uuid);
-        if let Err(e) = self.write_jsonl_line(&line) {
-            eprintln!("[session] write error: {e}");
+        if let Err(e) = write_jsonl_line(&self.dir, &line) {
+            diag!("[session] write error: {e}");
         }
     }
 
-    fn write_jsonl_line(&self, line: &TranscriptLine) -> std::io::Result<()> {
-        fs::create_dir_all(&self.dir)?;
-        let path = self.dir.join("full.jsonl");
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)?;
-        let json = serde_json::to_string(line).map_err(std::io::Error::other)?;
-        writeln!(file, "{json}")
+    /// Record that the last `count` messages were dropped from the conversation (an
+    /// interrupted turn unwound), so `load_conversation` drops them too.
+    pub fn rewind(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let uuid = (self.next_id)();
+        let line = TranscriptLine {
+            turn_type: "rewind",
+            session_id: &self.session_id,
+            uuid: Some(uuid.clone()),
+            parent_uuid: self.parent_uuid.take(),
+            timestamp: (self.clock)().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            cwd: &self.cwd,
+            version: env!("CARGO_PKG_VERSION"),
+            message: None,
+            rewound: Some(count),
+            reason: None,
+        };
+        self.parent_uuid = Some(uuid);
+        if let Err(e) = write_jsonl_line(&self.dir, &line) {
+            diag!("[session] write error: {e}");
+        }
     }
 
     /// Write supporting files (prompt.txt, context.md) at session end.
     pub fn write_supporting_files(&self, conversation: &[Message]) {
-        if let Err(e) = self.write_files_inner(conversation) {
-            eprintln!("[session] supporting files error: {e}");
+        if let Err(e) = self.files().write(conversation) {
+            diag!("[session] supporting files error: {e}");
         }
     }
 
-    fn write_files_inner(&self, conversation: &[Message]) -> std::io::Result<()> {
+    /// What writing the supporting files needs, detached from the Session (which owns
+    /// non-cloneable id and clock sources) so a panic hook can hold a copy.
+    pub fn files(&self) -> SessionFiles {
+        SessionFiles {
+            dir: self.dir.clone(),
+            session_id: self.session_id.clone(),
+            cwd: self.cwd.clone(),
+            model: self.model.clone(),
+            start_time: self.start_time.clone(),
+            first_prompt: self.first_prompt.clone(),
+            forked_from: self.forked_from.clone(),
+            clock: Arc::clone(&self.clock),
+        }
+    }
+}
+
+/// Append one line to `dir`'s transcript under an exclusive advisory lock (flock) so
+/// concurrent writers to the same transcript can never interleave partial lines.
+fn write_jsonl_line(dir: &Path, line: &TranscriptLine) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join("full.jsonl");
+    let mut json = serde_json::to_string(line).map_err(std::io::Error::other)?;
+    json.push('\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.lock()?;
+    let result = file.write_all(json.as_bytes()).and_then(|()| file.flush());
+    file.unlock()?;
+    result
+}
+
+#[derive(Clone)]
+pub struct SessionFiles {
+    dir: PathBuf,
+    session_id: String,
+    cwd: String,
+    model: String,
+    start_time: String,
+    first_prompt: Option<String>,
+    forked_from: Option<String>,
+    clock: Clock,
+}
+
+impl SessionFiles {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Supporting files plus a final `crashed` line in full.jsonl, so the transcript
+    /// shows the session did not end cleanly.
+    pub fn write_crashed(&self, conversation: &[Message], reason: &str) -> std::io::Result<()> {
+        self.write(conversation)?;
+        let line = TranscriptLine {
+            turn_type: "crashed",
+            session_id: &self.session_id,
+            uuid: None,
+            parent_uuid: None,
+            timestamp: (self.clock)().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            cwd: &self.cwd,
+            version: env!("CARGO_PKG_VERSION"),
+            message: None,
+            rewound: None,
+            reason: Some(reason),
+        };
+        write_jsonl_line(&self.dir, &line)
+    }
+
+    fn write(&self, conversation: &[Message]) -> std::io::Result<()> {
         fs::create_dir_all(&self.dir)?;
 
         // prompt.txt
@@ -126,23 +340,334 @@ impl Session {
 
         // context.md
         let mut ctx = format!(
-            "# Session {}\n\n- Model: {}\n- Started: {}\n- CWD: {}\n\n## Key Actions\n\n",
+            "# Session {}\n\n- Model: {}\n- Started: {}\n- CWD: {}\n",
             self.session_id, self.model, self.start_time, self.cwd
         );
-        for msg in conversation {
-            for block in &msg.content {
-                if let ContentBlock::ToolUse { name, input, .. } = block {
-                    let first_arg = input
-                        .as_object()
-                        .and_then(|m| m.values().next())
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("");
-                    ctx.push_str(&format!("- **{name}**: {first_arg}\n"));
+        if let Some(source) = &self.forked_from {
+            ctx.push_str(&format!("- Forked from: {source}\n"));
+        }
+        ctx.push_str("\n## Key Actions\n\n");
+        ctx.push_str(&render_key_actions(conversation));
+        fs::write(self.dir.join("context.md"), ctx)
+    }
+}
+
+/// Move each tool_result over `max` bytes in a serialized content array to
+/// `results/<tool_use_id>.txt`. The block keeps its fields, with `content` cut to a
+/// preview, plus `"content_ref": {"file": "results/<id>.txt", "bytes": <full length>}`.
+fn externalize_results(content: &mut Value, dir: &Path, max: usize) -> std::io::Result<()> {
+    for block in content.as_array_mut().into_iter().flatten() {
+        if block["type"] != "tool_result" {
+            continue;
+        }
+        let Some(text) = block["content"].as_str().filter(|t| t.len() > max) else {
+            continue;
+        };
+        let id: String = block["tool_use_id"]
+            .as_str()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
                 }
+            })
+            .collect();
+        let file = format!("{RESULTS_DIR}/{id}.txt");
+        fs::create_dir_all(dir.join(RESULTS_DIR))?;
+        fs::write(dir.join(&file), text)?;
+        let bytes = text.len();
+        let preview = text[..text.floor_char_boundary(COMPACT_PREVIEW_BYTES)].to_string();
+        block["content"] = Value::String(preview);
+        block["content_ref"] = serde_json::json!({"file": file, "bytes": bytes});
+    }
+    Ok(())
+}
+
+/// Rebuild a session's conversation from `dir/full.jsonl`, reading externalized tool
+/// results back from their sidecar files. Lines that are not messages (`crashed`) are
+/// passed over, except `rewind` lines, which drop the messages they count; corrupt or
+/// malformed ones are skipped with a warning apiece. Cut back
+/// to the last complete assistant reply, since a run that stopped mid-turn leaves calls
+/// without results. Fails only when the transcript cannot be read.
+pub fn load_conversation(dir: &Path) -> Result<(Vec<Message>, Vec<String>), String> {
+    let path = dir.join("full.jsonl");
+    let text = fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let (mut conversation, mut warnings) = (Vec::new(), Vec::new());
+    for (n, line) in text.lines().enumerate() {
+        match parse_message_line(line, dir) {
+            Ok(Some(Entry::Message(message))) => conversation.push(message),
+            Ok(Some(Entry::Rewind(count))) => {
+                conversation.truncate(conversation.len().saturating_sub(count))
             }
+            Ok(None) => {}
+            Err(e) => warnings.push(format!("full.jsonl line {}: {e}; skipped", n + 1)),
         }
-        fs::write(self.dir.join("context.md"), ctx)
     }
+    while conversation.last().is_some_and(|m: &Message| {
+        m.role == Role::User
+            || m.content
+                .iter()
+                .any(|b| matches!(b, ContentBlock::ToolUse { .. }))
+    }) {
+        conversation.pop();
+    }
+    Ok((conversation, warnings))
+}
+
+/// What one transcript line means for the conversation being loaded.
+enum Entry {
+    Message(Message),
+    /// Drop this many messages from the end
+    Rewind(usize),
+}
+
+/// The message (or rewind) on one transcript line; None for other line types. Unknown
+/// fields are ignored, and a plain-string `content` (as the API also accepts) becomes a
+/// text block.
+fn parse_message_line(line: &str, dir: &Path) -> Result<Option<Entry>, String> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut line: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let role = match line["type"].as_str() {
+        Some(role @ ("user" | "assistant")) => role.to_string(),
+        Some("rewind") => {
+            let count = line["rewound"]
+                .as_u64()
+                .ok_or("rewind line without a count")?;
+            return Ok(Some(Entry::Rewind(count as usize)));
+        }
+        _ => return Ok(None),
+    };
+    let mut message = line["message"].take();
+    if message["role"] != role.as_str() {
+        return Err(format!("{role} line holds a {} message", message["role"]));
+    }
+    if let Some(text) = message["content"].as_str() {
+        message["content"] = serde_json::json!([{"type": "text", "text": text}]);
+    }
+    for block in message["content"].as_array_mut().into_iter().flatten() {
+        let Some(file) = block["content_ref"]["file"].as_str() else {
+            continue;
+        };
+        let inside = Path::new(file)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !inside {
+            return Err(format!("bad content_ref {file}"));
+        }
+        let full = fs::read_to_string(dir.join(file)).map_err(|e| format!("{file}: {e}"))?;
+        block["content"] = Value::String(full);
+    }
+    let message: Message = serde_json::from_value(message).map_err(|e| e.to_string())?;
+    if message.content.is_empty() {
+        return Err("message has no content".into());
+    }
+    Ok(Some(Entry::Message(message)))
+}
+
+/// Which past sessions `sweep_sessions` leaves in place (--session-retention).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// The newest N session directories
+    Newest(usize),
+    /// Sessions written to within the last N days
+    Days(u64),
+}
+
+impl Retention {
+    /// `N` keeps the newest N sessions; `Nd` keeps those touched in the last N days.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let bad = || format!("invalid retention '{s}' (expected a count like 20 or days like 30d)");
+        match s.strip_suffix('d') {
+            Some(days) => days.parse().map(Self::Days).map_err(|_| bad()),
+            None => s.parse().map(Self::Newest).map_err(|_| bad()),
+        }
+    }
+}
+
+/// Most session directories archived in one sweep, so a huge backlog cannot stall startup.
+const MAX_SWEPT: usize = 100;
+
+/// Archive session directories under `root` that fall outside `retention` to
+/// `<id>.tar.gz` beside them, removing each directory once its archive is written.
+/// Only date-named session directories are considered, and those in `keep` (the
+/// active session, a --resume source) are never touched. Returns the ids archived
+/// and a warning for each one that could not be.
+pub fn sweep_sessions(
+    root: &Path,
+    retention: Retention,
+    keep: &[&Path],
+    now: std::time::SystemTime,
+) -> (Vec<String>, Vec<String>) {
+    let Ok(entries) = fs::read_dir(root) else {
+        return (Vec::new(), Vec::new());
+    };
+    let kept: Vec<PathBuf> = keep
+        .iter()
+        .filter_map(|p| fs::canonicalize(p).ok())
+        .collect();
+    let mut sessions: Vec<(std::time::SystemTime, String)> = entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.to_string();
+            let dated = name.len() > 11
+                && name.get(..10).is_some_and(|date| {
+                    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()
+                })
+                && name.as_bytes()[10] == b'-';
+            if !dated || fs::canonicalize(e.path()).is_ok_and(|p| kept.contains(&p)) {
+                return None;
+            }
+            // The transcript's mtime is the last time the session was written to
+            let path = e.path();
+            let touched = fs::metadata(path.join("full.jsonl"))
+                .or_else(|_| fs::metadata(&path))
+                .and_then(|m| m.modified())
+                .ok()?;
+            Some((touched, name))
+        })
+        .collect();
+    sessions.sort_by(|a, b| b.cmp(a));
+    let expired: Vec<String> = match retention {
+        Retention::Newest(n) => sessions.into_iter().skip(n).map(|(_, name)| name).collect(),
+        Retention::Days(days) => {
+            let window = std::time::Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+            sessions
+                .into_iter()
+                .filter(|(touched, _)| now.duration_since(*touched).is_ok_and(|age| age > window))
+                .map(|(_, name)| name)
+                .collect()
+        }
+    };
+    let (mut archived, mut warnings) = (Vec::new(), Vec::new());
+    for name in expired.into_iter().take(MAX_SWEPT) {
+        let archive = root.join(format!("{name}.tar.gz"));
+        if archive.exists() {
+            warnings.push(format!(
+                "{name}: {} already exists; left as is",
+                archive.display()
+            ));
+            continue;
+        }
+        let status = std::process::Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(root)
+            .arg(&name)
+            .stderr(std::process::Stdio::null())
+            .status();
+        match status {
+            Ok(s) if s.success() => match fs::remove_dir_all(root.join(&name)) {
+                Ok(()) => archived.push(name),
+                Err(e) => warnings.push(format!("{name}: archived but not removed: {e}")),
+            },
+            other => {
+                let _ = fs::remove_file(&archive);
+                let why = other.map_or_else(|e| e.to_string(), |s| s.to_string());
+                warnings.push(format!("{name}: tar failed ({why}); left as is"));
+            }
+        }
+    }
+    (archived, warnings)
+}
+
+/// Longest bash command shown in context.md before truncation.
+const MAX_COMMAND_CHARS: usize = 200;
+
+/// Markdown narrative of the session's tool calls: file actions grouped per path
+/// (first-touch order), bash commands in full, then searches and anything else.
+fn render_key_actions(conversation: &[Message]) -> String {
+    let mut files: Vec<(String, Vec<(&'static str, usize)>)> = Vec::new();
+    let (mut commands, mut searches, mut other) = (Vec::new(), Vec::new(), Vec::new());
+    let arg = |input: &serde_json::Value, key: &str| input[key].as_str().unwrap_or("").to_string();
+    for block in conversation.iter().flat_map(|m| &m.content) {
+        let ContentBlock::ToolUse { name, input, .. } = block else {
+            continue;
+        };
+        let verb = match name.as_str() {
+            "Read" | "read_file" => Some("read"),
+            "Edit" | "MultiEdit" | "edit_file" => Some("edited"),
+            _ => None,
+        };
+        match (verb, name.as_str()) {
+            (Some(verb), _) => {
+                let path = arg(input, "path");
+                let idx = match files.iter().position(|(p, _)| *p == path) {
+                    Some(i) => i,
+                    None => {
+                        files.push((path, Vec::new()));
+                        files.len() - 1
+                    }
+                };
+                let counts = &mut files[idx].1;
+                match counts.iter_mut().find(|(v, _)| *v == verb) {
+                    Some((_, n)) => *n += 1,
+                    None => counts.push((verb, 1)),
+                }
+            }
+            (None, "Bash" | "bash") => {
+                let cmd = arg(input, "command");
+                let mut shown: String = cmd.chars().take(MAX_COMMAND_CHARS).collect();
+                if shown.len() < cmd.len() {
+                    shown.push('…');
+                }
+                commands.push(format!(
+                    "- `{}`",
+                    shown.replace('`', "'").replace('\n', " ⏎ ")
+                ));
+            }
+            (None, "Grep" | "code_search") => {
+                let path = input["path"].as_str().unwrap_or(".");
+                searches.push(format!("- `{}` in {path}", arg(input, "pattern")));
+            }
+            (None, "FindDefinition") => {
+                searches.push(format!("- definition of `{}`", arg(input, "symbol")));
+            }
+            (None, _) => {
+                let first_arg = input
+                    .as_object()
+                    .and_then(|m| m.values().find_map(|v| v.as_str()))
+                    .unwrap_or("");
+                other.push(format!("- **{name}**: {first_arg}"));
+            }
+        }
+    }
+    let mut out = String::new();
+    let mut section = |title: &str, lines: Vec<String>| {
+        if !lines.is_empty() {
+            out.push_str(&format!("### {title}\n\n{}\n\n", lines.join("\n")));
+        }
+    };
+    let file_lines = files
+        .into_iter()
+        .map(|(path, counts)| {
+            let acts: Vec<String> = counts
+                .iter()
+                .map(|(v, n)| {
+                    if *n > 1 {
+                        format!("{v} ×{n}")
+                    } else {
+                        v.to_string()
+                    }
+                })
+                .collect();
+            format!("- `{path}`: {}", acts.join(", "))
+        })
+        .collect();
+    section("Files", file_lines);
+    section("Commands", commands);
+    section("Searches", searches);
+    section("Other", other);
+    if out.is_empty() {
+        out.push_str("_No tool actions._\n");
+    }
+    out
 }
 
 #[cfg(test)]
@@ -160,9 +685,104 @@ mod tests {
             first_prompt: None,
             model: "test-model".into(),
             start_time: "2026-02-11T00:00:00Z".into(),
+            forked_from: None,
+            compact_results: None,
+            next_id: Box::new(|| uuid::Uuid::new_v4().to_string()),
+            clock: Arc::new(chrono::Utc::now),
         }
     }
 
+    /// Session with counter ids and a clock that ticks one second per reading.
+    fn deterministic_session(dir: &Path) -> Session {
+        let mut n = 0;
+        let tick = std::sync::atomic::AtomicI64::new(0);
+        let mut session = Session::with_clock_and_id(
+            "/test/project",
+            "test-model",
+            Box::new(move || {
+                n += 1;
+                format!("id-{n}")
+            }),
+            Arc::new(move || {
+                let secs = tick.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                chrono::DateTime::from_timestamp(1_770_000_000 + secs, 0).unwrap()
+            }),
+        );
+        session.dir = dir.to_path_buf();
+        session
+    }
+
+    #[test]
+    fn sweep_archives_sessions_past_retention_and_keeps_the_active_one() {
+        let root = tempfile::tempdir().unwrap();
+        let hour = std::time::Duration::from_secs(3600);
+        let now = std::time::SystemTime::now();
+        for (name, age) in [
+            ("2026-01-01-old", 48),
+            ("2026-01-02-new", 1),
+            ("2026-01-03-active", 72),
+            ("2026-01-0é-accent", 96),
+        ] {
+            let dir = root.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            let transcript = fs::File::create(dir.join("full.jsonl")).unwrap();
+            transcript.set_modified(now - hour * age).unwrap();
+        }
+        fs::create_dir_all(root.path().join("notes")).unwrap();
+        let active = root.path().join("2026-01-03-active");
+
+        let (archived, warnings) =
+            sweep_sessions(root.path(), Retention::Newest(1), &[&active], now);
+        assert_eq!(archived, ["2026-01-01-old"]);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert!(!root.path().join("2026-01-01-old").exists());
+        assert!(root.path().join("2026-01-01-old.tar.gz").is_file());
+        assert!(root.path().join("2026-01-02-new/full.jsonl").is_file());
+        assert!(active.join("full.jsonl").is_file());
+        assert!(root.path().join("notes").is_dir());
+        assert!(
+            root.path().join("2026-01-0é-accent").is_dir(),
+            "not date-named"
+        );
+
+        // By age: the one-hour-old session is inside a one-day window
+        let (archived, _) = sweep_sessions(root.path(), Retention::Days(1), &[&active], now);
+        assert!(archived.is_empty());
+        // A window too long to represent keeps everything rather than wrapping around
+        let (archived, _) = sweep_sessions(root.path(), Retention::Days(u64::MAX), &[], now);
+        assert!(archived.is_empty());
+        assert_eq!(Retention::parse("30d"), Ok(Retention::Days(30)));
+        assert_eq!(Retention::parse("5"), Ok(Retention::Newest(5)));
+        assert!(Retention::parse("week").is_err());
+    }
+
+    #[test]
+    fn session_with_fixed_id_and_clock_is_byte_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |name: &str| {
+            let mut session = deterministic_session(&dir.path().join(name));
+            session.append_user_turn(&user_msg("hello"));
+            session.append_assistant_turn(&assistant_msg("hi"), &Usage::default());
+            session.files().write_crashed(&[], "panicked").unwrap();
+            assert_eq!(session.session_id, "2026-02-02-id-1");
+            fs::read_to_string(dir.path().join(name).join("full.jsonl")).unwrap()
+        };
+        let first = run("a");
+        assert_eq!(first, run("b"));
+        let lines: Vec<Value> = first
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["uuid"], "id-2");
+        assert_eq!(lines[0]["timestamp"], "2026-02-02T02:40:01Z");
+        assert_eq!(lines[1]["parentUuid"], "id-2");
+        assert_eq!(lines[1]["uuid"], "id-3");
+        // The crash line is stamped by the session clock too
+        assert_eq!(lines[2]["type"], "crashed");
+        assert_eq!(lines[2]["reason"], "panicked");
+        assert_eq!(lines[2]["timestamp"], "2026-02-02T02:40:03Z");
+    }
+
     fn user_msg(text: &str) -> Message {
         Message {
             role: Role::User,
@@ -323,7 +943,7 @@ mod tests {
         let ctx = fs::read_to_string(session_dir.join("context.md")).unwrap();
         assert!(ctx.contains("# Session 2026-02-11-test-uuid"));
         assert!(ctx.contains("Model: test-model"));
-        assert!(ctx.contains("**read_file**: src/main.rs"));
+        assert!(ctx.contains("### Files\n\n- `src/main.rs`: read\n"));
     }
 
     #[test]
@@ -338,7 +958,103 @@ mod tests {
         let ctx = fs::read_to_string(session_dir.join("context.md")).unwrap();
         assert!(ctx.contains("## Key Actions"));
         // No tool actions listed
-        assert!(!ctx.contains("**"));
+        assert!(ctx.contains("_No tool actions._"));
+        assert!(!ctx.contains("###"));
+    }
+
+    #[test]
+    fn fork_chains_first_line_onto_source_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = deterministic_session(&dir.path().join("source"));
+        source.append_user_turn(&user_msg("try something"));
+        source.append_assistant_turn(&assistant_msg("ok"), &Usage::default());
+        let source_id = source.id().to_string();
+        let conversation = [user_msg("try something"), assistant_msg("ok")];
+        let mut fork = source.fork(&conversation);
+        assert_ne!(fork.id(), source_id);
+        assert_eq!(fork.dir, dir.path().join(fork.id()));
+        fork.append_user_turn(&user_msg("other approach"));
+
+        let read = |p: PathBuf| -> Vec<Value> {
+            fs::read_to_string(p.join("full.jsonl"))
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        };
+        let source_lines = read(dir.path().join("source"));
+        let fork_lines = read(fork.dir.clone());
+        assert_eq!(fork_lines.len(), 1);
+        assert_eq!(fork_lines[0]["parentUuid"], source_lines[1]["uuid"]);
+        assert_eq!(fork_lines[0]["sessionId"], fork.id());
+        // The source was closed out with its supporting files at the fork point
+        assert!(dir.path().join("source/context.md").exists());
+        fork.write_supporting_files(&conversation);
+        let ctx = fs::read_to_string(fork.dir.join("context.md")).unwrap();
+        assert!(
+            ctx.contains(&format!("- Forked from: {source_id}\n")),
+            "{ctx}"
+        );
+    }
+
+    #[test]
+    fn key_actions_group_by_file_and_show_full_commands() {
+        let tool = |name: &str, input: Value| ContentBlock::ToolUse {
+            id: "t".into(),
+            name: name.into(),
+            input,
+        };
+        let conversation = vec![Message {
+            role: Role::Assistant,
+            content: vec![
+                tool("Read", serde_json::json!({"path": "src/api.rs"})),
+                tool("Read", serde_json::json!({"path": "src/main.rs"})),
+                tool(
+                    "Edit",
+                    serde_json::json!({"path": "src/api.rs", "old_str": "a", "new_str": "b"}),
+                ),
+                tool(
+                    "Bash",
+                    serde_json::json!({"cwd": "/tmp", "command": "cargo test --workspace -- --nocapture"}),
+                ),
+                tool(
+                    "MultiEdit",
+                    serde_json::json!({"path": "src/api.rs", "edits": []}),
+                ),
+                tool(
+                    "Grep",
+                    serde_json::json!({"pattern": "fn main", "path": "src"}),
+                ),
+                tool("Glob", serde_json::json!({"path": "docs"})),
+            ],
+        }];
+        assert_eq!(
+            render_key_actions(&conversation),
+            "### Files\n\n\
+             - `src/api.rs`: read, edited ×2\n\
+             - `src/main.rs`: read\n\n\
+             ### Commands\n\n\
+             - `cargo test --workspace -- --nocapture`\n\n\
+             ### Searches\n\n\
+             - `fn main` in src\n\n\
+             ### Other\n\n\
+             - **Glob**: docs\n\n"
+        );
+    }
+
+    #[test]
+    fn key_actions_truncate_long_commands() {
+        let long = "x".repeat(500);
+        let conversation = vec![Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::ToolUse {
+                id: "t".into(),
+                name: "Bash".into(),
+                input: serde_json::json!({"command": long}),
+            }],
+        }];
+        let out = render_key_actions(&conversation);
+        assert!(out.contains(&format!("- `{}…`", "x".repeat(MAX_COMMAND_CHARS))));
     }
 
     #[test]
@@ -424,6 +1140,40 @@ mod tests {
         assert!(session_dir.join("context.md").exists());
     }
 
+    #[test]
+    fn session_concurrent_appends_do_not_interleave() {
+        let dir = tempfile::tempdir().unwrap();
+        let session_dir = dir.path().join("session");
+        // Large payloads make a torn write far more likely without the lock
+        let big = "x".repeat(64 * 1024);
+        let handles: Vec<_> = (0..2)
+            .map(|t| {
+                let session_dir = session_dir.clone();
+                let text = format!("{t}{big}");
+                std::thread::spawn(move || {
+                    let mut session = make_session(&session_dir);
+                    for _ in 0..20 {
+                        session.append_user_turn(&user_msg(&text));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let jsonl = fs::read_to_string(session_dir.join("full.jsonl")).unwrap();
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 40);
+        for line in lines {
+            let v: Value = serde_json::from_str(line).expect("line must be whole JSON");
+            assert_eq!(
+                v["message"]["content"][0]["text"].as_str().unwrap().len(),
+                big.len() + 1
+            );
+        }
+    }
+
     #[test]
     fn session_tool_result_as_first_user_turn_no_prompt() {
         let dir = tempfile::tempdir().unwrap();
@@ -434,4 +1184,149 @@ mod tests {
         session.append_user_turn(&tool_result_msg());
         assert!(session.first_prompt.is_none());
     }
+
+    #[test]
+    fn a_resumed_session_can_itself_be_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = "line of output\n".repeat(1000);
+        let mut first = make_session(&dir.path().join("first")).with_compact_results(64);
+        first.append_user_turn(&user_msg("read it"));
+        first.append_assistant_turn(&assistant_tool_msg(), &Usage::default());
+        first.append_user_turn(&Message {
+            role: Role::User,
+            content: vec![ContentBlock::ToolResult {
+                tool_use_id: "t1".into(),
+                content: big.clone(),
+                is_error: None,
+            }],
+        });
+        first.append_assistant_turn(&assistant_msg("done"), &Usage::default());
+
+        // Resume into a second session, add a turn, then resume that one
+        let (mut conversation, _) = load_conversation(first.dir()).unwrap();
+        let second_dir = dir.path().join("second");
+        let mut second = make_session(&second_dir).with_compact_results(64);
+        second.replay(&conversation);
+        conversation.extend([user_msg("and then?"), assistant_msg("that's all")]);
+        second.append_user_turn(&conversation[4]);
+        second.append_assistant_turn(&conversation[5], &Usage::default());
+        assert_eq!(second.first_prompt.as_deref(), Some("read it"));
+
+        let (loaded, warnings) = load_conversation(&second_dir).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&conversation).unwrap()
+        );
+        // The big result went to the second session's own sidecar, not inline
+        assert!(second_dir.join("results/t1.txt").is_file());
+        assert!(
+            fs::read_to_string(second_dir.join("full.jsonl"))
+                .unwrap()
+                .len()
+                < big.len()
+        );
+    }
+
+    #[test]
+    fn compact_transcript_externalizes_big_results_and_loads_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let session_dir = dir.path().join("session");
+        let mut session = make_session(&session_dir).with_compact_results(64);
+        let big = "line of output\n".repeat(1000);
+        session.append_user_turn(&user_msg("read it"));
+        session.append_assistant_turn(&assistant_tool_msg(), &Usage::default());
+        session.append_user_turn(&Message {
+            role: Role::User,
+            content: vec![ContentBlock::ToolResult {
+                tool_use_id: "t1".into(),
+                content: big.clone(),
+                is_error: None,
+            }],
+        });
+        session.append_assistant_turn(&assistant_msg("done"), &Usage::default());
+
+        let jsonl = fs::read_to_string(session_dir.join("full.jsonl")).unwrap();
+        assert!(jsonl.len() < big.len(), "{jsonl}");
+        let line: Value = serde_json::from_str(jsonl.lines().nth(2).unwrap()).unwrap();
+        let block = &line["message"]["content"][0];
+        assert_eq!(
+            block["content_ref"],
+            serde_json::json!({"file": "results/t1.txt", "bytes": big.len()})
+        );
+        assert!(big.starts_with(block["content"].as_str().unwrap()));
+        assert_eq!(
+            fs::read_to_string(session_dir.join("results/t1.txt")).unwrap(),
+            big
+        );
+
+        let (conversation, warnings) = load_conversation(&session_dir).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(conversation.len(), 4);
+        assert!(matches!(
+            &conversation[2].content[0],
+            ContentBlock::ToolResult { content, .. } if *content == big
+        ));
+    }
+
+    #[test]
+    fn load_conversation_drops_an_unfinished_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = make_session(dir.path());
+        session.append_user_turn(&user_msg("q1"));
+        session.append_assistant_turn(&assistant_msg("a1"), &Usage::default());
+        session.append_user_turn(&user_msg("q2"));
+        session.append_assistant_turn(&assistant_tool_msg(), &Usage::default());
+        session.files().write_crashed(&[], "panicked").unwrap();
+        let (conversation, _) = load_conversation(dir.path()).unwrap();
+        assert_eq!(conversation.len(), 2);
+        assert!(load_conversation(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn load_conversation_tolerates_extra_fields_and_skips_corrupt_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = make_session(dir.path());
+        session.append_user_turn(&user_msg("q1"));
+        session.append_assistant_turn(&assistant_msg("a1"), &Usage::default());
+        let mut jsonl = fs::read_to_string(dir.path().join("full.jsonl")).unwrap();
+        let (conversation, warnings) = load_conversation(dir.path()).unwrap();
+        assert_eq!(conversation.len(), 2);
+        assert!(warnings.is_empty());
+
+        // Hand edits: an unknown field and string content are fine; the rest is not
+        jsonl.push_str(concat!(
+            r#"{"type":"user","message":{"role":"user","content":"q2","note":"edited"},"extra":1}"#,
+            "\n",
+            r#"{"type":"assistant","message":{"role":"assistant","content":[{"type":"text","te"#,
+            "\n",
+            r#"{"type":"assistant","message":{"role":"user","content":"wrong role"}}"#,
+            "\n",
+            r#"{"type":"assistant","message":{"role":"assistant","content":[]}}"#,
+            "\n",
+            r#"{"type":"assistant","message":{"role":"assistant","content":[{"type":"text","text":"a2","cached":true}]}}"#,
+            "\n",
+        ));
+        fs::write(dir.path().join("full.jsonl"), jsonl).unwrap();
+        let (conversation, warnings) = load_conversation(dir.path()).unwrap();
+        assert_eq!(conversation.len(), 4);
+        assert!(matches!(
+            &conversation[2].content[0],
+            ContentBlock::Text { text } if text == "q2"
+        ));
+        assert!(matches!(
+            &conversation[3].content[0],
+            ContentBlock::Text { text } if text == "a2"
+        ));
+        assert_eq!(warnings.len(), 3, "{warnings:?}");
+        assert!(
+            warnings[0].starts_with("full.jsonl line 4: "),
+            "{warnings:?}"
+        );
+        assert!(
+            warnings[1].contains("holds a \"user\" message"),
+            "{warnings:?}"
+        );
+        assert!(warnings[2].ends_with("message has no content; skipped"));
+    }
 }
@@ -0,0 +1,104 @@
+//! Per-tool counters (calls, failures, time, bytes returned) for the end-of-run report
+//! and `/stats`, to spot where a session spent its time.
+
+use std::{collections::BTreeMap, time::Duration};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ToolStat {
+    pub calls: usize,
+    pub failures: usize,
+    pub time: Duration,
+    /// Bytes of tool_result content returned to the model
+    pub bytes: usize,
+}
+
+impl ToolStat {
+    fn add(&mut self, other: &ToolStat) {
+        self.calls += other.calls;
+        self.failures += other.failures;
+        self.time += other.time;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ToolStats(BTreeMap<String, ToolStat>);
+
+impl ToolStats {
+    /// Count one finished tool call.
+    pub fn record(&mut self, tool: &str, time: Duration, bytes: usize, failed: bool) {
+        self.0.entry(tool.to_string()).or_default().add(&ToolStat {
+            calls: 1,
+            failures: usize::from(failed),
+            time,
+            bytes,
+        });
+    }
+
+    pub fn total(&self) -> ToolStat {
+        let mut total = ToolStat::default();
+        for stat in self.0.values() {
+            total.add(stat);
+        }
+        total
+    }
+
+    /// One row per tool, most time first, then a total row.
+    pub fn report(&self) -> String {
+        if self.0.is_empty() {
+            return "No tool calls yet".into();
+        }
+        let mut rows: Vec<(&String, &ToolStat)> = self.0.iter().collect();
+        rows.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        let total = self.total();
+        let total_name = "total".to_string();
+        let mut out = vec![format!(
+            "{:<16} {:>6} {:>6} {:>9} {:>10}",
+            "tool", "calls", "failed", "time", "bytes"
+        )];
+        for (name, s) in rows.into_iter().chain([(&total_name, &total)]) {
+            out.push(format!(
+                "{name:<16} {:>6} {:>6} {:>8.2}s {:>10}",
+                s.calls,
+                s.failures,
+                s.time.as_secs_f64(),
+                s.bytes
+            ));
+        }
+        out.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_counts_failures_and_totals() {
+        let mut stats = ToolStats::default();
+        let ms = Duration::from_millis;
+        stats.record("Grep", ms(100), 40, false);
+        stats.record("Bash", ms(2000), 10, true);
+        stats.record("Grep", ms(300), 60, true);
+        stats.record("Bash", ms(500), 5, false);
+        assert_eq!(
+            stats.0.get("Grep"),
+            Some(&ToolStat {
+                calls: 2,
+                failures: 1,
+                time: ms(400),
+                bytes: 100
+            })
+        );
+        let total = stats.total();
+        assert_eq!((total.calls, total.failures, total.bytes), (4, 2, 115));
+        assert_eq!(total.time, ms(2900));
+        let report = stats.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[1].starts_with("Bash "), "{report}");
+        assert!(lines[2].starts_with("Grep "), "{report}");
+        assert!(lines[3].starts_with("total "), "{report}");
+        assert!(lines[3].contains("2.90s"), "{report}");
+        assert_eq!(ToolStats::default().report(), "No tool calls yet");
+    }
+}
@@ -0,0 +1,129 @@
+//! `--status-line`: model, cumulative tokens and elapsed time pinned to the bottom row of
+//! the terminal. A scroll region keeps streamed text and tool output above it.
+
+use crate::api::Usage;
+use std::{
+    fs::File,
+    io::Write,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+/// The status text for known counters; no terminal control, so it is testable.
+pub fn render(model: &str, usage: &Usage, elapsed: Duration) -> String {
+    let input =
+        usage.input_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+    let secs = elapsed.as_secs();
+    format!(
+        " {model} | {} in / {} out | {}:{:02}",
+        tokens(input),
+        tokens(usage.output_tokens),
+        secs / 60,
+        secs % 60
+    )
+}
+
+/// `950`, `12.3k`, `1.5M`
+fn tokens(n: u64) -> String {
+    match n {
+        0..1_000 => n.to_string(),
+        1_000..1_000_000 => format!("{:.1}k", n as f64 / 1e3),
+        _ => format!("{:.1}M", n as f64 / 1e6),
+    }
+}
+
+/// The bottom row of the terminal, reserved while this is alive.
+pub struct StatusLine {
+    model: String,
+    started: Instant,
+    usage: Usage,
+    rows: u16,
+}
+
+impl StatusLine {
+    /// Reserve the last row, or None when stderr is not a terminal or its size is unknown.
+    pub fn start(model: &str) -> Option<Self> {
+        use std::io::IsTerminal;
+        if !std::io::stderr().is_terminal() {
+            return None;
+        }
+        let rows = terminal_rows().filter(|&r| r > 2)?;
+        // Scroll one line first so the cursor is not left on the reserved row
+        eprint!("\n\x1b[1A\x1b7\x1b[1;{}r\x1b8", rows - 1);
+        let status = Self {
+            model: model.to_string(),
+            started: Instant::now(),
+            usage: Usage::default(),
+            rows,
+        };
+        status.draw();
+        Some(status)
+    }
+
+    /// Count one response's tokens and redraw.
+    pub fn add(&mut self, usage: &Usage) {
+        self.usage.add(usage);
+        self.draw();
+    }
+
+    /// Redraw on the reserved row, leaving the cursor where the text above left it.
+    pub fn draw(&self) {
+        std::io::stdout().flush().ok();
+        let text = render(&self.model, &self.usage, self.started.elapsed());
+        eprint!("\x1b7\x1b[{};1H\x1b[2K\x1b[7m{text}\x1b[0m\x1b8", self.rows);
+        std::io::stderr().flush().ok();
+    }
+}
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        eprint!("\x1b7\x1b[r\x1b[{};1H\x1b[2K\x1b8", self.rows);
+        std::io::stderr().flush().ok();
+    }
+}
+
+/// Give the whole screen back to scrolling, for exits that skip `Drop`.
+pub fn reset_scroll_region() {
+    eprint!("\x1b7\x1b[r\x1b8");
+    std::io::stderr().flush().ok();
+}
+
+/// Row count from `stty size` on the controlling terminal.
+fn terminal_rows() -> Option<u16> {
+    let tty = File::open("/dev/tty").ok()?;
+    let out = Command::new("stty")
+        .arg("size")
+        .stdin(tty)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let size = String::from_utf8(out.stdout).ok()?;
+    size.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_model_tokens_and_elapsed() {
+        let usage = Usage {
+            input_tokens: 1_200,
+            output_tokens: 950,
+            cache_creation_input_tokens: 100,
+            cache_read_input_tokens: 11_000,
+        };
+        assert_eq!(
+            render("claude-opus-4-6", &usage, Duration::from_secs(125)),
+            " claude-opus-4-6 | 12.3k in / 950 out | 2:05"
+        );
+        let big = Usage {
+            input_tokens: 2_500_000,
+            ..Usage::default()
+        };
+        assert_eq!(
+            render("m", &big, Duration::ZERO),
+            " m | 2.5M in / 0 out | 0:00"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Terminal palette: one ANSI style per named role, set once at startup from `--theme`
+//! and `--colors` / FORGEFLARE_COLORS. Every styled write goes through `theme()`.
+
+use std::sync::{
+    LazyLock, OnceLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Suppresses ANSI color when NO_COLOR env var is set (https://no-color.org/).
+static USE_COLOR: LazyLock<bool> = LazyLock::new(|| std::env::var_os("NO_COLOR").is_none());
+/// Set by `disable_color` (--deterministic, --theme plain) regardless of NO_COLOR.
+static COLOR_DISABLED: AtomicBool = AtomicBool::new(false);
+static THEME: OnceLock<Theme> = OnceLock::new();
+static PLAIN: LazyLock<Theme> = LazyLock::new(Theme::plain);
+
+pub fn color_enabled() -> bool {
+    *USE_COLOR && !COLOR_DISABLED.load(Ordering::Relaxed)
+}
+
+/// Turn color off for the rest of the process, as NO_COLOR would.
+pub fn disable_color() {
+    COLOR_DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// The active palette; all roles are empty while color is off.
+pub fn theme() -> &'static Theme {
+    if color_enabled() {
+        THEME.get_or_init(Theme::default)
+    } else {
+        &PLAIN
+    }
+}
+
+/// Install the palette used by `theme()`; only the first call takes effect.
+pub fn set_theme(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+/// `--theme`: the built-in palette, or no styling at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ThemeName {
+    #[default]
+    Default,
+    Plain,
+}
+
+/// ANSI escape per role.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Theme {
+    /// Streamed assistant prose
+    pub assistant: String,
+    /// The `You:` prompt
+    pub user: String,
+    /// `tool:` lines announcing a call
+    pub tool: String,
+    /// `result:` lines
+    pub result: String,
+    pub error: String,
+    pub warning: String,
+    /// Secondary text: thinking, echoed tool results
+    pub context: String,
+    pub reset: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            assistant: "\x1b[93m".into(),
+            user: "\x1b[94m".into(),
+            tool: "\x1b[96m".into(),
+            result: "\x1b[92m".into(),
+            error: "\x1b[91m".into(),
+            warning: "\x1b[93m".into(),
+            context: "\x1b[2m".into(),
+            reset: "\x1b[0m".into(),
+        }
+    }
+}
+
+impl Theme {
+    pub fn plain() -> Self {
+        Self {
+            assistant: String::new(),
+            user: String::new(),
+            tool: String::new(),
+            result: String::new(),
+            error: String::new(),
+            warning: String::new(),
+            context: String::new(),
+            reset: String::new(),
+        }
+    }
+
+    /// Apply comma-separated `role=SGR` overrides, e.g. `assistant=36,warning=1;33`.
+    pub fn with_overrides(mut self, spec: &str) -> Result<Self, String> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (role, code) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected ROLE=CODE, got {entry:?}"))?;
+            let code = code.trim();
+            if code.is_empty() || !code.chars().all(|c| c.is_ascii_digit() || c == ';') {
+                return Err(format!(
+                    "{role}: {code:?} is not an SGR code (digits and ';', e.g. 36 or 1;33)"
+                ));
+            }
+            let slot = match role.trim() {
+                "assistant" => &mut self.assistant,
+                "user" => &mut self.user,
+                "tool" => &mut self.tool,
+                "result" => &mut self.result,
+                "error" => &mut self.error,
+                "warning" => &mut self.warning,
+                "context" => &mut self.context,
+                other => {
+                    return Err(format!(
+                        "unknown role {other:?} (assistant, user, tool, result, error, warning, context)"
+                    ));
+                }
+            };
+            *slot = format!("\x1b[{code}m");
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_replace_roles_and_plain_has_no_codes() {
+        let theme = Theme::default()
+            .with_overrides("assistant=36, warning=1;33")
+            .unwrap();
+        assert_eq!(theme.assistant, "\x1b[36m");
+        assert_eq!(theme.warning, "\x1b[1;33m");
+        assert_eq!(theme.error, Theme::default().error);
+        let plain = Theme::plain();
+        for code in [
+            &plain.assistant,
+            &plain.user,
+            &plain.tool,
+            &plain.result,
+            &plain.error,
+            &plain.warning,
+            &plain.context,
+            &plain.reset,
+        ] {
+            assert!(code.is_empty());
+        }
+        assert!(
+            Theme::default()
+                .with_overrides("banner=31")
+                .unwrap_err()
+                .starts_with("unknown role")
+        );
+        assert!(Theme::default().with_overrides("error=red").is_err());
+        assert!(Theme::default().with_overrides("error").is_err());
+    }
+}
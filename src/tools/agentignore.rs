@@ -0,0 +1,172 @@
+//! `.agentignore` support: gitignore-syntax rules that hide workspace paths from tools.
+//!
+//! Supported syntax: `#` comments, `!` negation (last match wins), leading `/` or an
+//! inner `/` anchors to the workspace root, trailing `/` matches directories only,
+//! `*` and `?` within a component, `**` across components.
+
+use std::path::{Component, Path, PathBuf};
+
+pub const AGENTIGNORE_FILE: &str = ".agentignore";
+
+#[derive(Debug)]
+struct Rule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct AgentIgnore {
+    root: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl AgentIgnore {
+    /// Load `<root>/.agentignore`; a missing or unreadable file yields no rules.
+    pub fn load(root: &Path) -> Self {
+        let text = std::fs::read_to_string(root.join(AGENTIGNORE_FILE)).unwrap_or_default();
+        Self::parse(root, &text)
+    }
+
+    pub fn parse(root: &Path, text: &str) -> Self {
+        let rules = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                let (negated, l) = match l.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, l.strip_prefix('\\').unwrap_or(l)),
+                };
+                let (dir_only, l) = match l.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, l),
+                };
+                let anchored = l.contains('/');
+                Rule {
+                    pattern: l.trim_start_matches('/').to_string(),
+                    negated,
+                    dir_only,
+                    anchored,
+                }
+            })
+            .filter(|r| !r.pattern.is_empty())
+            .collect();
+        Self {
+            root: root.to_path_buf(),
+            rules,
+        }
+    }
+
+    /// True when `path` (absolute, or relative to the process cwd) or any of its
+    /// ancestors inside the workspace is excluded. Paths outside the root never are.
+    pub fn excludes(&self, path: &Path) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let abs = if path.is_absolute() {
+            normalize(path)
+        } else {
+            normalize(&std::env::current_dir().unwrap_or_default().join(path))
+        };
+        let Ok(rel) = abs.strip_prefix(normalize(&self.root)) else {
+            return false;
+        };
+        let parts: Vec<String> = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let is_dir = abs.is_dir();
+        (1..=parts.len()).any(|n| self.matches(&parts[..n], n < parts.len() || is_dir))
+    }
+
+    fn matches(&self, parts: &[String], is_dir: bool) -> bool {
+        let rel = parts.join("/");
+        let name = parts.last().map(String::as_str).unwrap_or_default();
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let hit = if rule.anchored {
+                glob_match(&rule.pattern, &rel)
+            } else {
+                glob_match(&rule.pattern, name)
+            };
+            if hit {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+/// Lexically resolve `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Glob match where `*`/`?` stay within a path component and `**` spans components.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    fn go(p: &[char], t: &[char]) -> bool {
+        match p.first() {
+            None => t.is_empty(),
+            Some('*') if p.get(1) == Some(&'*') => {
+                // `**/` also matches zero directories
+                let rest = &p[2..];
+                let rest_no_slash = rest.strip_prefix(&['/']).unwrap_or(rest);
+                (0..=t.len()).any(|i| go(rest, &t[i..]) || go(rest_no_slash, &t[i..]))
+            }
+            Some('*') => (0..=t.len())
+                .take_while(|&i| i == 0 || t[i - 1] != '/')
+                .any(|i| go(&p[1..], &t[i..])),
+            Some('?') => t.first().is_some_and(|&c| c != '/') && go(&p[1..], &t[1..]),
+            Some(&c) => t.first() == Some(&c) && go(&p[1..], &t[1..]),
+        }
+    }
+    go(&p, &t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_star_stays_within_component() {
+        assert!(glob_match("*.env", "prod.env"));
+        assert!(!glob_match("*.env", "dir/prod.env"));
+        assert!(glob_match("data/**", "data/a/b.csv"));
+        assert!(glob_match("**/secret.txt", "secret.txt"));
+        assert!(glob_match("**/secret.txt", "a/b/secret.txt"));
+        assert!(glob_match("file?.txt", "file1.txt"));
+    }
+
+    #[test]
+    fn rules_match_names_anchors_and_negation() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("secrets")).unwrap();
+        let ig = AgentIgnore::parse(
+            dir.path(),
+            "# comment\nsecrets/\n*.key\n!public.key\n/build\n",
+        );
+        assert!(ig.excludes(&dir.path().join("secrets")));
+        assert!(ig.excludes(&dir.path().join("secrets/db.txt")));
+        assert!(ig.excludes(&dir.path().join("nested/id.key")));
+        assert!(!ig.excludes(&dir.path().join("public.key")));
+        assert!(ig.excludes(&dir.path().join("build/out.o")));
+        assert!(!ig.excludes(&dir.path().join("src/build")));
+        assert!(!ig.excludes(Path::new("/elsewhere/id.key")));
+    }
+}
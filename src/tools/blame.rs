@@ -0,0 +1,186 @@
+//! `GitBlame`: who last changed a line range and why, from `git blame --porcelain`,
+//! condensed to one line per run of consecutive lines from the same commit.
+
+use super::{ToolContext, git};
+use serde_json::Value;
+use std::{collections::HashMap, path::Path};
+
+/// Most lines blamed in one call.
+const MAX_BLAME_LINES: usize = 200;
+
+pub(super) fn git_blame_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let path = input["path"].as_str().ok_or("path is required")?;
+    ctx.check_visible(path)?;
+    let start = input["start"].as_u64().unwrap_or(1).max(1);
+    let end = input["end"]
+        .as_u64()
+        .unwrap_or(start + MAX_BLAME_LINES as u64 - 1);
+    if end < start {
+        return Err(format!("end {end} is before start {start}"));
+    }
+    // Older git rejects a range past EOF, so clamp to the file's length
+    let bytes = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let newlines = bytes.iter().filter(|&&b| b == b'\n').count();
+    let total = (newlines + usize::from(bytes.last().is_some_and(|&b| b != b'\n'))).max(1) as u64;
+    if start > total {
+        return Err(format!("start {start} is past end of file ({total} lines)"));
+    }
+    let capped = end.min(start + MAX_BLAME_LINES as u64 - 1).min(total);
+    let file = Path::new(path);
+    let dir = file.parent().filter(|p| !p.as_os_str().is_empty());
+    let name = file.file_name().ok_or("path has no file name")?;
+    let range = format!("{start},{capped}");
+    let name = name.to_string_lossy();
+    let out = git(
+        dir.unwrap_or(Path::new(".")),
+        &[],
+        &["blame", "--porcelain", "-L", &range, "--", &name],
+    )?;
+    let mut out = format_blame(&out);
+    if capped < end.min(total) {
+        out.push_str(&format!(
+            "\n... (showing lines {start}-{capped}; max {MAX_BLAME_LINES} per call)"
+        ));
+    }
+    Ok(out)
+}
+
+/// Collapse porcelain output into `A-B <sha8> <date> <author>: <summary>` runs.
+fn format_blame(porcelain: &str) -> String {
+    // Commit details appear only on a commit's first line, so remember them by sha
+    let mut info: HashMap<&str, (&str, String, &str)> = HashMap::new();
+    let mut lines: Vec<(usize, &str)> = Vec::new();
+    let mut current: Option<(&str, usize)> = None;
+    for line in porcelain.lines() {
+        if line.starts_with('\t') {
+            if let Some(entry) = current.take() {
+                lines.push((entry.1, entry.0));
+            }
+            continue;
+        }
+        let mut parts = line.split(' ');
+        let first = parts.next().unwrap_or_default();
+        if first.len() == 40 && first.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let final_line = parts.nth(1).and_then(|n| n.parse().ok()).unwrap_or(0);
+            current = Some((first, final_line));
+            info.entry(first).or_default();
+            continue;
+        }
+        let Some((sha, _)) = current else { continue };
+        let entry = info.entry(sha).or_default();
+        let rest = line.split_once(' ').map_or("", |(_, r)| r);
+        match first {
+            "author" => entry.0 = rest,
+            "author-time" => {
+                entry.1 = rest
+                    .parse()
+                    .ok()
+                    .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+                    .map(|d| d.format("%Y-%m-%d").to_string())
+                    .unwrap_or_default();
+            }
+            "summary" => entry.2 = rest,
+            _ => {}
+        }
+    }
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let (from, sha) = lines[i];
+        let mut to = from;
+        while i + 1 < lines.len() && lines[i + 1].1 == sha && lines[i + 1].0 == to + 1 {
+            i += 1;
+            to += 1;
+        }
+        let range = if from == to {
+            from.to_string()
+        } else {
+            format!("{from}-{to}")
+        };
+        if sha.bytes().all(|b| b == b'0') {
+            out.push(format!("{range} uncommitted"));
+        } else {
+            let (author, date, summary) = &info[sha];
+            out.push(format!("{range} {} {date} {author}: {summary}", &sha[..8]));
+        }
+        i += 1;
+    }
+    if out.is_empty() {
+        return "No blame output".into();
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::test_git;
+    use std::fs;
+
+    #[test]
+    fn blame_attributes_lines_to_their_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        test_git(dir.path(), &["init", "-q"]);
+        fs::write(&file, "one\ntwo\nthree\n").unwrap();
+        test_git(dir.path(), &["add", "."]);
+        test_git(dir.path(), &["commit", "-qm", "Initial lines"]);
+        let first = test_git(dir.path(), &["rev-parse", "HEAD"]);
+        fs::write(&file, "one\nTWO\nthree\nfour\n").unwrap();
+        test_git(dir.path(), &["commit", "-qam", "Shout two, add four"]);
+        let second = test_git(dir.path(), &["rev-parse", "HEAD"]);
+        let p = file.to_str().unwrap();
+        let out = git_blame_exec(serde_json::json!({"path": p}), &ToolContext::default()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4, "{out}");
+        assert!(
+            lines[0].starts_with(&format!("1 {} ", &first[..8])),
+            "{out}"
+        );
+        assert!(lines[0].ends_with("Ada: Initial lines"), "{out}");
+        assert!(
+            lines[1].starts_with(&format!("2 {} ", &second[..8])),
+            "{out}"
+        );
+        assert!(lines[1].ends_with("Ada: Shout two, add four"), "{out}");
+        let ranged = git_blame_exec(
+            serde_json::json!({"path": p, "start": 3, "end": 4}),
+            &ToolContext::default(),
+        )
+        .unwrap();
+        assert!(ranged.starts_with("3 "), "{ranged}");
+        assert!(
+            ranged
+                .lines()
+                .nth(1)
+                .unwrap()
+                .starts_with(&format!("4 {}", &second[..8]))
+        );
+    }
+
+    #[test]
+    fn blame_outside_a_repo_is_a_clean_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "x\n").unwrap();
+        let err = git_blame_exec(
+            serde_json::json!({"path": file.to_str().unwrap()}),
+            &ToolContext::default(),
+        )
+        .unwrap_err();
+        assert!(err.ends_with("not a git repo"), "{err}");
+    }
+
+    #[test]
+    fn format_blame_groups_consecutive_lines() {
+        let sha = "a".repeat(40);
+        let porcelain = format!(
+            "{sha} 1 1 2\nauthor Bo\nauthor-time 0\nsummary Start\n\tx\n{sha} 2 2\n\ty\n{} 3 3 1\n\tz\n",
+            "0".repeat(40)
+        );
+        assert_eq!(
+            format_blame(&porcelain),
+            "1-2 aaaaaaaa 1970-01-01 Bo: Start\n3 uncommitted"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! `GitBranch`: create, switch to, list and report git branches in the workspace, with
+//! clean errors and a warning when uncommitted changes follow a checkout.
+
+use super::{ToolContext, git};
+use serde_json::Value;
+use std::path::Path;
+
+pub(super) fn git_branch_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let action = input["action"].as_str().ok_or("action is required")?;
+    // Follow a persisted Bash `cd` so the branch is switched where commands run
+    let dir = ctx.bash_cwd.as_deref().unwrap_or(&ctx.root);
+    match action {
+        "current" => current_branch(dir),
+        "list" => {
+            let out = git(
+                dir,
+                &[],
+                &["branch", "--list", "--format=%(HEAD) %(refname:short)"],
+            )?;
+            if out.trim().is_empty() {
+                // A fresh repo has no branch refs until its first commit
+                return Ok(format!("{} (no commits yet)", current_branch(dir)?));
+            }
+            Ok(out.trim_end().to_string())
+        }
+        "create" | "checkout" => {
+            let name = input["name"]
+                .as_str()
+                .ok_or_else(|| format!("name is required for {action}"))?;
+            check_name(dir, name)?;
+            let exists = git(
+                dir,
+                &[],
+                &["rev-parse", "--verify", "--quiet", &branch_ref(name)],
+            )
+            .is_ok();
+            if action == "create" && exists {
+                return Err(format!("branch {name} already exists; use action=checkout"));
+            }
+            if action == "checkout" && !exists {
+                return Err(format!("no branch named {name}; use action=create"));
+            }
+            let dirty = dirty_files(dir)?;
+            let args: &[&str] = if action == "create" {
+                &["checkout", "-b", name]
+            } else {
+                &["checkout", name]
+            };
+            git(dir, &[], args).map_err(|e| {
+                if e.contains("would be overwritten") {
+                    format!("cannot switch to {name}: uncommitted changes would be overwritten; commit or stash them first")
+                } else {
+                    e
+                }
+            })?;
+            let verb = if action == "create" {
+                "Created and switched to"
+            } else {
+                "Switched to"
+            };
+            let mut out = format!("{verb} branch {name}");
+            if dirty > 0 {
+                out.push_str(&format!(
+                    "\nwarning: {dirty} files with uncommitted changes carried over to {name}"
+                ));
+            }
+            Ok(out)
+        }
+        other => Err(format!(
+            "unknown action {other:?} (expected create, checkout, current or list)"
+        )),
+    }
+}
+
+fn branch_ref(name: &str) -> String {
+    format!("refs/heads/{name}")
+}
+
+/// Branch name, or `HEAD detached at <sha>`.
+pub(super) fn current_branch(dir: &Path) -> Result<String, String> {
+    // symbolic-ref also names the unborn branch of a repo without commits
+    if let Ok(name) = git(dir, &[], &["symbolic-ref", "--short", "--quiet", "HEAD"]) {
+        return Ok(name.trim().to_string());
+    }
+    let sha = git(dir, &[], &["rev-parse", "--short", "HEAD"])?;
+    Ok(format!("HEAD detached at {}", sha.trim()))
+}
+
+/// Rejects names git would refuse (or mistake for an option) before running checkout.
+fn check_name(dir: &Path, name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.starts_with('-')
+        || git(dir, &[], &["check-ref-format", &branch_ref(name)]).is_err()
+    {
+        return Err(format!("invalid branch name: {name:?}"));
+    }
+    Ok(())
+}
+
+/// Changed paths listed in the status summary; the rest are counted.
+const MAX_STATUS_FILES: usize = 20;
+
+/// One-paragraph working-tree summary for the system prompt (`--git-status`), or None
+/// outside a repo.
+pub fn working_tree_summary(dir: &Path) -> Option<String> {
+    let porcelain = git(dir, &[], &["status", "--porcelain=v1", "--branch"]).ok()?;
+    Some(summarize_status(&porcelain))
+}
+
+/// Summarize `git status --porcelain=v1 --branch`: branch, ahead/behind, and the changed
+/// paths with their two-letter status codes (`M ` staged, ` M` unstaged, `??` untracked).
+fn summarize_status(porcelain: &str) -> String {
+    let mut lines = porcelain.lines();
+    let header = lines
+        .next()
+        .and_then(|l| l.strip_prefix("## "))
+        .unwrap_or("unknown");
+    let (branch, tracking) = match header.split_once(" [") {
+        Some((branch, rest)) => (branch, Some(rest.trim_end_matches(']'))),
+        None => (header, None),
+    };
+    let branch = branch
+        .strip_prefix("No commits yet on ")
+        .map_or(branch, |b| b);
+    let branch = branch.split_once("...").map_or(branch, |(local, _)| local);
+    let mut out = format!("Git: branch {branch}");
+    if let Some(tracking) = tracking {
+        out.push_str(&format!(" ({tracking})"));
+    }
+    let changed: Vec<&str> = lines.filter(|l| l.len() > 3).collect();
+    if changed.is_empty() {
+        out.push_str(", clean");
+        return out;
+    }
+    let staged = changed
+        .iter()
+        .filter(|l| !matches!(l.as_bytes()[0], b' ' | b'?'))
+        .count();
+    let unstaged = changed
+        .iter()
+        .filter(|l| !matches!(l.as_bytes()[1], b' ' | b'?'))
+        .count();
+    let untracked = changed.iter().filter(|l| l.starts_with("??")).count();
+    out.push_str(&format!(
+        ", uncommitted changes ({staged} staged, {unstaged} unstaged, {untracked} untracked):"
+    ));
+    for line in changed.iter().take(MAX_STATUS_FILES) {
+        out.push_str(&format!("\n  {line}"));
+    }
+    if changed.len() > MAX_STATUS_FILES {
+        out.push_str(&format!(
+            "\n  ... and {} more",
+            changed.len() - MAX_STATUS_FILES
+        ));
+    }
+    out
+}
+
+/// Count of tracked files with staged or unstaged changes.
+fn dirty_files(dir: &Path) -> Result<usize, String> {
+    let out = git(dir, &[], &["status", "--porcelain", "--untracked-files=no"])?;
+    Ok(out.lines().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::test_git;
+    use std::fs;
+
+    fn repo() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        test_git(dir.path(), &["init", "-q", "-b", "main"]);
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        test_git(dir.path(), &["add", "."]);
+        test_git(dir.path(), &["commit", "-qm", "first"]);
+        dir
+    }
+
+    #[test]
+    fn creates_switches_and_reports_branches() {
+        let dir = repo();
+        let ctx = ToolContext::new(dir.path());
+        let run = |v: Value| git_branch_exec(v, &ctx);
+        assert_eq!(
+            run(serde_json::json!({"action": "current"})).unwrap(),
+            "main"
+        );
+        assert_eq!(
+            run(serde_json::json!({"action": "create", "name": "feature/x"})).unwrap(),
+            "Created and switched to branch feature/x"
+        );
+        assert_eq!(
+            run(serde_json::json!({"action": "current"})).unwrap(),
+            "feature/x"
+        );
+        assert_eq!(
+            run(serde_json::json!({"action": "list"})).unwrap(),
+            "* feature/x\n  main"
+        );
+        fs::write(dir.path().join("a.txt"), "two\n").unwrap();
+        let out = run(serde_json::json!({"action": "checkout", "name": "main"})).unwrap();
+        assert!(out.starts_with("Switched to branch main"), "{out}");
+        assert!(
+            out.contains("warning: 1 files with uncommitted changes"),
+            "{out}"
+        );
+        assert_eq!(
+            run(serde_json::json!({"action": "current"})).unwrap(),
+            "main"
+        );
+    }
+
+    #[test]
+    fn status_summary_lists_changes_or_says_clean() {
+        let dir = repo();
+        assert_eq!(
+            working_tree_summary(dir.path()).unwrap(),
+            "Git: branch main, clean"
+        );
+        fs::write(dir.path().join("a.txt"), "changed\n").unwrap();
+        fs::write(dir.path().join("new.txt"), "x\n").unwrap();
+        assert_eq!(
+            working_tree_summary(dir.path()).unwrap(),
+            "Git: branch main, uncommitted changes (0 staged, 1 unstaged, 1 untracked):\n   M a.txt\n  ?? new.txt"
+        );
+        let outside = tempfile::tempdir().unwrap();
+        assert_eq!(working_tree_summary(outside.path()), None);
+
+        let porcelain = "## feature...origin/feature [ahead 2, behind 1]\nM  src/lib.rs\n";
+        assert_eq!(
+            summarize_status(porcelain),
+            "Git: branch feature (ahead 2, behind 1), uncommitted changes (1 staged, 0 unstaged, 0 untracked):\n  M  src/lib.rs"
+        );
+        assert_eq!(
+            summarize_status("## No commits yet on main\n"),
+            "Git: branch main, clean"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_names_and_wrong_actions() {
+        let dir = repo();
+        let ctx = ToolContext::new(dir.path());
+        let err = |v: Value| git_branch_exec(v, &ctx).unwrap_err();
+        for bad in ["-f", "a..b", "has space", "end.lock", ""] {
+            assert_eq!(
+                err(serde_json::json!({"action": "create", "name": bad})),
+                format!("invalid branch name: {bad:?}")
+            );
+        }
+        assert_eq!(
+            err(serde_json::json!({"action": "create", "name": "main"})),
+            "branch main already exists; use action=checkout"
+        );
+        assert_eq!(
+            err(serde_json::json!({"action": "checkout", "name": "nope"})),
+            "no branch named nope; use action=create"
+        );
+        assert!(err(serde_json::json!({"action": "delete"})).starts_with("unknown action"));
+        let outside = tempfile::tempdir().unwrap();
+        let ctx = ToolContext::new(outside.path());
+        let e = git_branch_exec(serde_json::json!({"action": "current"}), &ctx).unwrap_err();
+        assert!(e.ends_with("not a git repo"), "{e}");
+    }
+}
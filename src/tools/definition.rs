@@ -0,0 +1,254 @@
+//! `FindDefinition`: locate where a symbol is defined using per-language regex
+//! heuristics over the Grep engine, then return the whole defining region (brace- or
+//! indent-delimited) with a little leading context. Falls back to a plain word search.
+
+use super::{ToolContext, find_matches, format_matches, read_text_file};
+use serde_json::Value;
+
+/// Most definitions returned for one symbol.
+const MAX_DEFINITIONS: usize = 5;
+/// Longest region shown per definition before it is cut off.
+const MAX_REGION_LINES: usize = 150;
+/// Lines shown above a definition when no doc comment/attribute precedes it.
+const LEADING_CONTEXT: usize = 2;
+
+/// Definition patterns per language (`{}` is the escaped symbol) and the Grep file type.
+fn lang_patterns(lang: &str) -> Option<(&'static [&'static str], Option<&'static str>)> {
+    const RUST: &[&str] = &[
+        r"\b(fn|struct|enum|trait|type|union|mod|const|static)\s+{}\b",
+        r"\bmacro_rules!\s*{}\b",
+    ];
+    const PYTHON: &[&str] = &[r"^\s*(async\s+)?(def|class)\s+{}\b", r"^{}\s*(:[^=]*)?="];
+    const JS: &[&str] = &[
+        r"\b(function\*?|class|interface|type|enum)\s+{}\b",
+        r"\b(const|let|var)\s+{}\s*=",
+    ];
+    const GO: &[&str] = &[r"\bfunc\s+(\([^)]*\)\s*)?{}\b", r"\btype\s+{}\b"];
+    match lang {
+        "rust" | "rs" => Some((RUST, Some("rust"))),
+        "python" | "py" => Some((PYTHON, Some("py"))),
+        "javascript" | "js" => Some((JS, Some("js"))),
+        "typescript" | "ts" => Some((JS, Some("ts"))),
+        "go" => Some((GO, Some("go"))),
+        _ => None,
+    }
+}
+
+pub(super) fn find_definition_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let symbol = input["symbol"].as_str().ok_or("symbol is required")?;
+    if symbol.is_empty() {
+        return Err("symbol is required".into());
+    }
+    let path = input["path"].as_str().unwrap_or(".");
+    ctx.check_visible(path)?;
+    let escaped = regex::escape(symbol);
+    let (patterns, file_type): (Vec<&str>, Option<&str>) = match input["lang"].as_str() {
+        Some(lang) => {
+            let (p, ft) = lang_patterns(lang).ok_or_else(|| {
+                format!("unsupported lang '{lang}' (use rust, python, javascript, typescript, go)")
+            })?;
+            (p.to_vec(), ft)
+        }
+        None => (
+            ["rust", "python", "javascript", "go"]
+                .iter()
+                .filter_map(|l| lang_patterns(l))
+                .flat_map(|(p, _)| p.iter().copied())
+                .collect(),
+            None,
+        ),
+    };
+    let pattern = patterns
+        .iter()
+        .map(|p| format!("(?:{})", p.replace("{}", &escaped)))
+        .collect::<Vec<_>>()
+        .join("|");
+    let hits = find_matches(&pattern, path, true, file_type, ctx)?;
+    if hits.is_empty() {
+        let plain = find_matches(&format!(r"\b{escaped}\b"), path, true, file_type, ctx)?;
+        if plain.is_empty() {
+            return Ok(format!("No definition or usage of `{symbol}` found"));
+        }
+        return Ok(format!(
+            "No definition of `{symbol}` found; plain matches:\n{}",
+            format_matches(&plain)
+        ));
+    }
+    let mut sections = Vec::new();
+    for hit in hits.iter().take(MAX_DEFINITIONS) {
+        let Some((file, line)) = split_hit(hit) else {
+            continue;
+        };
+        let Ok(content) = read_text_file(file) else {
+            continue;
+        };
+        let lines: Vec<&str> = content.lines().collect();
+        let (start, end, cut) = region(&lines, line - 1, file.ends_with(".py"));
+        let mut section = format!("{file}:{}-{}", start + 1, end + 1);
+        for (i, l) in lines[start..=end].iter().enumerate() {
+            section.push_str(&format!("\n{}: {l}", start + i + 1));
+        }
+        if cut {
+            section.push_str("\n... (definition truncated)");
+        }
+        sections.push(section);
+    }
+    if hits.len() > MAX_DEFINITIONS {
+        let total = hits.len();
+        sections.push(format!(
+            "... (showing {MAX_DEFINITIONS} of {total} definitions)"
+        ));
+    }
+    Ok(sections.join("\n\n"))
+}
+
+/// Split a `file:line:text` hit into its path and 1-based line number.
+pub(super) fn split_hit(hit: &str) -> Option<(&str, usize)> {
+    // Paths may contain ':', so take the first `:<digits>:` boundary
+    let mut from = 0;
+    while let Some(i) = hit[from..].find(':').map(|i| i + from) {
+        let rest = &hit[i + 1..];
+        if let Some(j) = rest.find(':')
+            && let Ok(n) = rest[..j].parse::<usize>()
+            && n > 0
+        {
+            return Some((&hit[..i], n));
+        }
+        from = i + 1;
+    }
+    None
+}
+
+/// Inclusive (start, end) line indices of the definition at `at`, and whether it was cut.
+fn region(lines: &[&str], at: usize, indent_based: bool) -> (usize, usize, bool) {
+    // Pull in doc comments, attributes and decorators directly above
+    let mut start = at;
+    while start > 0 {
+        let prev = lines[start - 1].trim_start();
+        if ["///", "//!", "#[", "@", "/**", "*", "#", "//"]
+            .iter()
+            .any(|p| prev.starts_with(p))
+        {
+            start -= 1;
+        } else {
+            break;
+        }
+    }
+    if start == at {
+        start = at.saturating_sub(LEADING_CONTEXT);
+    }
+    let limit = (at + MAX_REGION_LINES).min(lines.len()) - 1;
+    let end = if indent_based {
+        indent_end(lines, at, limit)
+    } else {
+        brace_end(lines, at, limit)
+    };
+    (
+        start,
+        end.unwrap_or(limit),
+        end.is_none() && limit + 1 < lines.len(),
+    )
+}
+
+/// Last line of a brace-delimited item, or the line ending in `;` for bodiless items.
+fn brace_end(lines: &[&str], at: usize, limit: usize) -> Option<usize> {
+    let mut depth = 0i32;
+    let mut opened = false;
+    for (i, line) in lines.iter().enumerate().take(limit + 1).skip(at) {
+        for ch in line.chars() {
+            match ch {
+                '{' => {
+                    depth += 1;
+                    opened = true;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        if opened && depth <= 0 {
+            return Some(i);
+        }
+        if !opened && line.trim_end().ends_with(';') {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Last line of an indentation-delimited block (Python `def`/`class`).
+fn indent_end(lines: &[&str], at: usize, limit: usize) -> Option<usize> {
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let base = indent(lines[at]);
+    let mut end = at;
+    for (i, line) in lines.iter().enumerate().take(limit + 1).skip(at + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if indent(line) <= base {
+            return Some(end);
+        }
+        end = i;
+    }
+    (limit + 1 >= lines.len()).then_some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn find(input: Value) -> String {
+        find_definition_exec(input, &ToolContext::default()).unwrap()
+    }
+
+    #[test]
+    fn finds_rust_fn_with_doc_comment_and_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = "use std::fs;\n\n/// Adds one.\npub fn add_one(x: i32) -> i32 {\n    let y = x + 1;\n    y\n}\n\nfn other() { add_one(1); }\n";
+        fs::write(dir.path().join("lib.rs"), src).unwrap();
+        let out = find(
+            serde_json::json!({"symbol": "add_one", "path": dir.path().to_str().unwrap(), "lang": "rust"}),
+        );
+        let file = dir.path().join("lib.rs").display().to_string();
+        assert_eq!(
+            out,
+            format!(
+                "{file}:3-7\n3: /// Adds one.\n4: pub fn add_one(x: i32) -> i32 {{\n5:     let y = x + 1;\n6:     y\n7: }}"
+            )
+        );
+    }
+
+    #[test]
+    fn finds_python_def_by_indentation() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = "import os\n\n@cache\ndef load(path):\n    if path:\n\n        return os.read(path)\n    return None\n\nx = load('a')\n";
+        fs::write(dir.path().join("m.py"), src).unwrap();
+        let out = find(serde_json::json!({"symbol": "load", "path": dir.path().to_str().unwrap()}));
+        assert!(out.contains(":3-8\n3: @cache\n4: def load(path):"), "{out}");
+        assert!(out.ends_with("8:     return None"), "{out}");
+        assert!(!out.contains("x = load"), "{out}");
+    }
+
+    #[test]
+    fn falls_back_to_plain_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "let v = mystery();\n").unwrap();
+        let out =
+            find(serde_json::json!({"symbol": "mystery", "path": dir.path().to_str().unwrap()}));
+        assert!(
+            out.starts_with("No definition of `mystery` found; plain matches:"),
+            "{out}"
+        );
+        assert!(out.contains("a.rs:1:let v = mystery();"), "{out}");
+        let out =
+            find(serde_json::json!({"symbol": "absent", "path": dir.path().to_str().unwrap()}));
+        assert_eq!(out, "No definition or usage of `absent` found");
+    }
+
+    #[test]
+    fn split_hit_handles_colons_in_path() {
+        assert_eq!(split_hit("a:b/c.rs:12:fn x() {}"), Some(("a:b/c.rs", 12)));
+        assert_eq!(split_hit("src/lib.rs:3:x: i32"), Some(("src/lib.rs", 3)));
+        assert_eq!(split_hit("garbage"), None);
+    }
+}
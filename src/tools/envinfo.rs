@@ -0,0 +1,82 @@
+//! `EnvInfo`: OS, working directory, git state and common toolchain versions in one
+//! compact report, instead of several exploratory Bash calls at the start of a task.
+
+use super::{ToolContext, branch, run_captured};
+use std::{
+    process::Command,
+    time::{Duration, Instant},
+};
+
+/// Tools probed with `--version`, in report order.
+const PROBED_TOOLS: &[&str] = &[
+    "rustc", "cargo", "node", "npm", "python3", "go", "java", "git", "rg", "docker",
+];
+/// Per-tool limit; all probes run in parallel, so this bounds the whole call.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub(super) fn env_info_exec(ctx: &ToolContext) -> Result<String, String> {
+    let dir = ctx.bash_cwd.as_deref().unwrap_or(&ctx.root);
+    let mut out = vec![
+        format!(
+            "os: {} ({}, {})",
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            std::env::consts::FAMILY
+        ),
+        format!("cwd: {}", dir.display()),
+    ];
+    out.push(match branch::current_branch(dir) {
+        Ok(branch) => format!("git: repo, {branch}"),
+        Err(_) => "git: not a repository".into(),
+    });
+    let probes: Vec<_> = PROBED_TOOLS
+        .iter()
+        .map(|tool| (tool, std::thread::spawn(move || version_of(tool))))
+        .collect();
+    out.push("tools:".into());
+    for (tool, probe) in probes {
+        let version = probe.join().ok().flatten();
+        out.push(format!(
+            "  {tool}: {}",
+            version.as_deref().unwrap_or("not found")
+        ));
+    }
+    Ok(out.join("\n"))
+}
+
+/// First line of `tool --version`, or None when it is missing, fails or hangs.
+fn version_of(tool: &str) -> Option<String> {
+    let mut cmd = Command::new(tool);
+    cmd.arg("--version");
+    let (status, stdout, stderr) =
+        run_captured(cmd, Instant::now() + PROBE_TIMEOUT, |e| e.to_string()).ok()??;
+    if !status.success() {
+        return None;
+    }
+    // Some tools (older java, python2) print their version on stderr
+    let text = if stdout.is_empty() { stderr } else { stdout };
+    let line = String::from_utf8_lossy(&text)
+        .lines()
+        .next()?
+        .trim()
+        .to_string();
+    (!line.is_empty()).then(|| line.chars().take(80).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_has_os_arch_and_survives_missing_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = env_info_exec(&ToolContext::new(dir.path())).unwrap();
+        let first = report.lines().next().unwrap();
+        assert!(first.starts_with("os: "), "{report}");
+        assert!(first.contains(std::env::consts::OS), "{report}");
+        assert!(first.contains(std::env::consts::ARCH), "{report}");
+        assert!(report.contains("git: not a repository"), "{report}");
+        assert!(report.contains("  git: git version"), "{report}");
+        assert_eq!(version_of("forgeflare-no-such-tool"), None);
+    }
+}
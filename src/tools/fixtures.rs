@@ -0,0 +1,98 @@
+//! `--tool-fixtures`: canned tool results consulted by `dispatch_tool` before anything
+//! runs, so a recorded session replays the same way on a machine whose files and
+//! commands differ.
+//!
+//! The file is JSONL, one fixture per line:
+//! `{"tool": "Bash", "input": {"command": "ls"}, "content": "a\nb", "is_error": false}`.
+//! A fixture matches on the tool name and the exact input (object key order ignored).
+
+use serde_json::Value;
+use std::{collections::HashMap, path::Path};
+
+#[derive(Debug, Default)]
+pub struct ToolFixtures(HashMap<String, (String, Option<bool>)>);
+
+/// serde_json maps are sorted, so re-serializing gives an order-independent key.
+fn key(tool: &str, input: &Value) -> String {
+    format!("{tool}\0{input}")
+}
+
+impl ToolFixtures {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let n = i + 1;
+            let v: Value = serde_json::from_str(line).map_err(|e| format!("line {n}: {e}"))?;
+            let tool = v["tool"]
+                .as_str()
+                .ok_or(format!("line {n}: tool is required"))?;
+            let content = v["content"]
+                .as_str()
+                .ok_or(format!("line {n}: content is required"))?;
+            let is_error = v["is_error"].as_bool().unwrap_or(false).then_some(true);
+            map.insert(key(tool, &v["input"]), (content.to_string(), is_error));
+        }
+        Ok(Self(map))
+    }
+
+    /// Canned `(content, is_error)` for this call, if a fixture matches.
+    pub fn lookup(&self, tool: &str, input: &Value) -> Option<(String, Option<bool>)> {
+        self.0.get(&key(tool, input)).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn lookup_matches_tool_and_input_regardless_of_key_order() {
+        let f = ToolFixtures::parse(concat!(
+            r#"{"tool": "Grep", "input": {"pattern": "x", "path": "src"}, "content": "src/a.rs:1:x"}"#,
+            "\n\n",
+            r#"{"tool": "Bash", "input": {"command": "false"}, "content": "exit 1", "is_error": true}"#,
+        ))
+        .unwrap();
+        assert_eq!(f.len(), 2);
+        assert_eq!(
+            f.lookup("Grep", &json!({"path": "src", "pattern": "x"})),
+            Some(("src/a.rs:1:x".into(), None))
+        );
+        assert_eq!(
+            f.lookup("Bash", &json!({"command": "false"})),
+            Some(("exit 1".into(), Some(true)))
+        );
+        assert_eq!(
+            f.lookup("Read", &json!({"pattern": "x", "path": "src"})),
+            None
+        );
+        assert_eq!(
+            f.lookup("Grep", &json!({"pattern": "y", "path": "src"})),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_reports_bad_lines() {
+        let err = ToolFixtures::parse("{\"tool\": \"Bash\"}").unwrap_err();
+        assert_eq!(err, "line 1: content is required");
+        assert!(
+            ToolFixtures::parse("not json")
+                .unwrap_err()
+                .starts_with("line 1:")
+        );
+    }
+}
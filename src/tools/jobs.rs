@@ -0,0 +1,272 @@
+//! Background Bash jobs: `Bash` with `background: true` starts the command in its own
+//! process group and returns a job id at once; `JobOutput` returns what the job printed
+//! since the last poll and `JobKill` stops the whole group. Jobs still running when the
+//! session ends are killed (`kill_all`).
+
+use super::MAX_BASH_OUTPUT;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Jobs tracked at once, running or finished but not yet killed.
+const MAX_JOBS: usize = 8;
+/// How long `JobKill` waits after SIGTERM before SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// The session's background jobs, keyed by id.
+#[derive(Default)]
+pub struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+struct Job {
+    command: String,
+    child: Child,
+    unread: Arc<Mutex<Unread>>,
+}
+
+/// Output printed since the last poll; the oldest bytes go first past MAX_BASH_OUTPUT.
+#[derive(Default)]
+struct Unread {
+    bytes: Vec<u8>,
+    dropped: usize,
+}
+
+impl Unread {
+    fn push(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data);
+        if self.bytes.len() > MAX_BASH_OUTPUT {
+            let excess = self.bytes.len() - MAX_BASH_OUTPUT;
+            self.bytes.drain(..excess);
+            self.dropped += excess;
+        }
+    }
+}
+
+impl Jobs {
+    /// Spawn `cmd` detached from the agent's terminal and start capturing its output.
+    pub(super) fn start(&mut self, mut cmd: Command, command: &str) -> Result<String, String> {
+        let live = self.jobs.len();
+        if live >= MAX_JOBS {
+            return Err(format!(
+                "{live} background jobs already tracked; JobKill one first"
+            ));
+        }
+        let mut child = cmd
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("exec failed: {e}"))?;
+        let unread = Arc::new(Mutex::new(Unread::default()));
+        if let Some(out) = child.stdout.take() {
+            capture(out, Arc::clone(&unread));
+        }
+        if let Some(err) = child.stderr.take() {
+            capture(err, Arc::clone(&unread));
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        let pid = child.id();
+        self.jobs.insert(
+            id,
+            Job {
+                command: command.to_string(),
+                child,
+                unread,
+            },
+        );
+        Ok(format!(
+            "Started background job {id} (pid {pid}). Poll it with JobOutput(id={id}); stop it with JobKill(id={id})."
+        ))
+    }
+
+    /// New output since the last poll, behind a line giving the job's state.
+    pub(super) fn output(&mut self, input: &Value) -> Result<String, String> {
+        let id = job_id(input)?;
+        let job = self.jobs.get_mut(&id).ok_or(format!("no job {id}"))?;
+        let status = job.child.try_wait().map_err(|e| format!("wait: {e}"))?;
+        let (bytes, dropped) = {
+            let mut unread = job.unread.lock().unwrap_or_else(PoisonError::into_inner);
+            let bytes = std::mem::take(&mut unread.bytes);
+            (bytes, std::mem::take(&mut unread.dropped))
+        };
+        let mut out = format!("[job {id} {}: {}]", state(status), job.command);
+        if dropped > 0 {
+            out.push_str(&format!("\n[{dropped} earlier bytes dropped]"));
+        }
+        let text = String::from_utf8_lossy(&bytes);
+        match text.trim_end() {
+            "" => out.push_str("\n(no new output)"),
+            text => {
+                out.push('\n');
+                out.push_str(text);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Stop a job's process group (SIGTERM, then SIGKILL) and forget it.
+    pub(super) fn kill(&mut self, input: &Value) -> Result<String, String> {
+        let id = job_id(input)?;
+        let mut job = self.jobs.remove(&id).ok_or(format!("no job {id}"))?;
+        let status = match job.child.try_wait().map_err(|e| format!("wait: {e}"))? {
+            Some(status) => format!("had already {}", state(Some(status))),
+            None => {
+                stop(&mut job.child);
+                "killed".into()
+            }
+        };
+        Ok(format!("job {id} {status}: {}", job.command))
+    }
+
+    /// Kill every job still running; returns how many were.
+    pub fn kill_all(&mut self) -> usize {
+        let mut killed = 0;
+        for (_, mut job) in std::mem::take(&mut self.jobs) {
+            if matches!(job.child.try_wait(), Ok(None)) {
+                stop(&mut job.child);
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+impl Drop for Jobs {
+    fn drop(&mut self) {
+        self.kill_all();
+    }
+}
+
+fn job_id(input: &Value) -> Result<u64, String> {
+    input["id"].as_u64().ok_or_else(|| "id is required".into())
+}
+
+fn state(status: Option<ExitStatus>) -> String {
+    match status {
+        None => "running".into(),
+        Some(s) => match s.code() {
+            Some(code) => format!("exited with status {code}"),
+            None => "ended by a signal".into(),
+        },
+    }
+}
+
+fn capture(mut reader: impl Read + Send + 'static, unread: Arc<Mutex<Unread>>) {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            unread
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(&buf[..n]);
+        }
+    });
+}
+
+/// Signal the child's whole process group, so servers it forked go with it.
+fn stop(child: &mut Child) {
+    let group = format!("-{}", child.id());
+    let signal = |sig: &str| {
+        let _ = Command::new("kill")
+            .args([sig, "--", &group])
+            .stderr(Stdio::null())
+            .status();
+    };
+    signal("-TERM");
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline {
+        if !matches!(child.try_wait(), Ok(None)) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    signal("-KILL");
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ToolContext, dispatch_tool};
+    use crate::api::ContentBlock;
+
+    fn run(ctx: &mut ToolContext, name: &str, input: serde_json::Value) -> (String, bool) {
+        match dispatch_tool(name, input, "t1", ctx, &mut |_| {}) {
+            ContentBlock::ToolResult {
+                content, is_error, ..
+            } => (content, is_error == Some(true)),
+            other => panic!("unexpected block {other:?}"),
+        }
+    }
+
+    #[test]
+    fn background_job_output_is_polled_and_the_job_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = ToolContext::new(dir.path());
+        let started = std::time::Instant::now();
+        let input = serde_json::json!({"command": "echo ready; sleep 30", "background": true});
+        let (out, failed) = run(&mut ctx, "Bash", input);
+        assert!(!failed, "{out}");
+        assert!(out.starts_with("Started background job 1"), "{out}");
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        let id = serde_json::json!({"id": 1});
+        let mut polled = String::new();
+        for _ in 0..100 {
+            polled = run(&mut ctx, "JobOutput", id.clone()).0;
+            if polled.ends_with("\nready") {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert_eq!(polled, "[job 1 running: echo ready; sleep 30]\nready");
+        // Output is handed out once
+        let (again, _) = run(&mut ctx, "JobOutput", id.clone());
+        assert!(again.ends_with("(no new output)"), "{again}");
+
+        let (killed, failed) = run(&mut ctx, "JobKill", id.clone());
+        assert!(!failed);
+        assert_eq!(killed, "job 1 killed: echo ready; sleep 30");
+        let (gone, failed) = run(&mut ctx, "JobOutput", id);
+        assert!(failed);
+        assert_eq!(gone, "no job 1");
+    }
+
+    #[test]
+    fn finished_jobs_report_their_status_and_kill_all_stops_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = ToolContext::new(dir.path());
+        let quick = serde_json::json!({"command": "exit 3", "background": true});
+        run(&mut ctx, "Bash", quick);
+        let slow = serde_json::json!({"command": "sleep 30", "background": true});
+        run(&mut ctx, "Bash", slow);
+        let mut polled = String::new();
+        for _ in 0..100 {
+            polled = run(&mut ctx, "JobOutput", serde_json::json!({"id": 1})).0;
+            if !polled.contains("running") {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert!(
+            polled.starts_with("[job 1 exited with status 3: exit 3]"),
+            "{polled}"
+        );
+        assert_eq!(ctx.jobs.kill_all(), 1);
+        let (gone, _) = run(&mut ctx, "JobKill", serde_json::json!({"id": 2}));
+        assert_eq!(gone, "no job 2");
+    }
+}
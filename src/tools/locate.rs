@@ -0,0 +1,248 @@
+//! `Locate`: for a vague "where is X" question, the few files most likely to hold it.
+//! Filename hits (from the `Glob` walk) and content hits (from the Grep engine) for the
+//! query's words are merged per file, then ranked by `rank`, a pure function over them.
+
+use super::{ToolContext, definition::split_hit, find_matches, walk};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+/// Files listed in the result.
+const MAX_LOCATED: usize = 10;
+/// Longest snippet shown before it is cut.
+const MAX_SNIPPET_CHARS: usize = 100;
+/// Query words too common to say anything about where code lives.
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "where", "what", "which", "how", "does", "with", "are", "code", "logic",
+    "file", "files", "find",
+];
+/// Path segments that mark tests, fixtures or docs, ranked below the code itself.
+const SECONDARY_DIRS: &[&str] = &[
+    "test",
+    "tests",
+    "spec",
+    "specs",
+    "__tests__",
+    "fixtures",
+    "testdata",
+    "examples",
+    "docs",
+];
+/// Path segments that mark primary source.
+const PRIMARY_DIRS: &[&str] = &["src", "lib", "app", "pkg", "internal", "cmd"];
+
+/// What the query matched in one file.
+#[derive(Debug, Default, Clone)]
+struct FileHits {
+    /// Lines whose content matched a query word
+    lines: usize,
+    /// Query words found in the file's path
+    name_words: usize,
+    /// First matching line: (line number, text)
+    snippet: Option<(usize, String)>,
+}
+
+/// Score for one file: content matches (capped so one huge file cannot drown the rest),
+/// ten per query word in its path, a bonus under source dirs and halved under tests/docs.
+fn score(path: &str, hits: &FileHits) -> usize {
+    let mut score = hits.lines.min(50) + 10 * hits.name_words;
+    let segments: Vec<String> = path.split('/').map(str::to_lowercase).collect();
+    let dirs = &segments[..segments.len().saturating_sub(1)];
+    let name = segments.last().map_or("", String::as_str);
+    if dirs.iter().any(|s| SECONDARY_DIRS.contains(&s.as_str()))
+        || name.contains("_test.")
+        || name.contains(".test.")
+        || name.contains(".spec.")
+        || name.starts_with("test_")
+    {
+        score = score.div_ceil(2);
+    } else if dirs.iter().any(|s| PRIMARY_DIRS.contains(&s.as_str())) {
+        score += 3;
+    }
+    score
+}
+
+/// Order files best first by `score`, ties broken by path; files with no score dropped.
+fn rank(files: Vec<(String, FileHits)>) -> Vec<(String, FileHits)> {
+    let mut scored: Vec<(usize, String, FileHits)> = files
+        .into_iter()
+        .map(|(path, hits)| (score(&path, &hits), path, hits))
+        .filter(|(score, ..)| *score > 0)
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    scored
+        .into_iter()
+        .map(|(_, path, hits)| (path, hits))
+        .collect()
+}
+
+/// Lowercased query words worth searching for, each once, in query order.
+fn query_words(query: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    query
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .map(str::to_lowercase)
+        .filter(|w| w.len() >= 3 && !STOP_WORDS.contains(&w.as_str()))
+        .filter(|w| seen.insert(w.clone()))
+        .collect()
+}
+
+pub(super) fn locate_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let query = input["query"].as_str().ok_or("query is required")?;
+    let path = input["path"].as_str().unwrap_or(".");
+    ctx.check_visible(path)?;
+    let base = Path::new(path);
+    if !base.is_dir() {
+        return Err(format!("{path}: not a directory"));
+    }
+    let words = query_words(query);
+    if words.is_empty() {
+        return Err(format!(
+            "query '{query}' has no searchable words; name the thing you are looking for (e.g. 'auth token')"
+        ));
+    }
+
+    let mut files: BTreeMap<String, FileHits> = BTreeMap::new();
+    let pattern = words
+        .iter()
+        .map(|w| regex::escape(w))
+        .collect::<Vec<_>>()
+        .join("|");
+    for hit in find_matches(&pattern, path, false, None, ctx)? {
+        let Some((file, line)) = split_hit(&hit) else {
+            continue;
+        };
+        let entry = files.entry(display_path(file)).or_default();
+        entry.lines += 1;
+        if entry.snippet.is_none() {
+            let text = hit[file.len() + line.to_string().len() + 2..].trim();
+            let mut snippet: String = text.chars().take(MAX_SNIPPET_CHARS).collect();
+            if snippet.len() < text.len() {
+                snippet.push('…');
+            }
+            entry.snippet = Some((line, snippet));
+        }
+    }
+    let mut entries = Vec::new();
+    walk(base, base, &mut entries, true, 0, &ctx.ignore).map_err(|e| format!("{path}: {e}"))?;
+    for rel in entries.iter().filter(|e| !e.ends_with('/')) {
+        let lower = rel.to_lowercase();
+        let name_words = words.iter().filter(|w| lower.contains(w.as_str())).count();
+        if name_words > 0 {
+            let full = base.join(rel).to_string_lossy().into_owned();
+            files.entry(display_path(&full)).or_default().name_words = name_words;
+        }
+    }
+
+    let ranked = rank(files.into_iter().collect());
+    if ranked.is_empty() {
+        return Ok(format!(
+            "No files match {}; try other words or Grep",
+            words.join(", ")
+        ));
+    }
+    let mut out = format!(
+        "{} files match {}; best {}:",
+        ranked.len(),
+        words.join(", "),
+        ranked.len().min(MAX_LOCATED)
+    );
+    for (file, hits) in ranked.iter().take(MAX_LOCATED) {
+        let why = match (hits.lines, hits.name_words) {
+            (0, _) => "name match".to_string(),
+            (n, 0) => format!("{n} matching lines"),
+            (n, _) => format!("{n} matching lines, name match"),
+        };
+        out.push_str(&format!("\n{file} ({why})"));
+        if let Some((line, text)) = &hits.snippet {
+            out.push_str(&format!("\n  {line}: {text}"));
+        }
+    }
+    Ok(out)
+}
+
+/// A searched path without the leading `./`, so content and name hits share a key.
+fn display_path(path: &str) -> String {
+    path.strip_prefix("./").unwrap_or(path).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn hits(lines: usize, name_words: usize) -> FileHits {
+        FileHits {
+            lines,
+            name_words,
+            snippet: None,
+        }
+    }
+
+    #[test]
+    fn more_matches_rank_higher_and_source_beats_tests() {
+        let ranked = rank(vec![
+            ("src/session.rs".into(), hits(2, 0)),
+            ("src/api.rs".into(), hits(9, 0)),
+            ("tests/api_test.rs".into(), hits(12, 0)),
+            ("src/auth.rs".into(), hits(1, 1)),
+            ("README.md".into(), hits(0, 0)),
+        ]);
+        let order: Vec<&str> = ranked.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(
+            order,
+            [
+                "src/auth.rs",
+                "src/api.rs",
+                "tests/api_test.rs",
+                "src/session.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn query_words_drop_stop_words_and_repeats() {
+        assert_eq!(
+            query_words("Where is the token auth TOKEN, token?"),
+            ["token", "auth"]
+        );
+    }
+
+    #[test]
+    fn locates_files_by_name_and_content() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join("tests")).unwrap();
+        fs::write(
+            dir.path().join("src/login.rs"),
+            "fn check() {}\nfn verify_token() {}\nfn refresh_token() {}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("src/token.rs"), "// storage\n").unwrap();
+        fs::write(dir.path().join("tests/login.rs"), "// token test\n").unwrap();
+        fs::write(dir.path().join("src/other.rs"), "fn main() {}\n").unwrap();
+        let input = serde_json::json!({"query": "where is the token logic?", "path": dir.path().to_str().unwrap()});
+        let out = locate_exec(input, &ToolContext::default()).unwrap();
+        let root = dir.path().display();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "3 files match token; best 3:");
+        assert_eq!(lines[1], format!("{root}/src/token.rs (name match)"));
+        assert_eq!(lines[2], format!("{root}/src/login.rs (2 matching lines)"));
+        assert_eq!(lines[3], "  2: fn verify_token() {}");
+        assert_eq!(
+            lines[4],
+            format!("{root}/tests/login.rs (1 matching lines)")
+        );
+        assert!(!out.contains("other.rs"));
+
+        let none =
+            serde_json::json!({"query": "where is the", "path": dir.path().to_str().unwrap()});
+        assert!(
+            locate_exec(none, &ToolContext::default())
+                .unwrap_err()
+                .contains("no searchable words")
+        );
+    }
+}
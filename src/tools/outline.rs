@@ -0,0 +1,129 @@
+//! `Outline`: a file's top-level symbols (and the methods nested one level in) with their
+//! line numbers, found by per-extension patterns over the Grep engine, so a large file
+//! can be navigated without reading it whole.
+
+use super::{ToolContext, definition::split_hit, find_matches};
+use serde_json::Value;
+
+/// Most symbols listed for one file.
+const MAX_OUTLINE_ENTRIES: usize = 300;
+/// Longest signature line shown before it is cut.
+const MAX_SIGNATURE_CHARS: usize = 120;
+
+/// Symbol-line pattern for a file extension; items at column 0 or one indent level deep.
+fn outline_pattern(ext: &str) -> &'static str {
+    match ext {
+        "rs" => {
+            r#"^( {4})?(pub(\([^)]*\))?\s+)?((const|async|unsafe|default)\s+)*(extern\s+"[^"]*"\s+)?(fn|struct|enum|trait|type|union|mod|impl|macro_rules!)\b"#
+        }
+        "py" | "pyi" => r"^( {4}|\t)?(async\s+)?(def|class)\s+\w",
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" => {
+            r"^(export\s+)?(default\s+)?(declare\s+)?(abstract\s+)?(async\s+)?(function\*?|class|interface|type|enum|namespace)\s+\w|^(export\s+)?(const|let)\s+\w+\s*=\s*(async\s+)?(\([^)]*\)|\w+)\s*=>"
+        }
+        "go" => r"^func\s|^type\s+\w",
+        // Unknown language: a definition keyword near the start of a line
+        _ => {
+            r"^\s{0,4}(pub\s+|export\s+|public\s+|private\s+|static\s+)*(fn|func|function|def|class|struct|interface|enum|trait|module|sub|proc)\s+\w"
+        }
+    }
+}
+
+pub(super) fn outline_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let path = input["path"].as_str().ok_or("path is required")?;
+    ctx.check_visible(path)?;
+    let meta = std::fs::metadata(path).map_err(|e| super::describe_io_error(path, &e, ""))?;
+    if !meta.is_file() {
+        return Err(format!("{path}: not a file (use Glob to list a directory)"));
+    }
+    let ext = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let hits = find_matches(outline_pattern(ext), path, true, None, ctx)?;
+    if hits.is_empty() {
+        return Ok(format!("{path}: no symbols found"));
+    }
+    let mut entries: Vec<(usize, String)> = hits
+        .iter()
+        .filter_map(|hit| {
+            let (file, line) = split_hit(hit)?;
+            let text = &hit[file.len() + line.to_string().len() + 2..];
+            let mut text = text.trim_end().to_string();
+            if text.chars().count() > MAX_SIGNATURE_CHARS {
+                text = text.chars().take(MAX_SIGNATURE_CHARS).collect::<String>() + "…";
+            }
+            Some((line, text))
+        })
+        .collect();
+    entries.sort_by_key(|(line, _)| *line);
+    let total = entries.len();
+    let mut out = format!("{path}: {total} symbols");
+    for (line, text) in entries.iter().take(MAX_OUTLINE_ENTRIES) {
+        out.push_str(&format!("\n{line}: {text}"));
+    }
+    if total > MAX_OUTLINE_ENTRIES {
+        out.push_str(&format!(
+            "\n... (showing {MAX_OUTLINE_ENTRIES} of {total}; Read with a line range for the rest)"
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn outline(path: &std::path::Path) -> String {
+        let input = serde_json::json!({"path": path.to_str().unwrap()});
+        outline_exec(input, &ToolContext::default()).unwrap()
+    }
+
+    #[test]
+    fn outlines_rust_items_and_methods() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lib.rs");
+        let src = "use std::fs;\n\n/// A point.\npub struct Point {\n    x: i32,\n}\n\nimpl Point {\n    pub fn new() -> Self {\n        fn helper() {}\n        Point { x: 0 }\n    }\n}\n\npub(crate) async fn load() {}\nenum Kind { A }\n";
+        fs::write(&path, src).unwrap();
+        let file = path.display();
+        assert_eq!(
+            outline(&path),
+            format!(
+                "{file}: 5 symbols\n4: pub struct Point {{\n8: impl Point {{\n9:     pub fn new() -> Self {{\n15: pub(crate) async fn load() {{}}\n16: enum Kind {{ A }}"
+            )
+        );
+    }
+
+    #[test]
+    fn outlines_python_classes_and_defs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.py");
+        let src = "import os\n\nclass Store:\n    def get(self, key):\n        def inner():\n            pass\n        return key\n\n    async def put(self, key):\n        pass\n\ndef main():\n    Store().get('a')\n";
+        fs::write(&path, src).unwrap();
+        let file = path.display();
+        assert_eq!(
+            outline(&path),
+            format!(
+                "{file}: 4 symbols\n3: class Store:\n4:     def get(self, key):\n9:     async def put(self, key):\n12: def main():"
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_extensions_use_the_keyword_heuristic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.zig");
+        fs::write(
+            &path,
+            "const std = @import(\"std\");\n\npub fn main() void {}\n",
+        )
+        .unwrap();
+        assert!(outline(&path).ends_with(": 1 symbols\n3: pub fn main() void {}"));
+        let err = outline_exec(
+            serde_json::json!({"path": dir.path().to_str().unwrap()}),
+            &ToolContext::default(),
+        )
+        .unwrap_err();
+        assert!(err.contains("not a file"), "{err}");
+    }
+}
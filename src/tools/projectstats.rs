@@ -0,0 +1,142 @@
+//! `ProjectStats`: file counts per extension, line totals for source files and the
+//! largest files under a path, from the same walk as `Glob` (skip dirs, `.agentignore`).
+
+use super::{MAX_READ_SIZE, ToolContext, walk};
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Extensions whose lines are counted.
+const SOURCE_EXTS: &[&str] = &[
+    "rs", "py", "js", "jsx", "ts", "tsx", "go", "java", "kt", "c", "h", "cc", "cpp", "hpp", "cs",
+    "rb", "php", "swift", "scala", "sh", "sql", "html", "css", "md", "toml", "yaml", "yml", "json",
+];
+/// Extension rows shown; the rest are summed into one line.
+const MAX_EXT_ROWS: usize = 15;
+const MAX_LARGEST: usize = 10;
+
+#[derive(Default)]
+struct ExtStat {
+    files: usize,
+    bytes: u64,
+    /// None for extensions whose lines are not counted
+    lines: Option<usize>,
+}
+
+pub(super) fn project_stats_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let path = input["path"].as_str().unwrap_or(".");
+    ctx.check_visible(path)?;
+    let base = Path::new(path);
+    if !base.is_dir() {
+        return Err(format!("{path}: not a directory"));
+    }
+    let mut entries = Vec::new();
+    walk(base, base, &mut entries, true, 0, &ctx.ignore).map_err(|e| format!("{path}: {e}"))?;
+    let mut by_ext: HashMap<String, ExtStat> = HashMap::new();
+    let mut sizes: Vec<(u64, &str)> = Vec::new();
+    let (mut total_bytes, mut total_lines) = (0u64, 0usize);
+    for rel in entries.iter().filter(|e| !e.ends_with('/')) {
+        let full = base.join(rel);
+        let Ok(meta) = fs::symlink_metadata(&full) else {
+            continue;
+        };
+        let ext = Path::new(rel).extension().map_or_else(
+            || "(none)".to_string(),
+            |e| e.to_string_lossy().to_lowercase(),
+        );
+        let stat = by_ext.entry(ext.clone()).or_default();
+        stat.files += 1;
+        stat.bytes += meta.len();
+        total_bytes += meta.len();
+        sizes.push((meta.len(), rel));
+        if SOURCE_EXTS.contains(&ext.as_str()) && meta.len() <= MAX_READ_SIZE {
+            let lines = fs::read(&full).map_or(0, |b| count_lines(&b));
+            *stat.lines.get_or_insert(0) += lines;
+            total_lines += lines;
+        }
+    }
+    let files = sizes.len();
+    if files == 0 {
+        return Ok(format!("{path}: no files"));
+    }
+    let mut out = vec![format!(
+        "{files} files, {}, {total_lines} lines of source under {path}",
+        human_size(total_bytes)
+    )];
+    let mut rows: Vec<(String, ExtStat)> = by_ext.into_iter().collect();
+    rows.sort_by(|a, b| b.1.files.cmp(&a.1.files).then(a.0.cmp(&b.0)));
+    out.push("by extension:".into());
+    for (ext, s) in rows.iter().take(MAX_EXT_ROWS) {
+        let lines = s.lines.map_or(String::new(), |n| format!(", {n} lines"));
+        out.push(format!(
+            "  {ext}: {} files, {}{lines}",
+            s.files,
+            human_size(s.bytes)
+        ));
+    }
+    if rows.len() > MAX_EXT_ROWS {
+        let rest = &rows[MAX_EXT_ROWS..];
+        let n: usize = rest.iter().map(|(_, s)| s.files).sum();
+        out.push(format!("  ... {} more extensions ({n} files)", rest.len()));
+    }
+    sizes.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    out.push("largest files:".into());
+    for (size, rel) in sizes.iter().take(MAX_LARGEST) {
+        out.push(format!("  {rel}: {}", human_size(*size)));
+    }
+    Ok(out.join("\n"))
+}
+
+/// Newline count, plus one for a final line without a trailing newline.
+fn count_lines(bytes: &[u8]) -> usize {
+    let newlines = bytes.iter().filter(|&&b| b == b'\n').count();
+    newlines + usize::from(bytes.last().is_some_and(|&b| b != b'\n'))
+}
+
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_files_and_lines_per_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n// two\n").unwrap();
+        fs::write(root.join("src/nested/lib.rs"), "a\nb\nc").unwrap();
+        fs::write(root.join("README.md"), "# hi\n").unwrap();
+        fs::write(root.join("logo.png"), vec![0u8; 2048]).unwrap();
+        fs::write(root.join("Makefile"), "all:\n").unwrap();
+        fs::write(root.join("target/debug/out.rs"), "skipped\n").unwrap();
+        fs::write(root.join("secret.rs"), "hidden\n").unwrap();
+        fs::write(root.join(".agentignore"), "secret.rs\n").unwrap();
+        let ctx = ToolContext::new(root);
+        let out =
+            project_stats_exec(serde_json::json!({"path": root.to_str().unwrap()}), &ctx).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(
+            lines[0].starts_with("6 files, ") && lines[0].contains(", 6 lines of source"),
+            "{out}"
+        );
+        assert!(out.contains("\n  rs: 2 files, 25 B, 5 lines\n"), "{out}");
+        assert!(out.contains("\n  md: 1 files, 5 B, 1 lines\n"), "{out}");
+        assert!(out.contains("\n  png: 1 files, 2.0 KB\n"), "{out}");
+        assert!(out.contains("\n  (none): 2 files,"), "{out}");
+        assert!(
+            !out.contains("out.rs") && !out.contains("secret.rs"),
+            "{out}"
+        );
+        assert!(
+            out.contains("largest files:\n  logo.png: 2.0 KB\n"),
+            "{out}"
+        );
+    }
+}
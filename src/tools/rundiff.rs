@@ -0,0 +1,155 @@
+//! `RunAndDiff`: run a command through `Bash` and return the `git diff` of what it
+//! changed in the working tree (codegen, formatters, migrations), not its output.
+//!
+//! The tree is snapshotted before and after into throwaway tree objects, written via a
+//! temporary copy of the index, so changes already present are not attributed to the
+//! command and the real index is never touched. Untracked files are included;
+//! .gitignore'd ones are not.
+
+use super::{MAX_BASH_OUTPUT, ToolContext, bash_exec, git, truncate_with_marker};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub(super) fn run_and_diff_exec(
+    input: Value,
+    ctx: &mut ToolContext,
+    on_output: &mut dyn FnMut(&str),
+) -> Result<String, String> {
+    let command = input["command"].as_str().ok_or("command is required")?;
+    let dir = match input["cwd"].as_str() {
+        Some(cwd) => ctx.resolve_cwd(cwd)?,
+        None => ctx.bash_cwd.clone().unwrap_or_else(|| ctx.root.clone()),
+    };
+    let top = git(&dir, &[], &["rev-parse", "--show-toplevel"]).map_err(|e| {
+        if e.ends_with("not a git repo") {
+            format!("{e} (RunAndDiff needs one)")
+        } else {
+            e
+        }
+    })?;
+    let top = PathBuf::from(top.trim());
+    let before = snapshot(&top)?;
+    let mut bash_input = serde_json::json!({"command": command, "cwd": dir});
+    if let Some(secs) = input.get("timeout_secs") {
+        bash_input["timeout_secs"] = secs.clone();
+    }
+    let run = bash_exec(bash_input, ctx, on_output);
+    let after = snapshot(&top)?;
+    let diff = diff_trees(&top, &before, &after, ctx)?;
+    match run {
+        Ok(_) => Ok(diff),
+        // A failed command can still have written files; show both
+        Err(output) => Err(format!(
+            "command failed:\n{output}\n\nchanges made before it failed:\n{diff}"
+        )),
+    }
+}
+
+/// Tree object for the working tree as `git add -A` would stage it right now.
+fn snapshot(top: &Path) -> Result<String, String> {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let n = SEQ.fetch_add(1, Ordering::Relaxed);
+    let index = std::env::temp_dir().join(format!("forgeflare-index-{}-{n}", std::process::id()));
+    // Starting from the real index lets git skip rehashing files whose stat is unchanged
+    let real = git(top, &[], &["rev-parse", "--git-path", "index"])?;
+    let real = top.join(real.trim());
+    if real.is_file() {
+        fs::copy(&real, &index).map_err(|e| format!("snapshot: {e}"))?;
+    }
+    let env = [("GIT_INDEX_FILE", index.as_path())];
+    let tree = git(top, &env, &["add", "-A"]).and_then(|_| git(top, &env, &["write-tree"]));
+    fs::remove_file(&index).ok();
+    Ok(tree?.trim().to_string())
+}
+
+/// `--stat` summary and patch between two snapshots, minus .agentignore'd paths.
+fn diff_trees(top: &Path, before: &str, after: &str, ctx: &ToolContext) -> Result<String, String> {
+    let names = git(
+        top,
+        &[],
+        &["diff", "--name-only", "--no-renames", before, after],
+    )?;
+    let (visible, hidden): (Vec<&str>, Vec<&str>) = names
+        .lines()
+        .partition(|name| !ctx.ignore.excludes(&top.join(name)));
+    if visible.is_empty() && hidden.is_empty() {
+        return Ok("no changes".into());
+    }
+    let mut out = String::new();
+    if !visible.is_empty() {
+        let mut args = vec![
+            "diff",
+            "--no-color",
+            "--no-ext-diff",
+            "--stat",
+            "-p",
+            before,
+            after,
+            "--",
+        ];
+        args.extend(&visible);
+        out = git(top, &[], &args)?;
+    }
+    if !hidden.is_empty() {
+        out.push_str(&format!(
+            "({} changed files excluded by .agentignore not shown)\n",
+            hidden.len()
+        ));
+    }
+    if out.len() > MAX_BASH_OUTPUT {
+        let total = out.len();
+        truncate_with_marker(&mut out, MAX_BASH_OUTPUT, total);
+    }
+    Ok(out.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::test_git;
+
+    #[test]
+    fn returns_diff_of_files_the_command_created() {
+        let dir = tempfile::tempdir().unwrap();
+        test_git(dir.path(), &["init", "-q", "-b", "main"]);
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        test_git(dir.path(), &["add", "."]);
+        test_git(dir.path(), &["commit", "-qm", "first"]);
+        // Uncommitted before the run: must not show up as the command's doing
+        fs::write(dir.path().join("a.txt"), "one\ntwo\n").unwrap();
+        let mut ctx = ToolContext::new(dir.path());
+        let input = serde_json::json!({"command": "echo 'pub fn gen() {}' > gen.rs"});
+        let out = run_and_diff_exec(input, &mut ctx, &mut |_| {}).unwrap();
+        assert!(out.contains("gen.rs | 1 +"), "{out}");
+        assert!(out.contains("new file mode"), "{out}");
+        assert!(out.contains("+pub fn gen() {}"), "{out}");
+        assert!(!out.contains("a.txt"), "{out}");
+        // The real index is untouched
+        let status = git(dir.path(), &[], &["status", "--porcelain"]).unwrap();
+        assert_eq!(status, " M a.txt\n?? gen.rs\n");
+
+        let quiet = serde_json::json!({"command": "true"});
+        assert_eq!(
+            run_and_diff_exec(quiet, &mut ctx, &mut |_| {}).unwrap(),
+            "no changes"
+        );
+        let failing = serde_json::json!({"command": "echo x > partial.txt; exit 1"});
+        let err = run_and_diff_exec(failing, &mut ctx, &mut |_| {}).unwrap_err();
+        assert!(err.starts_with("command failed:"), "{err}");
+        assert!(err.contains("+x"), "{err}");
+    }
+
+    #[test]
+    fn requires_a_git_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = ToolContext::new(dir.path());
+        let input = serde_json::json!({"command": "touch made"});
+        let err = run_and_diff_exec(input, &mut ctx, &mut |_| {}).unwrap_err();
+        assert!(err.contains("not a git repo"), "{err}");
+        assert!(!dir.path().join("made").exists());
+    }
+}
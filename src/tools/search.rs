@@ -0,0 +1,200 @@
+//! Pure-Rust search engine for `Grep`, used with `--engine builtin` where ripgrep may
+//! be missing. Output lines match rg's `file:line:text`; files are streamed line by line
+//! so large files never load whole. Unlike rg it does not read `.gitignore`.
+
+use super::{AgentIgnore, MAX_WALK_DEPTH, SKIP_DIRS, search_timeout};
+use regex::RegexBuilder;
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Which backend `Grep` runs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchEngine {
+    /// Shell out to ripgrep (fast, honours .gitignore)
+    #[default]
+    Rg,
+    /// In-process regex search, no external binary
+    Builtin,
+}
+
+/// Extensions for the rg `--type` names the builtin engine understands.
+const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("rust", &["rs"]),
+    ("py", &["py", "pyi"]),
+    ("js", &["js", "mjs", "cjs", "jsx"]),
+    ("ts", &["ts", "tsx", "mts", "cts"]),
+    ("go", &["go"]),
+    ("c", &["c", "h"]),
+    ("cpp", &["cpp", "cc", "cxx", "hpp", "hh", "hxx", "h"]),
+    ("java", &["java"]),
+    ("sh", &["sh", "bash", "zsh"]),
+    ("md", &["md", "markdown"]),
+    ("toml", &["toml"]),
+    ("json", &["json"]),
+    ("yaml", &["yaml", "yml"]),
+    ("html", &["html", "htm"]),
+    ("css", &["css", "scss"]),
+];
+
+/// Search `path` (file or directory) for `pattern`, returning `file:line:text` lines
+/// sorted by file. Errors on an invalid regex, unknown file type, or the deadline.
+pub fn builtin_search(
+    pattern: &str,
+    path: &str,
+    case_sensitive: bool,
+    file_type: Option<&str>,
+    ignore: &AgentIgnore,
+    deadline: Instant,
+) -> Result<Vec<String>, String> {
+    let re = RegexBuilder::new(pattern)
+        .case_insensitive(!case_sensitive)
+        .build()
+        .map_err(|e| format!("search failed: {e}"))?;
+    let mut lines = Vec::new();
+    for file in builtin_files(path, file_type, ignore)? {
+        if Instant::now() >= deadline {
+            return Err(search_timeout());
+        }
+        let _ = search_file(&file, &re, &mut lines);
+    }
+    Ok(lines)
+}
+
+/// Files the builtin engine would search under `path`, sorted, filtered by `file_type`.
+pub fn builtin_files(
+    path: &str,
+    file_type: Option<&str>,
+    ignore: &AgentIgnore,
+) -> Result<Vec<PathBuf>, String> {
+    let exts = match file_type {
+        Some(ft) => Some(
+            FILE_TYPES
+                .iter()
+                .find(|(name, _)| *name == ft)
+                .map(|(_, exts)| *exts)
+                .ok_or_else(|| format!("search failed: unrecognized file type: {ft}"))?,
+        ),
+        None => None,
+    };
+    let root = Path::new(path);
+    let mut files = Vec::new();
+    if root.is_file() {
+        files.push(root.to_path_buf());
+    } else if root.is_dir() {
+        collect_files(root, &mut files, 0, ignore);
+        files.sort();
+    } else {
+        return Err(format!("search failed: {path}: No such file or directory"));
+    }
+    if let Some(exts) = exts {
+        files.retain(|file| {
+            let ext = file.extension().and_then(|e| e.to_str()).unwrap_or("");
+            exts.contains(&ext)
+        });
+    }
+    Ok(files)
+}
+
+/// Walk like rg does by default: skip hidden entries and SKIP_DIRS, honour .agentignore.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>, depth: usize, ignore: &AgentIgnore) {
+    if depth > MAX_WALK_DEPTH {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') || ignore.excludes(&path) {
+            continue;
+        }
+        let Ok(ft) = entry.file_type() else { continue };
+        if ft.is_dir() {
+            if !SKIP_DIRS.iter().any(|s| *s == name) {
+                collect_files(&path, files, depth + 1, ignore);
+            }
+        } else if ft.is_file() {
+            files.push(path);
+        }
+    }
+}
+
+/// Stream one file, appending matches. Binary files (a NUL byte) are skipped entirely.
+fn search_file(file: &Path, re: &regex::Regex, out: &mut Vec<String>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(fs::File::open(file)?);
+    let mut buf = Vec::new();
+    let mut hits = Vec::new();
+    let mut n = 0;
+    while reader.read_until(b'\n', &mut buf)? > 0 {
+        n += 1;
+        if buf.contains(&0) {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\n', '\r']);
+        if re.is_match(line) {
+            hits.push(format!("{}:{n}:{line}", file.display()));
+        }
+        buf.clear();
+    }
+    out.extend(hits);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn search(pattern: &str, path: &Path, file_type: Option<&str>) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let path = path.to_str().unwrap();
+        builtin_search(
+            pattern,
+            path,
+            false,
+            file_type,
+            &AgentIgnore::default(),
+            deadline,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn builtin_skips_hidden_binary_and_filters_type() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "needle\nhay\nNEEDLE\n").unwrap();
+        fs::write(dir.path().join("b.md"), "needle\n").unwrap();
+        fs::write(dir.path().join(".hidden.rs"), "needle\n").unwrap();
+        fs::write(dir.path().join("bin.rs"), b"needle\0\n").unwrap();
+        let all = search("needle", dir.path(), None);
+        let a = dir.path().join("a.rs").display().to_string();
+        let b = dir.path().join("b.md").display().to_string();
+        assert_eq!(
+            all,
+            [
+                format!("{a}:1:needle"),
+                format!("{a}:3:NEEDLE"),
+                format!("{b}:1:needle")
+            ]
+        );
+        assert_eq!(search("needle", dir.path(), Some("rust")).len(), 2);
+    }
+
+    #[test]
+    fn builtin_rejects_unknown_type_and_bad_regex() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().to_str().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let ig = AgentIgnore::default();
+        let err = builtin_search("x", p, false, Some("cobol"), &ig, deadline).unwrap_err();
+        assert!(err.contains("unrecognized file type"), "{err}");
+        let err = builtin_search("[bad(", p, false, None, &ig, deadline).unwrap_err();
+        assert!(err.starts_with("search failed"), "{err}");
+    }
+}
@@ -0,0 +1,95 @@
+//! `GitShow`: a file's contents as of a git revision (`git show <rev>:<path>`), numbered
+//! like `Read`, so history can be compared without checking anything out.
+
+use super::{MAX_READ_SIZE, ToolContext, git_bytes, number_lines};
+use serde_json::Value;
+use std::path::Path;
+
+pub(super) fn git_show_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let path = input["path"].as_str().ok_or("path is required")?;
+    let rev = input["rev"].as_str().unwrap_or("HEAD");
+    ctx.check_visible(path)?;
+    if rev.is_empty() || rev.starts_with('-') || rev.contains(':') {
+        return Err(format!("invalid rev: {rev:?}"));
+    }
+    let file = Path::new(path);
+    let dir = file.parent().filter(|p| !p.as_os_str().is_empty());
+    let name = file.file_name().ok_or("path has no file name")?;
+    // `./` makes git resolve the path relative to -C rather than the repo root
+    let spec = format!("{rev}:./{}", name.to_string_lossy());
+    let stdout = git_bytes(dir.unwrap_or(Path::new(".")), &[], &["show", &spec]).map_err(|e| {
+        if e.contains("does not exist in") || e.contains("exists on disk, but not in") {
+            format!("{path}: did not exist at {rev}")
+        } else if e.contains("invalid object name") || e.contains("unknown revision") {
+            format!("unknown revision {rev}")
+        } else {
+            e
+        }
+    })?;
+    if stdout.len() as u64 > MAX_READ_SIZE {
+        let (size, max) = (stdout.len() / 1024, MAX_READ_SIZE / 1024);
+        return Err(format!("{path}@{rev}: {size}KB exceeds {max}KB limit"));
+    }
+    if stdout[..stdout.len().min(8192)].contains(&0) {
+        return Err(format!("{path}@{rev}: binary file"));
+    }
+    let content =
+        String::from_utf8(stdout).map_err(|_| format!("{path}@{rev}: not valid UTF-8"))?;
+    Ok(number_lines(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::test_git;
+    use std::fs;
+
+    #[test]
+    fn show_reads_older_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let file = dir.path().join("src/lib.rs");
+        test_git(dir.path(), &["init", "-q"]);
+        fs::write(&file, "fn old() {}\n").unwrap();
+        test_git(dir.path(), &["add", "."]);
+        test_git(dir.path(), &["commit", "-qm", "first"]);
+        fs::write(&file, "fn new() {}\nfn more() {}\n").unwrap();
+        test_git(dir.path(), &["commit", "-qam", "second"]);
+        fs::write(&file, "uncommitted\n").unwrap();
+        let p = file.to_str().unwrap();
+        let ctx = ToolContext::default();
+        let show = |rev: &str| git_show_exec(serde_json::json!({"path": p, "rev": rev}), &ctx);
+        assert_eq!(show("HEAD~1").unwrap(), "1: fn old() {}");
+        assert_eq!(show("HEAD").unwrap(), "1: fn new() {}\n2: fn more() {}");
+        let missing = dir.path().join("src/nope.rs");
+        let err = git_show_exec(
+            serde_json::json!({"path": missing.to_str().unwrap(), "rev": "HEAD"}),
+            &ctx,
+        )
+        .unwrap_err();
+        assert!(err.ends_with("did not exist at HEAD"), "{err}");
+        assert!(
+            show("no-such-rev")
+                .unwrap_err()
+                .starts_with("unknown revision")
+        );
+        assert!(
+            show("--output=/tmp/x")
+                .unwrap_err()
+                .starts_with("invalid rev")
+        );
+    }
+
+    #[test]
+    fn show_outside_a_repo_is_a_clean_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "x\n").unwrap();
+        let err = git_show_exec(
+            serde_json::json!({"path": file.to_str().unwrap()}),
+            &ToolContext::default(),
+        )
+        .unwrap_err();
+        assert!(err.ends_with("not a git repo"), "{err}");
+    }
+}
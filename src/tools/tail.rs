@@ -0,0 +1,176 @@
+//! `TailFile`: the last lines of a file, read backwards from the end in chunks (no `Read`
+//! size cap). Lines are numbered like `Read` when the file is small enough to count its
+//! newlines; past `MAX_COUNTED_BYTES` they are numbered back from the end instead, so a
+//! large or growing log costs only its tail.
+
+use super::{MAX_READ_OUTPUT, ToolContext};
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+const DEFAULT_TAIL_LINES: usize = 50;
+const MAX_TAIL_LINES: usize = 1000;
+/// Bytes read per backward step.
+const TAIL_CHUNK: u64 = 8 * 1024;
+/// Largest file whose lines are counted from the top to number the tail.
+const MAX_COUNTED_BYTES: u64 = 16 * 1024 * 1024;
+
+pub(super) fn tail_file_exec(input: Value, ctx: &ToolContext) -> Result<String, String> {
+    let path = input["path"].as_str().ok_or("path is required")?;
+    ctx.check_visible(path)?;
+    let want = input["lines"]
+        .as_u64()
+        .map_or(DEFAULT_TAIL_LINES, |n| n as usize)
+        .clamp(1, MAX_TAIL_LINES);
+    let meta = std::fs::metadata(path).map_err(|e| format!("{path}: {e}"))?;
+    if meta.is_dir() {
+        return Err(format!("{path}: is a directory"));
+    }
+    if !meta.is_file() {
+        return Err(format!(
+            "{path}: not a regular file (FIFO, device, or socket)"
+        ));
+    }
+    let mut file = File::open(path).map_err(|e| format!("{path}: {e}"))?;
+    let io = |e: std::io::Error| format!("{path}: {e}");
+
+    // Step back until the tail holds `want` whole lines, the start, or the byte cap
+    let mut start = meta.len();
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        let body = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let newlines = body.iter().filter(|&&b| b == b'\n').count();
+        if newlines >= want || start == 0 || buf.len() >= MAX_READ_OUTPUT {
+            break;
+        }
+        let step = TAIL_CHUNK.min(start);
+        start -= step;
+        let mut chunk = vec![0; step as usize];
+        file.seek(SeekFrom::Start(start)).map_err(io)?;
+        file.read_exact(&mut chunk).map_err(io)?;
+        chunk.extend_from_slice(&buf);
+        buf = chunk;
+    }
+    if buf.is_empty() {
+        return Ok(format!("{path}: empty file"));
+    }
+
+    let body = buf.strip_suffix(b"\n").unwrap_or(&buf);
+    let mut pieces: Vec<&[u8]> = body.split(|&b| b == b'\n').collect();
+    // Above the file's start, the first piece is the end of a line cut by the seek
+    let mut skipped = 0;
+    if start > 0 {
+        skipped = 1;
+        pieces.remove(0);
+    }
+    if pieces.len() > want {
+        skipped += pieces.len() - want;
+        pieces.drain(..pieces.len() - want);
+    }
+    if pieces.is_empty() {
+        return Err(format!(
+            "{path}: last line is longer than {}KB; use Read with start_byte",
+            MAX_READ_OUTPUT / 1024
+        ));
+    }
+    if pieces.iter().any(|p| p.contains(&0)) {
+        return Err(format!("{path}: binary file"));
+    }
+    let mut out = String::new();
+    if meta.len() > MAX_COUNTED_BYTES {
+        // Too big to count from the top: -1 is the last line
+        let n = pieces.len();
+        out.push_str(&format!(
+            "[last {n} lines of {} bytes; numbered from the end]",
+            meta.len()
+        ));
+        for (i, piece) in pieces.iter().enumerate() {
+            let line = utf8_line(piece, path)?;
+            out.push_str(&format!("\n-{}: {line}", n - i));
+        }
+        return Ok(out);
+    }
+    // Every newline before the first kept line ends one earlier line: those ahead of
+    // the tail, plus one per skipped piece
+    let first = 1 + count_newlines(&mut file, start).map_err(io)? + skipped;
+    let last = first + pieces.len() - 1;
+    out.push_str(&format!("[lines {first}-{last} of {last}]"));
+    for (i, piece) in pieces.iter().enumerate() {
+        let line = utf8_line(piece, path)?;
+        out.push_str(&format!("\n{}: {line}", first + i));
+    }
+    Ok(out)
+}
+
+fn utf8_line<'a>(piece: &'a [u8], path: &str) -> Result<&'a str, String> {
+    let line = std::str::from_utf8(piece).map_err(|_| format!("{path}: not valid UTF-8"))?;
+    Ok(line.trim_end_matches('\r'))
+}
+
+/// Newlines in the file's first `end` bytes, streamed so nothing is held in memory.
+fn count_newlines(file: &mut File, end: u64) -> std::io::Result<usize> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = file.take(end);
+    let mut chunk = vec![0; 64 * 1024];
+    let mut count = 0;
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(count);
+        }
+        count += chunk[..n].iter().filter(|&&b| b == b'\n').count();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tail(path: &std::path::Path, lines: u64) -> Result<String, String> {
+        let ctx = ToolContext::default();
+        let input = serde_json::json!({"path": path.to_str().unwrap(), "lines": lines});
+        tail_file_exec(input, &ctx)
+    }
+
+    #[test]
+    fn returns_the_last_lines_numbered_from_the_top() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.log");
+        let text: String = (1..=5000).map(|n| format!("log line {n}\n")).collect();
+        std::fs::write(&path, text).unwrap();
+        let out = tail(&path, 10).unwrap();
+        let expected: Vec<String> = std::iter::once("[lines 4991-5000 of 5000]".to_string())
+            .chain((4991..=5000).map(|n| format!("{n}: log line {n}")))
+            .collect();
+        assert_eq!(out, expected.join("\n"));
+
+        // Fewer lines than asked for, and no trailing newline
+        let short = dir.path().join("short.log");
+        std::fs::write(&short, "a\r\nb\nc").unwrap();
+        assert_eq!(
+            tail(&short, 10).unwrap(),
+            "[lines 1-3 of 3]\n1: a\n2: b\n3: c"
+        );
+
+        // Past the counting cap, numbered back from the end without reading the top
+        let big = dir.path().join("big.log");
+        let file = std::fs::File::create(&big).unwrap();
+        file.set_len(MAX_COUNTED_BYTES + 1).unwrap();
+        drop(file);
+        let mut file = std::fs::OpenOptions::new().append(true).open(&big).unwrap();
+        std::io::Write::write_all(&mut file, b"\nsecond last\nlast\n").unwrap();
+        assert_eq!(
+            tail(&big, 2).unwrap(),
+            format!(
+                "[last 2 lines of {} bytes; numbered from the end]\n-2: second last\n-1: last",
+                MAX_COUNTED_BYTES + 19
+            )
+        );
+
+        let binary = dir.path().join("core");
+        std::fs::write(&binary, b"\x7fELF\0\0\nmore\0\n").unwrap();
+        assert!(tail(&binary, 5).unwrap_err().contains("binary"));
+    }
+}